@@ -0,0 +1,19 @@
+; Writes the Fibonacci numbers that fit in a byte to memory from 0x80 on
+; R1 and R2 hold the last two numbers, R3 points at where the next one goes
+; and R5 counts them up to the 14 in R0, the last being 233
+    loadb r0, 14
+    loadb r1, 0
+    loadb r2, 1
+    loadb r3, 0x80
+    loadb r4, 1
+    loadb r5, 1
+    storep r1, r3
+next: addi r3, r3, r4
+    storep r2, r3
+    addi r5, r5, r4
+    jump r5, done
+    addi r6, r1, r2
+    move r1, r2
+    move r2, r6
+    jump r0, next
+done: halt
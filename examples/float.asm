@@ -0,0 +1,8 @@
+; Adds floating-point numbers: a sign bit, a 3-bit excess-4 exponent and a
+; 4-bit mantissa, so 0x5C is 0.1100 * 2^1 = 1.5 and 0x69 is 0.1001 * 2^2 = 2.25
+    loadb r1, 0x5C          ; 1.5
+    loadb r2, 0x69          ; 2.25
+    addf r3, r1, r2         ; 3.75 = 0x6F
+    loadb r4, 0xDC          ; -1.5
+    addf r5, r3, r4         ; 2.25 = 0x69
+    halt
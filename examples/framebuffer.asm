@@ -0,0 +1,27 @@
+; Draws a box with diagonal stripes on the 16x16 framebuffer at 0xD0, two
+; bytes per row with the leftmost pixel in the top bit
+; R1 points at the row, and R7 has the pixel of the stripes, moving right
+    loadb r1, 0xD0
+    loadb r2, 0xFF
+    loadb r3, 1
+    loadb r0, 0xEE          ; Where the last row starts
+    storep r2, r1           ; The top edge
+    addi r1, r1, r3
+    storep r2, r1
+    addi r1, r1, r3
+    loadb r7, 0x40
+    loadb r8, 0x80          ; The left edge
+    loadb r9, 0x01          ; The right edge
+row: jump r1, bottom
+    or r5, r7, r8
+    storep r5, r1
+    addi r1, r1, r3
+    or r6, r7, r9
+    storep r6, r1
+    addi r1, r1, r3
+    rot r7, 1
+    jump r0, row
+bottom: storep r2, r1       ; The bottom edge
+    addi r1, r1, r3
+    storep r2, r1
+    halt
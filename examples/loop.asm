@@ -0,0 +1,11 @@
+; Adds up 1 to 10 into R2 with a counted loop
+; R1 counts up by R3, until it reaches the 10 in R0 the jump compares with
+    loadb r0, 10
+    loadb r1, 0
+    loadb r2, 0
+    loadb r3, 1
+next: addi r1, r1, r3
+    addi r2, r2, r1
+    jump r1, done
+    jump r0, next           ; R0 always equals itself, so this always jumps
+done: halt
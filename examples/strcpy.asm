@@ -0,0 +1,28 @@
+; Copies a zero-terminated string onto the text screen at 0xB0
+; R1 points into the string, R2 into the screen, and R0 is the 0 that ends it
+    loadb r0, 0
+    loadb r1, text
+    loadb r2, 0xB0
+    loadb r4, 1
+next: loadp r3, r1
+    jump r3, done
+    storep r3, r2
+    addi r1, r1, r4
+    addi r2, r2, r4
+    jump r0, next
+done: halt
+text: db 0x48               ; Hello, v8-cpu!
+    db 0x65
+    db 0x6C
+    db 0x6C
+    db 0x6F
+    db 0x2C
+    db 0x20
+    db 0x76
+    db 0x38
+    db 0x2D
+    db 0x63
+    db 0x70
+    db 0x75
+    db 0x21
+    db 0
@@ -1,37 +1,261 @@
-use anyhow::{anyhow, bail, Context, Result};
+use crate::vm::Isa;
 use std::collections::HashMap;
+use thiserror::Error;
+
+/// An error assembling a program, with the line it occurred on.
+#[derive(Debug, Error)]
+#[error("Error on line {line}: {kind}")]
+pub struct AsmError {
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column, counting from 1, of what the error is about, or of the
+    /// start of the line.
+    pub column: usize,
+    /// What went wrong.
+    pub kind: AsmErrorKind,
+}
+
+/// What went wrong assembling a line.
+#[derive(Debug, Error)]
+pub enum AsmErrorKind {
+    /// The program does not fit in memory.
+    #[error("The compiled bytecode exceeded the limit {limit}")]
+    TooLarge {
+        /// The size of memory.
+        limit: usize,
+    },
+    /// An operand that should be a register is not.
+    #[error("Expected register, got '{0}'")]
+    ExpectedRegister(String),
+    /// A number or label that cannot be used where it is.
+    #[error("Invalid value {0}")]
+    InvalidValue(String),
+    /// Operands without a comma between them.
+    #[error("Expected comma between arguments")]
+    ExpectedComma,
+    /// A label name with characters labels cannot have.
+    #[error("Not a valid label: {0}")]
+    InvalidLabel(String),
+    /// A label defined more than once.
+    #[error("Label {0} already exists")]
+    DuplicateLabel(String),
+    /// A label somewhere other than the start of a line.
+    #[error("Label at invalid position")]
+    LabelPosition,
+    /// An instruction of an extension that is not enabled.
+    #[error("{mnemonic} requires {extension}")]
+    RequiresExtension {
+        /// The instruction, as written.
+        mnemonic: String,
+        /// The flag that enables it.
+        extension: &'static str,
+    },
+    /// An instruction that does not exist.
+    #[error("Unknown mnemonic: {0}")]
+    UnknownMnemonic(String),
+    /// Anything after the operands of an instruction.
+    #[error("Unexpected extra content: {0}")]
+    ExtraContent(String),
+    /// A reference to a label that is never defined.
+    #[error("Unknown label: {0}")]
+    UnknownLabel(String),
+    /// A label plus an offset that points outside memory.
+    #[error("Address of {label}{offset:+} is out of memory bounds")]
+    OutOfBounds {
+        /// The label, as written.
+        label: String,
+        /// What is added to it.
+        offset: i32,
+    },
+}
+
+impl AsmErrorKind {
+    /// A name for this kind of error that stays the same across versions,
+    /// for tools reading `--error-format json`.
+    pub fn code(&self) -> &'static str {
+        use AsmErrorKind::*;
+        match self {
+            TooLarge { .. } => "too-large",
+            ExpectedRegister(_) => "expected-register",
+            InvalidValue(_) => "invalid-value",
+            ExpectedComma => "expected-comma",
+            InvalidLabel(_) => "invalid-label",
+            DuplicateLabel(_) => "duplicate-label",
+            LabelPosition => "label-position",
+            RequiresExtension { .. } => "requires-extension",
+            UnknownMnemonic(_) => "unknown-mnemonic",
+            ExtraContent(_) => "extra-content",
+            UnknownLabel(_) => "unknown-label",
+            OutOfBounds { .. } => "out-of-bounds",
+        }
+    }
+
+    /// The text in the source the error is about, if it names any.
+    fn token(&self) -> Option<&str> {
+        use AsmErrorKind::*;
+        match self {
+            ExpectedRegister(s) | InvalidValue(s) | InvalidLabel(s) | DuplicateLabel(s)
+            | UnknownMnemonic(s) | ExtraContent(s) | UnknownLabel(s) => Some(s),
+            RequiresExtension { mnemonic, .. } => Some(mnemonic),
+            OutOfBounds { label, .. } => Some(label),
+            TooLarge { .. } | ExpectedComma | LabelPosition => None,
+        }
+    }
+}
+
+/// Where in `line` the error `kind` points, counting from 1.
+fn column(line: &str, kind: &AsmErrorKind) -> usize {
+    let start = line.len() - line.trim_start().len();
+    let at = kind
+        .token()
+        .filter(|it| !it.is_empty())
+        .and_then(|token| {
+            line.find(token)
+                .or_else(|| line.to_ascii_lowercase().find(&token.to_ascii_lowercase()))
+        })
+        .unwrap_or(start);
+    line[..at].chars().count() + 1
+}
+
+type Result<T> = std::result::Result<T, AsmErrorKind>;
 
 fn identifier(ch: char) -> bool {
     ch.is_alphanumeric() || ch == '_'
 }
 
-pub fn assemble(code: &str) -> Result<Vec<u8>> {
+/// Which source line every byte of an assembled program came from, and where
+/// its labels are.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    lines: Vec<Option<usize>>,
+    labels: HashMap<String, u16>,
+}
+
+impl SourceMap {
+    /// The line the byte at `addr` was assembled from.
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.lines.get(addr as usize).copied().flatten()
+    }
+
+    /// The address of the label `name`.
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels
+            .get(name)
+            .or_else(|| self.labels.get(&name.to_ascii_lowercase()))
+            .copied()
+    }
+
+    /// The first label, by name, at `addr`.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels_at(addr).into_iter().next()
+    }
+
+    /// Every label at `addr`, by name.
+    pub fn labels_at(&self, addr: u16) -> Vec<&str> {
+        let mut labels: Vec<_> = self
+            .labels
+            .iter()
+            .filter(|&(_, &it)| it == addr)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        labels.sort_unstable();
+        labels
+    }
+
+    /// The address of the first byte assembled from `line`.
+    pub fn addr(&self, line: usize) -> Option<u16> {
+        self.lines
+            .iter()
+            .position(|&it| it == Some(line))
+            .map(|it| it as u16)
+    }
+}
+
+/// Assembly source along with where it ended up in memory.
+pub struct Source {
+    /// The lines of the source, as written.
+    pub lines: Vec<String>,
+    /// Where the lines ended up.
+    pub map: SourceMap,
+}
+
+/// Parses a register name such as `R1` or `rf`.
+pub fn parse_reg(s: &str) -> Option<u8> {
+    let s = s.trim().to_ascii_lowercase();
+    u8::from_str_radix(s.strip_prefix('r')?, 16)
+        .ok()
+        .filter(|&it| it < 16)
+}
+
+/// Parses an address given as hex with `0x`, decimal, or a label of `source`.
+pub fn parse_location(s: &str, source: Option<&Source>) -> Option<u16> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(addr) = lower.parse() {
+        return Some(addr);
+    }
+    source?.map.label(s)
+}
+
+/// Assembles `code` into a memory image for `size` bytes of memory, ending
+/// right after the last byte written. `isa` enables the mnemonics of the
+/// extensions of the instruction set.
+///
+/// Labels may be placed anywhere in memory, but using one as an operand only
+/// keeps the low byte of its address.
+pub fn assemble(code: &str, isa: Isa, size: usize) -> std::result::Result<Vec<u8>, AsmError> {
+    assemble_with_map(code, isa, size).map(|(bytes, _)| bytes)
+}
+
+/// Like `assemble`, but also returns which line every byte came from.
+pub fn assemble_with_map(
+    code: &str,
+    isa: Isa,
+    size: usize,
+) -> std::result::Result<(Vec<u8>, SourceMap), AsmError> {
+    use AsmErrorKind::*;
     const WS: fn(char) -> bool = char::is_whitespace;
 
     enum Val {
         Const(u8),
-        Ref(String),
+        /// A label reference, with the line it is on.
+        Ref(String, usize),
     }
     use Val::*;
 
     struct Output {
-        pub mem: [Val; 256],
+        pub mem: Vec<Val>,
+        /// The line every byte was written on.
+        pub lines: Vec<Option<usize>>,
+        /// The line being assembled.
+        pub line: usize,
         pub pos: usize,
+        /// One past the last byte written.
+        pub end: usize,
     }
     impl Output {
-        pub fn new() -> Self {
-            const INIT: Val = Const(0);
+        pub fn new(size: usize) -> Self {
             Self {
-                mem: [INIT; 256],
+                mem: (0..size).map(|_| Const(0)).collect(),
+                lines: vec![None; size],
+                line: 0,
                 pos: 0,
+                end: 0,
             }
         }
         pub fn push(&mut self, val: Val) -> Result<()> {
-            if self.pos >= 256 {
-                bail!("The compiled bytecode exceeded the limit 256");
+            if self.pos >= self.mem.len() {
+                return Err(TooLarge {
+                    limit: self.mem.len(),
+                });
             }
             self.mem[self.pos] = val;
+            self.lines[self.pos] = Some(self.line);
             self.pos += 1;
+            self.end = self.end.max(self.pos);
             Ok(())
         }
     }
@@ -42,7 +266,7 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
     fn getr(s: String) -> Result<(u8, String)> {
         let s = s.trim_start();
         if s.len() < 2 {
-            bail!("Expected register like RX");
+            return Err(ExpectedRegister(s.to_string()));
         }
         let (reg, s) = s
             .find(|c: char| !c.is_alphanumeric())
@@ -53,9 +277,9 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
                 return Ok((reg, s.to_string()));
             }
         }
-        bail!("Expected register, got '{reg}'");
+        Err(ExpectedRegister(reg.to_string()))
     }
-    fn getv(s: String) -> Result<(Val, String)> {
+    fn getv(s: String, line: usize) -> Result<(Val, String)> {
         let s = s.trim_start();
         let (val, s) = s
             .find(|c: char| c.is_whitespace() || c == ',')
@@ -64,29 +288,34 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
         let s = s.to_string();
         let val = val.to_ascii_lowercase();
         let num = if let Some(hex) = val.strip_prefix("0x") {
-            i32::from_str_radix(hex, 16)?
+            i32::from_str_radix(hex, 16)
         } else if val.starts_with(|c: char| c.is_alphabetic() || c == '.') {
-            return Ok((Ref(val), s));
+            return Ok((Ref(val, line), s));
         } else {
-            val.parse()?
+            val.parse()
         };
+        let num = num.map_err(|_| InvalidValue(val.clone()))?;
         if num.abs() > 256 {
-            bail!("Invalid value {val}");
+            return Err(InvalidValue(val));
         }
         let num = (if num < 0 { 256 + num } else { num }) as u8;
         Ok((Const(num), s))
     }
     fn comma(s: String) -> Result<String> {
         let s = s.trim_start();
-        s.strip_prefix(',')
-            .map(str::to_string)
-            .ok_or_else(|| anyhow!("Expected comma between arguments"))
+        s.strip_prefix(',').map(str::to_string).ok_or(ExpectedComma)
     }
 
-    fn parse_line(s: &str, labels: &mut HashMap<String, u8>, res: &mut Output) -> Result<()> {
-        fn p_rv(s: String, res: &mut Output, op: u8) -> Result<String> {
+    fn parse_line(
+        s: &str,
+        line: usize,
+        labels: &mut HashMap<String, u16>,
+        res: &mut Output,
+        isa: Isa,
+    ) -> Result<()> {
+        fn p_rv(s: String, line: usize, res: &mut Output, op: u8) -> Result<String> {
             let (reg, s) = getr(s)?;
-            let (addr, s) = getv(comma(s)?)?;
+            let (addr, s) = getv(comma(s)?, line)?;
             res.push(Const(jo(op, reg)))?;
             res.push(addr)?;
             Ok(s)
@@ -117,10 +346,11 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
                 let num = &label[index + 1..].to_ascii_lowercase();
                 label = &label[..index];
                 let addr = if let Some(hex) = num.strip_prefix("0x") {
-                    u8::from_str_radix(hex, 16)?
+                    u16::from_str_radix(hex, 16)
                 } else {
-                    num.parse()?
+                    num.parse()
                 };
+                let addr = addr.map_err(|_| InvalidValue(num.clone()))?;
                 res.pos = addr as usize;
             }
             if label.is_empty()
@@ -133,15 +363,15 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
                     }
                 }
             {
-                bail!("Not a valid label: {label}");
+                return Err(InvalidLabel(label.to_string()));
             }
             if labels.contains_key(label) {
-                bail!("Label {label} already exists");
+                return Err(DuplicateLabel(label.to_string()));
             }
-            if res.pos == 256 {
-                bail!("Label at invalid position");
+            if res.pos >= res.mem.len() {
+                return Err(LabelPosition);
             }
-            labels.insert(label.to_string(), res.pos as u8);
+            labels.insert(label.to_string(), res.pos as u16);
             s = &s[index + 1..];
         }
         let s = s.trim_start();
@@ -156,9 +386,9 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
                 res.push(Const(0x00))?;
                 s
             }
-            "loadm" => p_rv(s, res, 1)?,
-            "loadb" => p_rv(s, res, 2)?,
-            "storem" => p_rv(s, res, 3)?,
+            "loadm" => p_rv(s, line, res, 1)?,
+            "loadb" => p_rv(s, line, res, 2)?,
+            "storem" => p_rv(s, line, res, 3)?,
             "move" => {
                 let (r1, s) = getr(s)?;
                 let (r2, s) = getr(comma(s)?)?;
@@ -171,8 +401,8 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
             "or" => p_rrr(s, res, 7)?,
             "and" => p_rrr(s, res, 8)?,
             "xor" => p_rrr(s, res, 9)?,
-            "rot" => p_rv(s, res, 10)?,
-            "jump" => p_rv(s, res, 11)?,
+            "rot" => p_rv(s, line, res, 10)?,
+            "jump" => p_rv(s, line, res, 11)?,
             "halt" => {
                 res.push(Const(0xC0))?;
                 res.push(Const(0x00))?;
@@ -180,47 +410,158 @@ pub fn assemble(code: &str) -> Result<Vec<u8>> {
             }
             "loadp" => p_rr(s, res, 13)?,
             "storep" => p_rr(s, res, 14)?,
-            "jumpl" => p_rv(s, res, 15)?,
+            "jumpl" => p_rv(s, line, res, 15)?,
+            "push" | "pop" if isa.ext => {
+                let (reg, s) = getr(s)?;
+                res.push(Const(if mnemonic.eq_ignore_ascii_case("push") {
+                    0x01
+                } else {
+                    0x02
+                }))?;
+                res.push(Const(reg))?;
+                s
+            }
+            "call" if isa.ext => {
+                let (addr, s) = getv(s, line)?;
+                res.push(Const(0xC1))?;
+                res.push(addr)?;
+                s
+            }
+            "ret" if isa.ext => {
+                res.push(Const(0xC2))?;
+                res.push(Const(0x00))?;
+                s
+            }
+            "jz" | "jnz" | "jc" | "jnc" if isa.flags => {
+                let (addr, s) = getv(s, line)?;
+                res.push(Const(match mnemonic.to_ascii_lowercase().as_ref() {
+                    "jz" => 0xC3,
+                    "jnz" => 0xC4,
+                    "jc" => 0xC5,
+                    _ => 0xC6,
+                }))?;
+                res.push(addr)?;
+                s
+            }
+            "push" | "pop" | "call" | "ret" => {
+                return Err(RequiresExtension {
+                    mnemonic: mnemonic.to_string(),
+                    extension: "the extended instruction set (--ext)",
+                });
+            }
+            "jz" | "jnz" | "jc" | "jnc" => {
+                return Err(RequiresExtension {
+                    mnemonic: mnemonic.to_string(),
+                    extension: "the flags extension (--flags)",
+                });
+            }
             "db" => {
-                let (val, s) = getv(s)?;
+                let (val, s) = getv(s, line)?;
                 res.push(val)?;
                 s
             }
             _ => {
-                bail!("Unknown mnemonic: {mnemonic}");
+                return Err(UnknownMnemonic(mnemonic.to_string()));
             }
         };
         let s = s.trim_start();
         if !s.is_empty() {
-            bail!("Unexpected extra content: {s}");
+            return Err(ExtraContent(s.to_string()));
         }
         Ok(())
     }
     let mut labels = HashMap::new();
-    let mut res = Output::new();
+    let mut res = Output::new(size);
     for (i, line) in code.split('\n').enumerate() {
-        parse_line(line.trim(), &mut labels, &mut res)
-            .context(format!("Error on line {}", i + 1))?;
+        res.line = i + 1;
+        parse_line(line.trim(), i + 1, &mut labels, &mut res, isa).map_err(|kind| AsmError {
+            line: i + 1,
+            column: column(line, &kind),
+            kind,
+        })?;
     }
-    res.mem
+    res.mem.truncate(res.end);
+    res.lines.truncate(res.end);
+    let bytes = res
+        .mem
         .into_iter()
         .map(|val| match val {
             Const(val) => Ok(val),
-            Ref(label) => {
-                let (offset, label): (i32, &str) =
-                    if let Some(index) = label.find('+').or_else(|| label.find('-')) {
-                        (label[index..].parse()?, &label[..index])
-                    } else {
-                        (0, &label)
-                    };
-                Ok(u8::try_from(
-                    labels
-                        .get(label)
-                        .ok_or_else(|| anyhow!("Unknown label: {label}"))
-                        .copied()? as i32
-                        + offset,
-                )?)
+            Ref(label, line) => {
+                let resolve = || {
+                    let (offset, name): (i32, &str) =
+                        if let Some(index) = label.find('+').or_else(|| label.find('-')) {
+                            let offset = label[index..]
+                                .parse()
+                                .map_err(|_| InvalidValue(label.clone()))?;
+                            (offset, &label[..index])
+                        } else {
+                            (0, &label)
+                        };
+                    let addr = *labels
+                        .get(name)
+                        .ok_or_else(|| UnknownLabel(name.to_string()))?
+                        as i32
+                        + offset;
+                    if !(0..size as i32).contains(&addr) {
+                        return Err(OutOfBounds {
+                            label: name.to_string(),
+                            offset,
+                        });
+                    }
+                    Ok(addr as u8)
+                };
+                resolve().map_err(|kind| AsmError {
+                    line,
+                    column: column(code.split('\n').nth(line - 1).unwrap_or_default(), &kind),
+                    kind,
+                })
             }
         })
-        .collect()
+        .collect::<std::result::Result<_, _>>()?;
+    let map = SourceMap {
+        lines: res.lines,
+        labels,
+    };
+    Ok((bytes, map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The code, line and column of the error assembling `code`.
+    fn error(code: &str) -> (&'static str, usize, usize) {
+        let err = assemble_with_map(code, Isa::default(), 16).unwrap_err();
+        (err.kind.code(), err.line, err.column)
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(error("halt\n  loadb rx, 1"), ("expected-register", 2, 9));
+        assert_eq!(error("loadb r1, 0x1FF"), ("invalid-value", 1, 11));
+        assert_eq!(error("loadb r1 1"), ("expected-comma", 1, 1));
+        assert_eq!(error("a-b: halt"), ("invalid-label", 1, 1));
+        assert_eq!(error("top: halt\ntop: halt"), ("duplicate-label", 2, 1));
+        let full = "halt\n".repeat(8);
+        assert_eq!(error(&format!("{full}end:")), ("label-position", 9, 1));
+        assert_eq!(error("push r1"), ("requires-extension", 1, 1));
+        assert_eq!(error("  nop"), ("unknown-mnemonic", 1, 3));
+        assert_eq!(error("halt now"), ("extra-content", 1, 6));
+        assert_eq!(error("jump r0, nowhere"), ("unknown-label", 1, 10));
+        assert_eq!(
+            error("jump r0, top+20\ntop: halt"),
+            ("out-of-bounds", 1, 10)
+        );
+        assert_eq!(error(&"halt\n".repeat(9)), ("too-large", 9, 1));
+    }
+
+    #[test]
+    fn messages() {
+        let err = assemble_with_map("push r1", Isa::default(), 256).unwrap_err();
+        assert_eq!(
+            err.kind.to_string(),
+            "push requires the extended instruction set (--ext)"
+        );
+    }
 }
@@ -0,0 +1,132 @@
+use crate::{
+    builder::VmBuilder,
+    device::{Devices, OutputPort},
+    program::{self, InputFormat},
+    vm::{Instr, Isa, Result, VM},
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    mem::{discriminant, Discriminant},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How long the instructions of one kind took across all runs.
+struct Timing {
+    name: String,
+    count: usize,
+    time: Duration,
+}
+
+/// The results of `bench`.
+pub struct Report {
+    runs: usize,
+    steps: usize,
+    time: Duration,
+    /// By instruction, the slowest first.
+    timings: Vec<Timing>,
+}
+
+/// Runs a fresh VM from `build` to completion `runs` times, timing the runs
+/// as a whole, then runs it as many times again one step at a time to time
+/// every kind of instruction, which the timing itself slows down.
+pub fn bench(build: impl Fn() -> VM, runs: usize) -> Result<Report> {
+    let mut steps = 0;
+    let start = Instant::now();
+    for _ in 0..runs {
+        let mut vm = build();
+        vm.run()?;
+        steps += vm.history.len();
+    }
+    let time = start.elapsed();
+    let mut timings: HashMap<Discriminant<Instr>, Timing> = HashMap::new();
+    for _ in 0..runs {
+        let mut vm = build();
+        loop {
+            let instr = vm.dis(vm.pc);
+            let start = Instant::now();
+            let running = vm.step()?;
+            let time = start.elapsed();
+            let timing = timings.entry(discriminant(&instr)).or_insert_with(|| {
+                let name = format!("{instr:?}");
+                Timing {
+                    name: name.split('(').next().unwrap_or_default().to_string(),
+                    count: 0,
+                    time: Duration::ZERO,
+                }
+            });
+            timing.count += 1;
+            timing.time += time;
+            if !running {
+                break;
+            }
+        }
+    }
+    let mut timings: Vec<_> = timings.into_values().collect();
+    timings.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+    Ok(Report {
+        runs,
+        steps,
+        time,
+        timings,
+    })
+}
+
+/// Benchmarks the program in `file`, in `format`, on `memory` bytes with
+/// `isa`, giving up on a run after `max_steps` if nonzero. The program can
+/// print to the output port at 0xFF.
+pub fn file(
+    file: &Path,
+    format: InputFormat,
+    runs: usize,
+    max_steps: usize,
+    memory: usize,
+    isa: Isa,
+) -> anyhow::Result<Report> {
+    let (bytes, _) = program::load(file, format, memory, isa)?;
+    let build = || {
+        VmBuilder::new()
+            .memory_size(memory)
+            .isa(isa)
+            .program(&bytes)
+            .step_limit(Some(max_steps).filter(|&it| it > 0))
+            .devices(Devices {
+                output: Some(OutputPort::new(0xFF)),
+                ..Default::default()
+            })
+            .build()
+    };
+    Ok(bench(build, runs.max(1))?)
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let per_second = self.steps as f64 / self.time.as_secs_f64().max(f64::MIN_POSITIVE);
+        writeln!(
+            f,
+            "{} runs of {} steps in {:.3?}: {:.0} steps per second",
+            self.runs,
+            self.steps / self.runs.max(1),
+            self.time,
+            per_second
+        )?;
+        let total: Duration = self.timings.iter().map(|it| it.time).sum();
+        writeln!(
+            f,
+            "{:<20} {:>10} {:>8} {:>7}",
+            "Instruction", "Count", "ns/step", "Time"
+        )?;
+        for timing in &self.timings {
+            writeln!(
+                f,
+                "{:<20} {:>10} {:>8.1} {:>6.1}%",
+                timing.name,
+                timing.count,
+                timing.time.as_nanos() as f64 / timing.count as f64,
+                timing.time.as_secs_f64() * 100.0 / total.as_secs_f64().max(f64::MIN_POSITIVE)
+            )?;
+        }
+        Ok(())
+    }
+}
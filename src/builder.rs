@@ -0,0 +1,275 @@
+use crate::{
+    device::Devices,
+    memory::Memory,
+    trace::Tracer,
+    vm::{Addr, EndOfMemory, FillPattern, Isa, Reg, Unloaded, VM},
+};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Something a `VmBuilder` was asked to put past the end of memory.
+#[derive(Debug, Error)]
+#[error("The {name} at 0x{addr:02X} would exceed memory bounds")]
+pub struct OutOfBounds {
+    /// What it is, like `screen` or `entry point`.
+    pub name: &'static str,
+    /// Where it starts.
+    pub addr: u16,
+}
+
+/// Configures the initial state of a VM before it starts running.
+pub struct VmBuilder {
+    memory: Option<Box<dyn Memory>>,
+    size: usize,
+    isa: Isa,
+    program: Vec<u8>,
+    fill: FillPattern,
+    data: Vec<(u16, Vec<u8>)>,
+    regs: [u8; 16],
+    entry: Addr,
+    devices: Devices,
+    detect_loops: bool,
+    unloaded: Unloaded,
+    end_of_memory: EndOfMemory,
+    step_limit: Option<usize>,
+    time_limit: Option<Duration>,
+    tracer: Option<Tracer>,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmBuilder {
+    /// 256 bytes of zeroed RAM, the base instruction set and no devices.
+    pub fn new() -> Self {
+        Self {
+            memory: None,
+            size: 256,
+            isa: Isa::default(),
+            program: Vec::new(),
+            fill: FillPattern::default(),
+            data: Vec::new(),
+            regs: [0; 16],
+            entry: Addr(0),
+            devices: Devices::default(),
+            detect_loops: false,
+            unloaded: Unloaded::Ignore,
+            end_of_memory: EndOfMemory::Error,
+            step_limit: None,
+            time_limit: None,
+            tracer: None,
+        }
+    }
+
+    /// Uses plain RAM of `size` bytes, which must be one of `MEMORY_SIZES`.
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self.memory = None;
+        self
+    }
+
+    /// Uses a custom memory backend instead of plain RAM.
+    pub fn memory(mut self, memory: Box<dyn Memory>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Enables the extensions in `isa`.
+    pub fn isa(mut self, isa: Isa) -> Self {
+        self.isa = isa;
+        self
+    }
+
+    /// Loads `program` from address 0.
+    pub fn program(mut self, program: &[u8]) -> Self {
+        self.program = program.to_vec();
+        self
+    }
+
+    /// Fills memory past the program with `fill`.
+    pub fn fill(mut self, fill: FillPattern) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Stores `bytes` from `addr` on, over the program, also when it is
+    /// loaded again.
+    pub fn data(mut self, addr: u16, bytes: &[u8]) -> Self {
+        self.data.push((addr, bytes.to_vec()));
+        self
+    }
+
+    /// Starts with `value` in `reg`, also after a reset.
+    pub fn reg(mut self, reg: Reg, value: u8) -> Self {
+        self.regs[reg.0 as usize] = value;
+        self
+    }
+
+    /// Starts executing at `entry` instead of address 0.
+    pub fn entry(mut self, entry: Addr) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Attaches `devices` to memory.
+    pub fn devices(mut self, devices: Devices) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// See `VM::detect_loops`.
+    pub fn detect_loops(mut self, enable: bool) -> Self {
+        self.detect_loops = enable;
+        self
+    }
+
+    /// See `VM::unloaded`.
+    pub fn unloaded(mut self, unloaded: Unloaded) -> Self {
+        self.unloaded = unloaded;
+        self
+    }
+
+    /// See `VM::end_of_memory`.
+    pub fn end_of_memory(mut self, end_of_memory: EndOfMemory) -> Self {
+        self.end_of_memory = end_of_memory;
+        self
+    }
+
+    /// Fails once this many steps ran without halting.
+    pub fn step_limit(mut self, limit: Option<usize>) -> Self {
+        self.step_limit = limit;
+        self
+    }
+
+    /// Fails once the program ran for this long without halting.
+    pub fn time_limit(mut self, limit: Option<Duration>) -> Self {
+        self.time_limit = limit;
+        self
+    }
+
+    /// Logs every step to `tracer`.
+    pub fn tracer(mut self, tracer: Option<Tracer>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    /// Checks that the entry point, the data and every device fit in
+    /// memory, which `build` leaves to fail once the program runs.
+    pub fn check(&self) -> Result<(), OutOfBounds> {
+        let size = self.memory.as_ref().map_or(self.size, |it| it.len());
+        let data = self
+            .data
+            .iter()
+            .map(|(addr, bytes)| ("data", *addr, bytes.len()));
+        let regions = [("entry point", self.entry.0, 1)]
+            .into_iter()
+            .chain(self.devices.regions())
+            .chain(data);
+        for (name, addr, len) in regions {
+            if addr as usize + len > size {
+                return Err(OutOfBounds { name, addr });
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the VM after checking that everything fits, as `check` does.
+    pub fn try_build(self) -> Result<VM, OutOfBounds> {
+        self.check()?;
+        Ok(self.build())
+    }
+
+    /// Creates the VM, reset and ready to run.
+    pub fn build(self) -> VM {
+        let mut vm = match self.memory {
+            Some(memory) => VM::with_memory(memory),
+            None => VM::with_memory_size(self.size),
+        };
+        vm.isa = self.isa;
+        vm.fill_pattern = self.fill;
+        vm.data = self.data;
+        vm.fill(&self.program);
+        vm.initial_regs = self.regs;
+        vm.entry = self.entry;
+        vm.devices = self.devices;
+        vm.detect_loops(self.detect_loops);
+        vm.unloaded = self.unloaded;
+        vm.end_of_memory = self.end_of_memory;
+        vm.step_limit = self.step_limit;
+        vm.time_limit = self.time_limit;
+        vm.tracer = self.tracer;
+        vm.reset();
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Framebuffer;
+
+    fn with_framebuffer(base: u16) -> VmBuilder {
+        VmBuilder::new().devices(Devices {
+            framebuffer: Some(Framebuffer { base }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn framebuffer_must_fit() {
+        assert!(with_framebuffer(0xE0).check().is_ok());
+        let err = with_framebuffer(0xF0).check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The framebuffer at 0xF0 would exceed memory bounds"
+        );
+    }
+
+    #[test]
+    fn data_must_fit() {
+        assert!(VmBuilder::new().data(0xFF, &[1]).check().is_ok());
+        let err = VmBuilder::new().data(0xFF, &[1, 2]).check().unwrap_err();
+        assert_eq!((err.name, err.addr), ("data", 0xFF));
+    }
+
+    #[test]
+    fn entry_point_must_fit() {
+        let builder = VmBuilder::new().memory_size(16).entry(Addr(16));
+        assert_eq!(builder.check().unwrap_err().name, "entry point");
+    }
+
+    #[test]
+    fn builds_initial_state() {
+        let mut vm = VmBuilder::new()
+            .memory_size(512)
+            .program(&[0x21, 0x05, 0xC0, 0x00])
+            .fill(FillPattern::Byte(0xAA))
+            .data(0x01, &[0x07])
+            .reg(Reg(2), 9)
+            .entry(Addr(0))
+            .build();
+        assert_eq!(vm.memory.len(), 512);
+        assert_eq!(vm.memory.bytes()[..5], [0x21, 0x07, 0xC0, 0x00, 0xAA]);
+        assert_eq!(vm.regs[2], 9);
+        vm.run().unwrap();
+        assert_eq!(vm.regs[1], 7);
+        vm.reset_hard();
+        assert_eq!((vm.regs[1], vm.regs[2], vm.pc.0), (0, 9, 0));
+    }
+
+    #[test]
+    fn random_fill_repeats() {
+        let fill = |seed| {
+            VmBuilder::new()
+                .fill(FillPattern::Random(seed))
+                .build()
+                .memory
+                .bytes()
+        };
+        assert_eq!(fill(1), fill(1));
+        assert_ne!(fill(1), fill(2));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{
+    memory::Memory,
+    vm::{VmError, VM},
+};
+use std::{cell::RefCell, ops::Range, rc::Rc};
+
+/// A range of memory shared between VMs, each of which gets its own
+/// `SharedMemory` on top of it.
+#[derive(Clone)]
+pub struct Window {
+    shared: Rc<RefCell<Vec<u8>>>,
+    start: u16,
+}
+
+impl Window {
+    /// A zeroed window of `len` bytes from `start`.
+    pub fn new(start: u16, len: u16) -> Self {
+        Self {
+            shared: Rc::new(RefCell::new(vec![0; len as usize])),
+            start,
+        }
+    }
+
+    /// The addresses the window covers.
+    pub fn range(&self) -> Range<usize> {
+        let start = self.start as usize;
+        start..start + self.shared.borrow().len()
+    }
+
+    /// Creates `size` bytes of private memory with this window mapped in.
+    pub fn memory(&self, size: usize) -> SharedMemory {
+        SharedMemory {
+            local: vec![0; size],
+            window: self.clone(),
+        }
+    }
+
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.checked_sub(self.start)? as usize;
+        (offset < self.shared.borrow().len()).then_some(offset)
+    }
+}
+
+/// Memory that is private to one VM except for a shared `Window`.
+pub struct SharedMemory {
+    local: Vec<u8>,
+    window: Window,
+}
+
+impl Memory for SharedMemory {
+    fn len(&self) -> usize {
+        self.local.len()
+    }
+
+    fn load(&self, addr: u16) -> u8 {
+        match self.window.offset(addr) {
+            Some(offset) => self.window.shared.borrow()[offset],
+            None => self.local[addr as usize],
+        }
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        match self.window.offset(addr) {
+            Some(offset) => self.window.shared.borrow_mut()[offset] = value,
+            None => self.local[addr as usize] = value,
+        }
+    }
+}
+
+/// Several VMs stepped in lockstep, each with its own registers and memory
+/// except for a shared window. In every round, each running VM executes one
+/// instruction in order, so races between them are reproducible.
+pub struct Cluster {
+    /// The VMs, the first of which runs the main program.
+    pub vms: Vec<VM>,
+    /// The addresses they share.
+    pub window: Range<usize>,
+    /// Whether each VM is still running.
+    pub running: Vec<bool>,
+    /// Which VMs stepped in each round, to undo them.
+    rounds: Vec<Vec<usize>>,
+}
+
+impl Cluster {
+    /// Groups `vms`, whose memory should share `window`.
+    pub fn new(vms: Vec<VM>, window: &Window) -> Self {
+        Self {
+            running: vec![true; vms.len()],
+            vms,
+            window: window.range(),
+            rounds: Vec::new(),
+        }
+    }
+
+    /// Steps every running VM once, returning whether any is still running.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        let mut round = Vec::new();
+        for (i, vm) in self.vms.iter_mut().enumerate() {
+            if self.running[i] {
+                round.push(i);
+                self.running[i] = vm.step()?;
+            }
+        }
+        self.rounds.push(round);
+        Ok(self.running.contains(&true))
+    }
+
+    /// Steps until every VM halted.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Undoes the last round, in reverse so that shared writes unwind in
+    /// order.
+    pub fn undo(&mut self) {
+        if let Some(round) = self.rounds.pop() {
+            for &i in round.iter().rev() {
+                self.vms[i].undo();
+                self.running[i] = true;
+            }
+        }
+    }
+
+    /// Resets every VM and forgets the rounds so far.
+    pub fn reset(&mut self) {
+        for vm in &mut self.vms {
+            vm.reset();
+        }
+        self.running.fill(true);
+        self.rounds.clear();
+    }
+
+    /// Also reloads every program, leaving the shared memory as the first VM
+    /// initializes it.
+    pub fn reset_hard(&mut self) {
+        for vm in self.vms.iter_mut().rev() {
+            vm.reset_hard();
+        }
+        self.running.fill(true);
+        self.rounds.clear();
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, Command};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// A setting in the config file, and the subcommand its table is for, if any.
+struct Entry<'a> {
+    table: Option<&'a str>,
+    key: &'a str,
+    value: &'a Value,
+}
+
+/// Where the config file is: `$XDG_CONFIG_HOME/v8-cpu/config.toml`, or
+/// `~/.config/v8-cpu/config.toml` without it.
+pub fn path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|it| !it.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|it| Path::new(&it).join(".config")))?;
+    Some(dir.join("v8-cpu").join("config.toml"))
+}
+
+/// The settings of a config file: those at the top, and those in a table
+/// for a subcommand.
+fn entries(table: &Table) -> Vec<Entry<'_>> {
+    let mut res = Vec::new();
+    for (key, value) in table {
+        match value {
+            Value::Table(inner) => res.extend(inner.iter().map(|(inner, value)| Entry {
+                table: Some(key),
+                key: inner,
+                value,
+            })),
+            value => res.push(Entry {
+                table: None,
+                key,
+                value,
+            }),
+        }
+    }
+    res
+}
+
+/// Turns a setting into the arguments that give it on the command line.
+fn arguments(long: &str, value: &Value, flag: bool) -> Result<Vec<String>> {
+    Ok(match (value, flag) {
+        (Value::Boolean(true), true) => vec![format!("--{long}")],
+        (Value::Boolean(false), true) => Vec::new(),
+        (_, true) => bail!("{long} is a flag, expected true or false"),
+        (Value::String(s), false) => vec![format!("--{long}={s}")],
+        (Value::Integer(n), false) => vec![format!("--{long}={n}")],
+        (Value::Float(x), false) => vec![format!("--{long}={x}")],
+        (Value::Boolean(b), false) => vec![format!("--{long}={b}")],
+        (Value::Datetime(d), false) => vec![format!("--{long}={d}")],
+        (Value::Array(values), false) => values
+            .iter()
+            .map(|it| match it {
+                Value::Array(_) => bail!("Nested arrays are not supported"),
+                it => arguments(long, it, false),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+        (Value::Table(_), false) => bail!("{long} is an option, expected a value"),
+    })
+}
+
+/// The arguments a config file adds to `cmd`: every setting in its table or
+/// at the top that `cmd` has an option for and `matches` has not been given
+/// on the command line. The settings of the table come first, so they win
+/// when the option can only be given once.
+fn defaults(
+    text: &str,
+    root: &Command,
+    name: Option<&str>,
+    cmd: &Command,
+    matches: &ArgMatches,
+) -> Result<Vec<String>> {
+    let table: Table = text.parse()?;
+    let entries = entries(&table);
+    let has = |cmd: &Command, long: &str| {
+        cmd.get_arguments()
+            .find(|it| it.get_long() == Some(long) && !it.is_hide_set())
+            .cloned()
+    };
+    for entry in &entries {
+        let long = entry.key.replace('_', "-");
+        let known = match entry.table {
+            Some(table) => {
+                let Some(sub) = root.find_subcommand(table) else {
+                    bail!("There is no subcommand {table}");
+                };
+                has(sub, &long).is_some()
+            }
+            None => {
+                has(root, &long).is_some()
+                    || root.get_subcommands().any(|it| has(it, &long).is_some())
+            }
+        };
+        match (known, entry.table) {
+            (true, _) => {}
+            (false, Some(table)) => bail!("There is no option {long} for {table}"),
+            (false, None) => bail!("There is no option {long}"),
+        }
+    }
+    let mut res = Vec::new();
+    for from_table in [true, false] {
+        for entry in &entries {
+            if entry.table.is_some() != from_table || (from_table && entry.table != name) {
+                continue;
+            }
+            let long = entry.key.replace('_', "-");
+            let Some(arg) = has(cmd, &long) else {
+                continue;
+            };
+            let given =
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+            let set = res.iter().any(|it: &String| {
+                it == &format!("--{long}") || it.starts_with(&format!("--{long}="))
+            });
+            if given || set {
+                continue;
+            }
+            let flag = !arg.get_action().takes_values();
+            res.extend(arguments(&long, entry.value, flag)?);
+        }
+    }
+    Ok(res)
+}
+
+/// `args` with the global options in front of a subcommand moved after its
+/// name, since clap takes the name for a file once it has seen an option.
+fn hoist_globals(cmd: &Command, mut args: Vec<OsString>) -> Vec<OsString> {
+    let mut end = 1;
+    while let Some(long) = args
+        .get(end)
+        .and_then(|it| it.to_str())
+        .and_then(|it| it.strip_prefix("--"))
+    {
+        let (long, inline) = match long.split_once('=') {
+            Some((long, _)) => (long, true),
+            None => (long, false),
+        };
+        let Some(global) = cmd
+            .get_arguments()
+            .find(|it| it.is_global_set() && it.get_long() == Some(long))
+        else {
+            break;
+        };
+        end += if !inline && global.get_action().takes_values() {
+            2
+        } else {
+            1
+        };
+    }
+    let sub = args
+        .get(end)
+        .and_then(|it| it.to_str())
+        .is_some_and(|name| cmd.find_subcommand(name).is_some());
+    if sub {
+        args[1..=end].rotate_right(1);
+    }
+    args
+}
+
+/// Parses the command line with the defaults of the config file, if there
+/// is one, filled in for whatever it does not give. Exits on errors in the
+/// command line, like clap does.
+pub fn matches(cmd: Command) -> Result<ArgMatches> {
+    let args = hoist_globals(&cmd, env::args_os().collect());
+    let matches = cmd.clone().get_matches_from(&args);
+    let Some(path) = path().filter(|it| it.exists()) else {
+        return Ok(matches);
+    };
+    let text = fs::read_to_string(&path)
+        .context(format!("Failed to read config from {}", path.display()))?;
+    let (name, sub, sub_matches) = match matches.subcommand() {
+        Some((name, sub_matches)) => (
+            Some(name),
+            cmd.find_subcommand(name).unwrap().clone(),
+            sub_matches,
+        ),
+        None => (None, cmd.clone(), &matches),
+    };
+    let extra = defaults(&text, &cmd, name, &sub, sub_matches)
+        .context(format!("Invalid config {}", path.display()))?;
+    if extra.is_empty() {
+        return Ok(matches);
+    }
+    // The options of a subcommand go after its name
+    let at = match name {
+        Some(name) => args.iter().position(|it| it.as_os_str() == name).unwrap() + 1,
+        None => 1,
+    };
+    let mut args = args;
+    args.splice(at..at, extra.into_iter().map(Into::into));
+    Ok(cmd.get_matches_from(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn cli() -> Command {
+        let steps = || Arg::new("max-steps").long("max-steps");
+        Command::new("v8-cpu")
+            .arg(Arg::new("vim").long("vim").action(ArgAction::SetTrue))
+            .subcommand(
+                Command::new("run")
+                    .arg(steps())
+                    .arg(Arg::new("reg").long("reg").action(ArgAction::Append)),
+            )
+            .subcommand(Command::new("debug").arg(steps()))
+    }
+
+    /// The arguments `text` adds to the command line `args`.
+    fn run(text: &str, args: &[&str]) -> Result<Vec<String>> {
+        let root = cli();
+        let matches = root.clone().try_get_matches_from(args)?;
+        let (name, sub_matches) = matches.subcommand().unwrap();
+        let sub = root.find_subcommand(name).unwrap();
+        defaults(text, &root, Some(name), sub, sub_matches)
+    }
+
+    #[test]
+    fn values() {
+        let text = "max_steps = 0x10\nreg = ['R1=1', \"R2=2\"]\nvim = true";
+        assert_eq!(
+            run(text, &["v8-cpu", "run"]).unwrap(),
+            ["--max-steps=16", "--reg=R1=1", "--reg=R2=2"]
+        );
+    }
+
+    #[test]
+    fn table_wins() {
+        let text = "max_steps = 5\n[run]\nmax_steps = 7";
+        assert_eq!(run(text, &["v8-cpu", "run"]).unwrap(), ["--max-steps=7"]);
+        assert_eq!(run(text, &["v8-cpu", "debug"]).unwrap(), ["--max-steps=5"]);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let text = "max_steps = 5";
+        assert!(run(text, &["v8-cpu", "run", "--max-steps", "9"])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn globals_before_subcommand() {
+        let root = cli().arg(Arg::new("format").long("format").global(true));
+        let hoist = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            hoist_globals(&root, args)
+        };
+        assert_eq!(
+            hoist(&["v8-cpu", "--format", "json", "run", "a.asm"]),
+            ["v8-cpu", "run", "--format", "json", "a.asm"]
+        );
+        assert_eq!(
+            hoist(&["v8-cpu", "--format=json", "run"]),
+            ["v8-cpu", "run", "--format=json"]
+        );
+        // Only a subcommand right after the global options is one
+        assert_eq!(
+            hoist(&["v8-cpu", "--format", "json", "--vim", "run"]),
+            ["v8-cpu", "--format", "json", "--vim", "run"]
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let err = |text| run(text, &["v8-cpu", "run"]).unwrap_err().to_string();
+        assert_eq!(err("max_step = 5"), "There is no option max-step");
+        assert_eq!(err("[debug]\nreg = []"), "There is no option reg for debug");
+        assert_eq!(err("[walk]\nvim = true"), "There is no subcommand walk");
+    }
+}
@@ -0,0 +1,534 @@
+//! A Debug Adapter Protocol server on stdin and stdout, so that editors can
+//! debug assembly with breakpoints in the gutter. It debugs one program
+//! with a single thread and a single stack frame, at the line of the PC.
+
+use crate::{
+    asm::Source,
+    builder::VmBuilder,
+    device::{Devices, OutputPort},
+    program::{self, InputFormat},
+    spec::OUTPUT_PORT,
+    vm::{Addr, Instr, Isa, StopReason, VmError, SP, VM},
+};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+/// The steps a running program takes between checks for a pause.
+const CHUNK: usize = 10_000;
+
+const REGISTERS: i64 = 1;
+const MEMORY: i64 = 2;
+
+/// Reads one message, framed by a `Content-Length` header.
+fn read(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+    let mut body = vec![0; length.context("Message without a Content-Length")?];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// How the program is being run.
+#[derive(PartialEq, Eq)]
+enum Mode {
+    Stopped,
+    Running,
+    /// Stepping over the call at `next`: running until it returns there.
+    Over {
+        next: Addr,
+        sp: u8,
+    },
+    /// Running until the current subroutine returns, leaving `sp` above.
+    Out {
+        sp: u8,
+    },
+}
+
+impl Mode {
+    /// Whether stepping over or out is done.
+    fn reached(&self, vm: &VM) -> bool {
+        match *self {
+            Mode::Over { next, sp } => vm.pc == next && vm.getr(SP).0 == sp,
+            Mode::Out { sp } => {
+                let returned = vm
+                    .history
+                    .last()
+                    .is_some_and(|it| matches!(vm.dis(it.pc), Instr::Ret));
+                returned && vm.getr(SP).0 == sp.wrapping_add(1)
+            }
+            Mode::Stopped | Mode::Running => false,
+        }
+    }
+}
+
+struct Session {
+    vm: VM,
+    source: Source,
+    path: PathBuf,
+    /// How much of the output port has been sent as output events.
+    printed: usize,
+    mode: Mode,
+    done: bool,
+}
+
+struct Adapter {
+    seq: i64,
+    session: Option<Session>,
+    stop_on_entry: bool,
+    out: io::Stdout,
+}
+
+impl Adapter {
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let mut out = self.out.lock();
+        write!(out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value>) -> Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(err) => response["message"] = json!(format!("{err:#}")),
+        }
+        self.send(response)
+    }
+
+    fn session(&mut self) -> Result<&mut Session> {
+        self.session
+            .as_mut()
+            .context("No program has been launched")
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value> {
+        let path = PathBuf::from(args["program"].as_str().context("program is required")?);
+        let isa = Isa {
+            ext: args["ext"].as_bool().unwrap_or(false),
+            flags: args["flags"].as_bool().unwrap_or(false),
+        };
+        let memory = match &args["memory"] {
+            Value::Null => 256,
+            Value::Number(n) => n.as_u64().context("memory must be a size")? as usize,
+            value => crate::parse_memory_size(value.as_str().unwrap_or_default())?,
+        };
+        let (program, source) = program::load(&path, InputFormat::Assembly, memory, isa)?;
+        let vm = VmBuilder::new()
+            .memory_size(memory)
+            .isa(isa)
+            .program(&program)
+            .devices(Devices {
+                output: Some(OutputPort::new(OUTPUT_PORT)),
+                ..Default::default()
+            })
+            .build();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.session = Some(Session {
+            vm,
+            source: source.unwrap(),
+            path,
+            printed: 0,
+            mode: Mode::Stopped,
+            done: false,
+        });
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let session = self.session()?;
+        let lines = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let count = session.source.lines.len();
+        session.vm.breakpoints.clear();
+        let mut res = Vec::new();
+        for line in lines {
+            let line = line["line"].as_u64().unwrap_or(0) as usize;
+            // A breakpoint on a line without code moves to the next one with code
+            let found =
+                (line..=count).find_map(|it| session.source.map.addr(it).map(|addr| (it, addr)));
+            match found {
+                Some((line, addr)) => {
+                    session.vm.breakpoints.insert(Addr(addr));
+                    res.push(json!({ "verified": true, "line": line }));
+                }
+                None => res.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code at or after this line",
+                })),
+            }
+        }
+        Ok(json!({ "breakpoints": res }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value> {
+        let session = self.session()?;
+        let pc = session.vm.pc;
+        let line = session.source.map.line(pc.0).unwrap_or(0);
+        let name = session.source.map.label_at(pc.0).map_or_else(
+            || format!("0x{:02X}", pc.0),
+            |label| format!("{label} (0x{:02X})", pc.0),
+        );
+        Ok(json!({
+            "stackFrames": [{
+                "id": 1,
+                "name": name,
+                "line": line,
+                "column": 1,
+                "instructionPointerReference": format!("0x{:02X}", pc.0),
+                "source": {
+                    "name": session.path.file_name().map(|it| it.to_string_lossy()),
+                    "path": session.path.canonicalize().unwrap_or(session.path.clone()),
+                },
+            }],
+            "totalFrames": 1,
+        }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value> {
+        let vm = &self.session()?.vm;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<_> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS) => {
+                let mut res: Vec<_> = vm
+                    .regs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| {
+                        variable(format!("R{i:X}"), format!("0x{value:02X} ({value})"))
+                    })
+                    .collect();
+                res.push(variable("PC".to_string(), format!("0x{:02X}", vm.pc.0)));
+                if vm.isa.flags {
+                    res.push(variable("Flags".to_string(), format!("0x{:X}", vm.flags)));
+                }
+                res
+            }
+            Some(MEMORY) => vm
+                .memory
+                .bytes()
+                .chunks(16)
+                .enumerate()
+                .map(|(i, row)| {
+                    let bytes: Vec<_> = row.iter().map(|it| format!("{it:02X}")).collect();
+                    variable(format!("0x{:02X}", i * 16), bytes.join(" "))
+                })
+                .collect(),
+            _ => bail!("No such variables"),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Starts the program running in `mode`, from the current instruction.
+    fn resume(&mut self, mode: impl FnOnce(&VM) -> Mode) -> Result<Value> {
+        let session = self.session()?;
+        if session.done {
+            bail!("The program has ended");
+        }
+        session.mode = mode(&session.vm);
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    fn handle(&mut self, request: &Value) -> Result<()> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": 1, "name": "v8-cpu" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY, "expensive": true },
+            ]})),
+            "variables" => self.variables(args),
+            "continue" => self.resume(|_| Mode::Running),
+            "next" => self.resume(|vm| match vm.dis(vm.pc) {
+                Instr::Call(_) => Mode::Over {
+                    next: Addr(vm.pc.0 + 2),
+                    sp: vm.getr(SP).0,
+                },
+                _ => Mode::Stopped,
+            }),
+            "stepIn" => self.resume(|_| Mode::Stopped),
+            "stepOut" => self.resume(|vm| Mode::Out { sp: vm.getr(SP).0 }),
+            "pause" => {
+                let stopped = self.session()?.mode != Mode::Stopped;
+                self.session()?.mode = Mode::Stopped;
+                self.respond(request, Ok(Value::Null))?;
+                if stopped {
+                    self.stopped("pause", None)?;
+                }
+                return Ok(());
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+                if let Some(session) = &mut self.session {
+                    session.done = true;
+                }
+                if command == "terminate" {
+                    self.event("terminated", json!({}))?;
+                }
+                return Ok(());
+            }
+            command => Err(anyhow::anyhow!("{command} is not supported")),
+        };
+        let ok = result.is_ok();
+        self.respond(request, result)?;
+        match command {
+            "launch" if ok => self.event("initialized", json!({}))?,
+            "configurationDone" if self.session.is_some() => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.session()?.mode = Mode::Running;
+                }
+            }
+            "next" | "stepIn" | "stepOut" if ok => {
+                // Every kind of step takes at least one, after which stepping
+                // over a call or out of a subroutine keeps running
+                let session = self.session()?;
+                let stop = session.vm.step().map(|running| match running {
+                    true => StopReason::Budget,
+                    false => StopReason::Halted,
+                });
+                let done = !matches!(stop, Ok(StopReason::Budget))
+                    || session.mode == Mode::Stopped
+                    || session.mode.reached(&session.vm);
+                if done {
+                    self.finish(stop, "step")?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    /// Sends what the program printed since the last time.
+    fn flush_output(&mut self) -> Result<()> {
+        let session = self.session()?;
+        let Some(output) = &session.vm.devices.output else {
+            return Ok(());
+        };
+        let text: String = output.buffer[session.printed..]
+            .iter()
+            .map(|&b| b as char)
+            .collect();
+        session.printed = output.buffer.len();
+        if !text.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": text }))?;
+        }
+        Ok(())
+    }
+
+    /// Reports how a run ended: `reason` is why it was asked to stop.
+    fn finish(
+        &mut self,
+        stop: std::result::Result<StopReason, VmError>,
+        reason: &str,
+    ) -> Result<()> {
+        self.flush_output()?;
+        let session = self.session()?;
+        session.mode = Mode::Stopped;
+        match stop {
+            Ok(StopReason::Halted) => {
+                session.done = true;
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))
+            }
+            Ok(StopReason::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Ok(_) => self.stopped(reason, None),
+            Err(err) => {
+                let message = err.to_string();
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": format!("{message}\n") }),
+                )?;
+                self.stopped("exception", Some(message))
+            }
+        }
+    }
+
+    /// Runs the program a chunk of steps further in its mode, if it is
+    /// running.
+    fn advance(&mut self) -> Result<()> {
+        let session = self.session()?;
+        let vm = &mut session.vm;
+        let mut steps = 0;
+        let mode = &session.mode;
+        let stop = match mode {
+            Mode::Stopped => return Ok(()),
+            Mode::Running => vm.run_steps(CHUNK),
+            _ => vm.run_until(|vm| {
+                steps += 1;
+                mode.reached(vm) || steps >= CHUNK
+            }),
+        };
+        match stop {
+            Ok(StopReason::Budget) => self.flush_output(),
+            Ok(StopReason::Predicate) if steps >= CHUNK => self.flush_output(),
+            stop => self.finish(stop, "step"),
+        }
+    }
+}
+
+/// Serves the Debug Adapter Protocol on stdin and stdout until the client
+/// disconnects.
+pub fn serve() -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(io::stdin().lock());
+        while let Ok(Some(message)) = read(&mut reader) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut adapter = Adapter {
+        seq: 0,
+        session: None,
+        stop_on_entry: false,
+        out: io::stdout(),
+    };
+    run(&mut adapter, rx)
+}
+
+fn run(adapter: &mut Adapter, rx: Receiver<Value>) -> Result<()> {
+    loop {
+        let running = adapter
+            .session
+            .as_ref()
+            .is_some_and(|it| it.mode != Mode::Stopped && !it.done);
+        let message = if running {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match rx.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return Ok(()),
+            }
+        };
+        if let Some(message) = message {
+            if message["type"] == "request" {
+                adapter.handle(&message)?;
+            }
+            if message["command"] == "disconnect" {
+                return Ok(());
+            }
+        }
+        if running {
+            adapter.advance()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(source: &str) -> Adapter {
+        let (program, source) =
+            program::decode(source.into(), InputFormat::Assembly, 256, Isa::default()).unwrap();
+        Adapter {
+            seq: 0,
+            session: Some(Session {
+                vm: VmBuilder::new().program(&program).build(),
+                source: source.unwrap(),
+                path: PathBuf::from("test.asm"),
+                printed: 0,
+                mode: Mode::Stopped,
+                done: false,
+            }),
+            stop_on_entry: false,
+            out: io::stdout(),
+        }
+    }
+
+    #[test]
+    fn reads_framed_messages() {
+        let body = r#"{"seq":1,"type":"request"}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        let mut reader = input.as_bytes();
+        assert_eq!(read(&mut reader).unwrap().unwrap()["seq"], 1);
+        assert!(read(&mut reader).unwrap().is_none());
+        assert!(read(&mut "X-Other: 1\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn breakpoints_move_to_code() {
+        let mut adapter = adapter("; start\nloadb r1, 1\n\nhalt\n; end");
+        let args = json!({ "breakpoints": [{ "line": 1 }, { "line": 3 }, { "line": 5 }] });
+        let res = adapter.set_breakpoints(&args).unwrap();
+        let lines: Vec<_> = res["breakpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|it| {
+                (
+                    it["verified"].as_bool().unwrap(),
+                    it["line"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(lines, [(true, 2), (true, 4), (false, 5)]);
+        let breakpoints = &adapter.session.unwrap().vm.breakpoints;
+        assert!(breakpoints.contains(&Addr(0)) && breakpoints.contains(&Addr(2)));
+    }
+
+    #[test]
+    fn stack_frame_is_at_the_pc() {
+        let mut adapter = adapter("loadb r1, 1\ndone: halt");
+        adapter.session().unwrap().vm.step().unwrap();
+        let frame = &adapter.stack_trace().unwrap()["stackFrames"][0];
+        assert_eq!(frame["line"], 2);
+        assert_eq!(frame["name"], "done (0x02)");
+    }
+}
@@ -0,0 +1,548 @@
+use crate::{
+    memory::Memory,
+    vm::{Action, Addr, Const},
+};
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+
+/// A memory-mapped output port. Every byte stored to `addr` is appended to
+/// `buffer` as a character, on top of landing in memory as usual.
+pub struct OutputPort {
+    /// Where the port is mapped.
+    pub addr: u16,
+    /// Everything written so far.
+    pub buffer: Vec<u8>,
+}
+
+impl OutputPort {
+    /// A port at `addr` that has not been written to.
+    pub fn new(addr: u16) -> Self {
+        Self {
+            addr,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The output so far, one character per byte.
+    pub fn text(&self) -> String {
+        self.buffer.iter().map(|&b| b as char).collect()
+    }
+}
+
+/// A memory-mapped input port. Every load from `addr` consumes one byte of
+/// `data`; once it runs dry, `prompt` (if any) is asked for more, and 0 is
+/// read at the end of input.
+pub struct InputPort {
+    /// Where the port is mapped.
+    pub addr: u16,
+    /// The input so far, including what was prompted for.
+    pub data: Vec<u8>,
+    /// How much of `data` was read.
+    pub pos: usize,
+    /// Asked for more input once `data` runs out, and `None` at the end.
+    pub prompt: Option<Box<dyn FnMut() -> Option<Vec<u8>>>>,
+}
+
+impl InputPort {
+    /// A port at `addr` that reads `data` and then zeroes.
+    pub fn new(addr: u16, data: Vec<u8>) -> Self {
+        Self {
+            addr,
+            data,
+            pos: 0,
+            prompt: None,
+        }
+    }
+
+    /// Asks `prompt` for more input once the data runs out.
+    pub fn with_prompt(mut self, prompt: impl FnMut() -> Option<Vec<u8>> + 'static) -> Self {
+        self.prompt = Some(Box::new(prompt));
+        self
+    }
+
+    /// The next byte of input, asking the prompt for more once it runs out,
+    /// or 0 without any.
+    pub fn read(&mut self) -> u8 {
+        if self.pos == self.data.len() {
+            if let Some(more) = self.prompt.as_mut().and_then(|prompt| prompt()) {
+                self.data.extend(more);
+            }
+        }
+        let Some(&byte) = self.data.get(self.pos) else {
+            return 0;
+        };
+        self.pos += 1;
+        byte
+    }
+}
+
+/// A text screen backed by `WIDTH * HEIGHT` bytes of memory starting at
+/// `base`, one character per byte in row-major order.
+pub struct Screen {
+    /// The address of the top left character.
+    pub base: u16,
+}
+
+impl Screen {
+    /// The number of characters in a row.
+    pub const WIDTH: usize = 16;
+    /// The number of rows.
+    pub const HEIGHT: usize = 4;
+
+    /// The rows of text on the screen. A screen running past the end of
+    /// memory wraps around to address 0.
+    pub fn rows(&self, memory: &dyn Memory) -> impl Iterator<Item = String> {
+        let base = self.base as usize;
+        let rows: Vec<String> = (0..Self::HEIGHT)
+            .map(|row| {
+                (0..Self::WIDTH)
+                    .map(|col| {
+                        let addr = (base + row * Self::WIDTH + col) % memory.len();
+                        match memory.load(addr as u16) {
+                            b @ 0x20..=0x7e => b as char,
+                            _ => ' ',
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        rows.into_iter()
+    }
+}
+
+/// A 1-bit framebuffer backed by `BYTES` bytes of memory starting at `base`.
+/// Each row is two bytes, with the most significant bit being the leftmost
+/// pixel.
+pub struct Framebuffer {
+    /// The address of the first row.
+    pub base: u16,
+}
+
+impl Framebuffer {
+    /// The width and height in pixels.
+    pub const SIZE: usize = 16;
+    /// How many bytes of memory the pixels take up.
+    pub const BYTES: usize = Self::SIZE * Self::SIZE / 8;
+
+    /// Whether the pixel `x` from the left and `y` from the top is set.
+    pub fn pixel(&self, memory: &dyn Memory, x: usize, y: usize) -> bool {
+        let addr = (self.base as usize + y * 2 + x / 8) % memory.len();
+        let byte = memory.load(addr as u16);
+        byte & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Renders two rows of pixels per line of text using half blocks.
+    pub fn lines<'a>(&'a self, memory: &'a dyn Memory) -> impl Iterator<Item = String> + 'a {
+        (0..Self::SIZE).step_by(2).map(move |y| {
+            (0..Self::SIZE)
+                .map(
+                    |x| match (self.pixel(memory, x, y), self.pixel(memory, x, y + 1)) {
+                        (false, false) => ' ',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (true, true) => '█',
+                    },
+                )
+                .collect()
+        })
+    }
+}
+
+/// A one-shot timer. While the byte at `counter` is nonzero, it is decremented
+/// after every step; when it reaches zero, the program counter is saved to
+/// `save` (only the low byte, as it is an address in the current page) and
+/// execution continues at `vector`.
+///
+/// There is no indirect jump, so a handler usually returns with a `jump`
+/// instruction whose address operand is the `save` cell.
+#[derive(Clone, Debug)]
+pub struct Timer {
+    /// The cell counting down the steps until the timer fires.
+    pub counter: u16,
+    /// Where execution continues when it fires.
+    pub vector: u16,
+    /// The cell the interrupted PC is saved to.
+    pub save: u16,
+}
+
+impl Timer {
+    /// What one step does to the timer, given the PC it would continue at:
+    /// count down, fire, or nothing while it is stopped.
+    pub fn tick(&self, memory: &dyn Memory, pc: Addr) -> Option<Action> {
+        let count = memory.load(self.counter);
+        Some(match count {
+            0 => return None,
+            1 => Action::Batch(vec![
+                Action::SetMem(Addr(self.counter), Const(0)),
+                Action::SetMem(Addr(self.save), Const(pc.0 as u8)),
+                Action::Jump(Addr(self.vector)),
+            ]),
+            _ => Action::SetMem(Addr(self.counter), Const(count - 1)),
+        })
+    }
+}
+
+/// A memory-mapped port whose loads return pseudo-random bytes, generated by
+/// xorshift64* from `seed` so that runs are reproducible.
+pub struct Random {
+    /// Where the port is mapped.
+    pub addr: u16,
+    /// What the generator starts from, also after a reset.
+    pub seed: u64,
+    state: u64,
+}
+
+impl Random {
+    /// A port at `addr` generating bytes from `seed`.
+    pub fn new(addr: u16, seed: u64) -> Self {
+        Self {
+            addr,
+            seed,
+            // xorshift gets stuck at zero
+            state: seed.max(1),
+        }
+    }
+
+    /// The next random byte.
+    pub fn read(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
+
+/// Bank-switched memory: the `len` bytes starting at `start` show one of
+/// several banks, selected by storing its number to `control`.
+#[derive(Clone, Debug)]
+pub struct Banks {
+    /// The port selecting the bank.
+    pub control: u16,
+    /// The first address of the window.
+    pub start: u16,
+    /// The size of the window and of every bank.
+    pub len: u16,
+    /// The contents of every bank. The mapped one is only up to date as of
+    /// the last switch, as it lives in memory while mapped.
+    pub data: Vec<Vec<u8>>,
+    /// The bank that is mapped.
+    pub current: usize,
+}
+
+impl Banks {
+    /// `count` zeroed banks of `len` bytes mapped at `start`, with the first
+    /// one mapped.
+    pub fn new(count: usize, control: u16, start: u16, len: u16) -> Self {
+        Self {
+            control,
+            start,
+            len,
+            data: vec![vec![0; len as usize]; count],
+            current: 0,
+        }
+    }
+
+    /// The addresses the banks are mapped at.
+    pub fn window(&self) -> std::ops::Range<usize> {
+        self.start as usize..self.start as usize + self.len as usize
+    }
+
+    /// Maps bank `bank` (modulo the number of banks) into `memory`.
+    pub fn switch(&mut self, memory: &mut dyn Memory, bank: u8) {
+        let bank = bank as usize % self.data.len();
+        for (i, addr) in self.window().enumerate() {
+            self.data[self.current][i] = memory.load(addr as u16);
+            memory.store(addr as u16, self.data[bank][i]);
+        }
+        self.current = bank;
+    }
+}
+
+/// The mutable state of all devices, as captured by `Devices::save`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DeviceState {
+    output: Vec<u8>,
+    input_pos: usize,
+    random: u64,
+    banks: Option<(Vec<Vec<u8>>, usize)>,
+}
+
+/// The part of the device state that reading a port changes, as captured by
+/// `Devices::reads`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadState {
+    input_pos: usize,
+    random: u64,
+}
+
+/// The devices attached to a VM, at most one of each kind.
+#[derive(Default)]
+pub struct Devices {
+    /// See `OutputPort`.
+    pub output: Option<OutputPort>,
+    /// See `InputPort`.
+    pub input: Option<InputPort>,
+    /// See `Screen`.
+    pub screen: Option<Screen>,
+    /// See `Framebuffer`.
+    pub framebuffer: Option<Framebuffer>,
+    /// See `Timer`.
+    pub timer: Option<Timer>,
+    /// See `Random`.
+    pub random: Option<Random>,
+    /// See `Banks`.
+    pub banks: Option<Banks>,
+}
+
+impl Devices {
+    /// What a load from `addr` reads, if a device is mapped there.
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(input) = self.input.as_mut().filter(|it| it.addr == addr) {
+            return Some(input.read());
+        }
+        if let Some(random) = self.random.as_mut().filter(|it| it.addr == addr) {
+            return Some(random.read());
+        }
+        None
+    }
+
+    /// Passes a store to `addr` on to the device mapped there, if any.
+    pub fn write(&mut self, addr: u16, value: u8) {
+        if let Some(output) = self.output.as_mut().filter(|it| it.addr == addr) {
+            output.buffer.push(value);
+        }
+    }
+
+    /// Reverts the side effects of the last `write` to `addr`.
+    pub fn undo_write(&mut self, addr: u16) {
+        if let Some(output) = self.output.as_mut().filter(|it| it.addr == addr) {
+            output.buffer.pop();
+        }
+    }
+
+    /// Puts every device back as it was before the program ran.
+    pub fn reset(&mut self) {
+        if let Some(output) = &mut self.output {
+            output.buffer.clear();
+        }
+        // Input that was already prompted for is kept, so that a rerun sees
+        // the same bytes.
+        if let Some(input) = &mut self.input {
+            input.pos = 0;
+        }
+        if let Some(random) = &mut self.random {
+            *random = Random::new(random.addr, random.seed);
+        }
+    }
+
+    /// Captures the state of the devices, for `restore`.
+    pub fn save(&self) -> DeviceState {
+        DeviceState {
+            output: self
+                .output
+                .as_ref()
+                .map(|it| it.buffer.clone())
+                .unwrap_or_default(),
+            input_pos: self.input.as_ref().map_or(0, |it| it.pos),
+            random: self.random.as_ref().map_or(0, |it| it.state),
+            banks: self.banks.as_ref().map(|it| (it.data.clone(), it.current)),
+        }
+    }
+
+    /// Puts the devices back in a state from `save`.
+    pub fn restore(&mut self, state: &DeviceState) {
+        if let Some(output) = &mut self.output {
+            output.buffer.clone_from(&state.output);
+        }
+        if let Some(input) = &mut self.input {
+            input.pos = state.input_pos;
+        }
+        if let Some(random) = &mut self.random {
+            random.state = state.random;
+        }
+        if let (Some(banks), Some((data, current))) = (&mut self.banks, &state.banks) {
+            banks.data.clone_from(data);
+            banks.current = *current;
+        }
+    }
+
+    /// The name, address and length in bytes of the memory every device
+    /// takes up.
+    pub fn regions(&self) -> Vec<(&'static str, u16, usize)> {
+        let port = |name, addr: Option<u16>| addr.map(|addr| (name, addr, 1));
+        let mut res: Vec<_> = [
+            port("output port", self.output.as_ref().map(|it| it.addr)),
+            port("input port", self.input.as_ref().map(|it| it.addr)),
+            port("random port", self.random.as_ref().map(|it| it.addr)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if let Some(screen) = &self.screen {
+            res.push(("screen", screen.base, Screen::WIDTH * Screen::HEIGHT));
+        }
+        if let Some(framebuffer) = &self.framebuffer {
+            res.push(("framebuffer", framebuffer.base, Framebuffer::BYTES));
+        }
+        if let Some(timer) = &self.timer {
+            res.extend([timer.counter, timer.vector, timer.save].map(|addr| ("timer", addr, 1)));
+        }
+        if let Some(banks) = &self.banks {
+            res.push(("bank control port", banks.control, 1));
+            res.push(("bank window", banks.start, banks.len as usize));
+        }
+        res
+    }
+
+    /// Captures what reading the ports changes, for `set_reads`.
+    pub fn reads(&self) -> ReadState {
+        ReadState {
+            input_pos: self.input.as_ref().map_or(0, |it| it.pos),
+            random: self.random.as_ref().map_or(0, |it| it.state),
+        }
+    }
+
+    /// Puts the ports back to where they were when `reads` captured `state`,
+    /// so that they read the same bytes again.
+    pub fn set_reads(&mut self, state: ReadState) {
+        if let Some(input) = &mut self.input {
+            input.pos = state.input_pos;
+        }
+        if let Some(random) = &mut self.random {
+            random.state = state.random;
+        }
+    }
+
+    /// Hashes the device state that can influence execution.
+    pub fn hash_state<H: Hasher>(&self, state: &mut H) {
+        if let Some(input) = &self.input {
+            input.pos.hash(state);
+            input.data.len().hash(state);
+        }
+        if let Some(random) = &self.random {
+            random.state.hash(state);
+        }
+        if let Some(banks) = &self.banks {
+            banks.current.hash(state);
+            banks.data.hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framebuffer_wraps_at_end_of_memory() {
+        let mut memory = vec![0; 256];
+        memory[0] = 0x80;
+        // Row 7 starts 14 bytes in, past the end of memory
+        let framebuffer = Framebuffer { base: 0xF2 };
+        assert!(framebuffer.pixel(&memory, 0, 7));
+        assert!(!framebuffer.pixel(&memory, 1, 7));
+    }
+
+    #[test]
+    fn regions_cover_every_device() {
+        let devices = Devices {
+            output: Some(OutputPort::new(0xFF)),
+            framebuffer: Some(Framebuffer { base: 0xC0 }),
+            ..Default::default()
+        };
+        assert_eq!(
+            devices.regions(),
+            [("output port", 0xFF, 1), ("framebuffer", 0xC0, 32)]
+        );
+    }
+
+    #[test]
+    fn input_prompts_then_reads_zero() {
+        let mut answers = vec![b"b".to_vec()];
+        let mut input = InputPort::new(0xFE, b"a".to_vec()).with_prompt(move || answers.pop());
+        assert_eq!([input.read(), input.read(), input.read()], [b'a', b'b', 0]);
+        assert_eq!(input.data, b"ab");
+    }
+
+    #[test]
+    fn random_repeats_after_reset() {
+        let mut devices = Devices {
+            random: Some(Random::new(0xF0, 0)),
+            ..Default::default()
+        };
+        let first: Vec<_> = (0..8).map(|_| devices.read(0xF0).unwrap()).collect();
+        assert!(first.iter().any(|&it| it != first[0]));
+        devices.reset();
+        let again: Vec<_> = (0..8).map(|_| devices.read(0xF0).unwrap()).collect();
+        assert_eq!(first, again);
+        assert_eq!(devices.read(0xF1), None);
+    }
+
+    #[test]
+    fn banks_keep_their_contents() {
+        let mut memory = vec![0; 256];
+        let mut banks = Banks::new(2, 0xFD, 0x80, 4);
+        memory[0x80] = 1;
+        banks.switch(&mut memory, 1);
+        assert_eq!(memory[0x80], 0);
+        memory[0x80] = 2;
+        banks.switch(&mut memory, 2);
+        assert_eq!((banks.current, memory[0x80]), (0, 1));
+        banks.switch(&mut memory, 1);
+        assert_eq!(memory[0x80], 2);
+    }
+
+    #[test]
+    fn timer_counts_down_and_fires() {
+        let timer = Timer {
+            counter: 0xF0,
+            vector: 0x40,
+            save: 0xF1,
+        };
+        let mut memory = vec![0; 256];
+        assert!(timer.tick(&memory, Addr(0x10)).is_none());
+        memory[0xF0] = 2;
+        assert!(matches!(
+            timer.tick(&memory, Addr(0x10)),
+            Some(Action::SetMem(Addr(0xF0), Const(1)))
+        ));
+        memory[0xF0] = 1;
+        let Some(Action::Batch(actions)) = timer.tick(&memory, Addr(0x10)) else {
+            panic!("the timer should fire");
+        };
+        assert!(matches!(
+            actions[..],
+            [
+                Action::SetMem(Addr(0xF0), Const(0)),
+                Action::SetMem(Addr(0xF1), Const(0x10)),
+                Action::Jump(Addr(0x40)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn screen_shows_printable_characters() {
+        let mut memory = vec![0; 256];
+        memory[0xC0..0xC3].copy_from_slice(b"hi\n");
+        memory[0xD0] = b'!';
+        let rows: Vec<_> = Screen { base: 0xC0 }.rows(&memory).collect();
+        assert_eq!(rows[0], format!("hi{}", " ".repeat(14)));
+        assert!(rows[1].starts_with('!'));
+    }
+
+    #[test]
+    fn restore_undoes_output_and_reads() {
+        let mut devices = Devices {
+            output: Some(OutputPort::new(0xFF)),
+            input: Some(InputPort::new(0xFE, b"xy".to_vec())),
+            ..Default::default()
+        };
+        devices.read(0xFE);
+        let state = devices.save();
+        devices.write(0xFF, b'!');
+        assert_eq!(devices.read(0xFE), Some(b'y'));
+        devices.restore(&state);
+        assert!(devices.output.as_ref().unwrap().buffer.is_empty());
+        assert_eq!(devices.read(0xFE), Some(b'y'));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{asm::AsmError, vm::VmError};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// How errors are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// A message with its causes, for people
+    Human,
+    /// A line of JSON for every error, as editors and graders read them
+    Json,
+}
+
+/// An error in a program file, with the line of it the error is about, if
+/// known. Displays as the error it wraps, so it only adds where to point.
+#[derive(Debug)]
+pub struct Located {
+    /// The program file.
+    pub file: PathBuf,
+    /// The line, counting from 1.
+    pub line: Option<usize>,
+    error: anyhow::Error,
+}
+
+impl Located {
+    /// Points `error` at `line` of `file`.
+    pub fn new(file: &Path, line: Option<usize>, error: anyhow::Error) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for Located {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// An error as `--error-format json` prints it.
+#[derive(Serialize)]
+struct Record {
+    file: Option<PathBuf>,
+    line: Option<usize>,
+    column: Option<usize>,
+    code: &'static str,
+    message: String,
+}
+
+/// `err` as a line of JSON, pointing at where in which file it happened as
+/// far as it is known. Errors of no particular kind have the code `error`.
+pub fn record(err: &anyhow::Error) -> String {
+    let located = err.chain().find_map(|it| it.downcast_ref::<Located>());
+    // What a `Located` wraps only shows through its display
+    let inner = located.map_or(err, |it| &it.error);
+    let asm = inner.chain().find_map(|it| it.downcast_ref::<AsmError>());
+    let vm = inner.chain().find_map(|it| it.downcast_ref::<VmError>());
+    let (code, message) = match (asm, vm) {
+        (Some(asm), _) => (asm.kind.code(), asm.kind.to_string()),
+        (None, Some(vm)) => (vm.code(), vm.to_string()),
+        (None, None) => ("error", format!("{err:#}")),
+    };
+    let record = Record {
+        file: located.map(|it| it.file.clone()),
+        line: asm.map(|it| it.line).or(located.and_then(|it| it.line)),
+        column: asm.map(|it| it.column),
+        code,
+        message,
+    };
+    serde_json::to_string(&record).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::{decode, InputFormat};
+    use anyhow::{anyhow, Context};
+    use serde_json::{json, Value};
+
+    fn parsed(err: anyhow::Error) -> Value {
+        serde_json::from_str(&record(&err)).unwrap()
+    }
+
+    #[test]
+    fn assembler_error() {
+        let Err(err) = decode(
+            b"halt\n  nop".to_vec(),
+            InputFormat::Assembly,
+            256,
+            Default::default(),
+        ) else {
+            panic!("nop should not assemble")
+        };
+        let err = Located::new(Path::new("a.asm"), None, err).into();
+        assert_eq!(
+            parsed(err),
+            json!({
+                "file": "a.asm",
+                "line": 2,
+                "column": 3,
+                "code": "unknown-mnemonic",
+                "message": "Unknown mnemonic: nop",
+            })
+        );
+    }
+
+    #[test]
+    fn runtime_error() {
+        let err = Located::new(
+            Path::new("a.asm"),
+            Some(4),
+            VmError::PcOverflow { size: 256 }.into(),
+        );
+        let record = parsed(anyhow::Error::from(err).context("Failed to run"));
+        assert_eq!(record["line"], 4);
+        assert_eq!(record["column"], Value::Null);
+        assert_eq!(record["code"], VmError::PcOverflow { size: 256 }.code());
+    }
+
+    #[test]
+    fn other_error() {
+        let err = Err::<(), _>(anyhow!("No such file")).context("Failed to read");
+        assert_eq!(
+            parsed(err.unwrap_err()),
+            json!({
+                "file": null,
+                "line": null,
+                "column": null,
+                "code": "error",
+                "message": "Failed to read: No such file",
+            })
+        );
+    }
+}
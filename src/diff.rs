@@ -0,0 +1,205 @@
+use crate::{
+    builder::{OutOfBounds, VmBuilder},
+    device::{Devices, InputPort, OutputPort},
+    program::{self, InputFormat},
+    vm::{Isa, Reg, StopReason, VmError, VM},
+};
+use crossterm::style::Stylize;
+use std::{fmt::Write, path::Path};
+
+/// How a run ended, as `diff` compares it.
+pub struct Outcome {
+    /// What the run is called in the header of the table.
+    pub name: String,
+    /// The machine as the run left it.
+    pub vm: VM,
+    /// Whether the program halted, or the error that stopped it.
+    pub result: Result<bool, VmError>,
+}
+
+impl Outcome {
+    fn status(&self) -> String {
+        match &self.result {
+            Ok(true) => "halted".to_string(),
+            Ok(false) => "stopped".to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn steps(&self) -> usize {
+        self.vm.history.iter().filter(|it| !it.edit).count()
+    }
+
+    fn output(&self) -> String {
+        let text = self.vm.devices.output.as_ref().map(OutputPort::text);
+        format!("{:?}", text.unwrap_or_default())
+    }
+}
+
+/// What one of the runs of `diff` starts with.
+pub struct Side<'a> {
+    /// Bytes stored to memory before the run, at the address they go to.
+    pub data: &'a [(u16, Vec<u8>)],
+    /// The initial values of registers.
+    pub regs: &'a [(Reg, u8)],
+    /// What the input port at 0xFE reads, if there is one.
+    pub input: Option<Vec<u8>>,
+}
+
+impl Side<'_> {
+    /// Runs `program` to completion, or for `max_steps` if nonzero, failing
+    /// only if the data does not fit in memory.
+    pub fn run(
+        self,
+        name: String,
+        program: &[u8],
+        memory: usize,
+        isa: Isa,
+        max_steps: usize,
+    ) -> Result<Outcome, OutOfBounds> {
+        let builder = VmBuilder::new()
+            .memory_size(memory)
+            .isa(isa)
+            .program(program)
+            .step_limit(Some(max_steps).filter(|&it| it > 0))
+            .devices(Devices {
+                output: Some(OutputPort::new(0xFF)),
+                input: self.input.map(|it| InputPort::new(0xFE, it)),
+                ..Default::default()
+            });
+        let builder = self
+            .regs
+            .iter()
+            .fold(builder, |builder, &(reg, value)| builder.reg(reg, value));
+        let mut vm = self
+            .data
+            .iter()
+            .fold(builder, |builder, (addr, bytes)| builder.data(*addr, bytes))
+            .try_build()?;
+        let result = vm.run().map(|it| it == StopReason::Halted);
+        Ok(Outcome { name, vm, result })
+    }
+}
+
+/// Runs the program in each of `files`, in `format`, from its side as
+/// `Side::run` does. The runs are named after their files, or `left` and
+/// `right` if both are the same.
+pub fn files(
+    files: [&Path; 2],
+    sides: [Side; 2],
+    format: InputFormat,
+    memory: usize,
+    isa: Isa,
+    max_steps: usize,
+) -> anyhow::Result<[Outcome; 2]> {
+    let same = files[0] == files[1];
+    let run = |file: &Path, name: &str, side: Side| -> anyhow::Result<Outcome> {
+        let name = match same {
+            true => name.to_string(),
+            false => file.display().to_string(),
+        };
+        let (bytes, _) = program::load(file, format, memory, isa)?;
+        Ok(side.run(name, &bytes, memory, isa, max_steps)?)
+    };
+    let [left, right] = sides;
+    Ok([run(files[0], "left", left)?, run(files[1], "right", right)?])
+}
+
+/// A table of everything that differs between how `left` and `right` ended:
+/// the status, the steps, the registers, the flags, memory and the output,
+/// with the values of `left` in red and those of `right` in green if `color`
+/// is set. Returns `None` if they ended the same.
+pub fn diff(left: &Outcome, right: &Outcome, color: bool) -> Option<String> {
+    let mut rows: Vec<(String, String, String)> = Vec::new();
+    let mut row = |name: String, a: String, b: String| {
+        if a != b {
+            rows.push((name, a, b));
+        }
+    };
+    row("Status".to_string(), left.status(), right.status());
+    row(
+        "Steps".to_string(),
+        left.steps().to_string(),
+        right.steps().to_string(),
+    );
+    row(
+        "PC".to_string(),
+        format!("{:?}", left.vm.pc),
+        format!("{:?}", right.vm.pc),
+    );
+    for i in 0..16 {
+        row(
+            format!("R{i:X}"),
+            format!("0x{:02X}", left.vm.regs[i]),
+            format!("0x{:02X}", right.vm.regs[i]),
+        );
+    }
+    row(
+        "Flags".to_string(),
+        format!("0x{:X}", left.vm.flags),
+        format!("0x{:X}", right.vm.flags),
+    );
+    let (a, b) = (left.vm.memory.bytes(), right.vm.memory.bytes());
+    let width = if a.len().max(b.len()) > 256 { 3 } else { 2 };
+    for addr in 0..a.len().max(b.len()) {
+        let cell = |bytes: &[u8]| {
+            bytes
+                .get(addr)
+                .map_or("-".to_string(), |it| format!("0x{it:02X}"))
+        };
+        row(format!("mem[0x{addr:0width$X}]"), cell(&a), cell(&b));
+    }
+    row("Output".to_string(), left.output(), right.output());
+    if rows.is_empty() {
+        return None;
+    }
+    let name = rows.iter().map(|it| it.0.len()).max().unwrap_or(0);
+    let value = rows
+        .iter()
+        .map(|it| it.1.len())
+        .chain([left.name.len()])
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    writeln!(out, "{:name$}  {:value$}  {}", "", left.name, right.name).unwrap();
+    for (field, a, b) in rows {
+        let a = format!("{a:value$}");
+        if color {
+            writeln!(out, "{field:name$}  {}  {}", a.red(), b.green()).unwrap();
+        } else {
+            writeln!(out, "{field:name$}  {a}  {b}").unwrap();
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn side(data: &[(u16, Vec<u8>)]) -> Result<Outcome, OutOfBounds> {
+        let side = Side {
+            data,
+            regs: &[],
+            input: None,
+        };
+        // halt
+        side.run("left".to_string(), &[0xC0, 0x00], 256, Isa::default(), 0)
+    }
+
+    #[test]
+    fn data_out_of_bounds() {
+        let err = side(&[(0x200, vec![0xAA])]).err().unwrap();
+        assert_eq!((err.name, err.addr), ("data", 0x200));
+        assert!(side(&[(0xFF, vec![0xAA, 0xBB])]).is_err());
+    }
+
+    #[test]
+    fn same_runs_do_not_differ() {
+        let data = [(0x80, vec![0xAA])];
+        let (left, right) = (side(&data).unwrap(), side(&data).unwrap());
+        assert!(matches!(left.result, Ok(true)));
+        assert_eq!(left.vm.memory.load(0x80), 0xAA);
+        assert!(diff(&left, &right, false).is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::vm::{Instr, Isa};
+use std::fmt::Write;
+
+/// The assembly of an instruction word, or `None` for the reserved encodings
+/// that have no mnemonic.
+pub fn mnemonic(i0: u8, i1: u8, isa: Isa) -> Option<String> {
+    use Instr::*;
+    if Instr::is_reserved(i0, i1, isa) {
+        return Option::None;
+    }
+    let r = |reg: u8| format!("r{reg:x}");
+    Some(match Instr::new(i0, i1, isa) {
+        None => "none".to_string(),
+        LoadFromMemory(reg, addr) => format!("loadm {}, 0x{:02X}", r(reg.0), addr.0),
+        LoadWithConstant(reg, value) => format!("loadb {}, 0x{:02X}", r(reg.0), value.0),
+        StoreToMemory(reg, addr) => format!("storem {}, 0x{:02X}", r(reg.0), addr.0),
+        Move(from, to) => format!("move {}, {}", r(to.0), r(from.0)),
+        AddInt(r0, r1, r2) => format!("addi {}, {}, {}", r(r0.0), r(r1.0), r(r2.0)),
+        AddFloat(r0, r1, r2) => format!("addf {}, {}, {}", r(r0.0), r(r1.0), r(r2.0)),
+        Or(r0, r1, r2) => format!("or {}, {}, {}", r(r0.0), r(r1.0), r(r2.0)),
+        And(r0, r1, r2) => format!("and {}, {}, {}", r(r0.0), r(r1.0), r(r2.0)),
+        Xor(r0, r1, r2) => format!("xor {}, {}, {}", r(r0.0), r(r1.0), r(r2.0)),
+        Rotate(reg, count) => format!("rot {}, {}", r(reg.0), count.0),
+        JumpIfEqual(reg, addr) => format!("jump {}, 0x{:02X}", r(reg.0), addr.0),
+        Halt => "halt".to_string(),
+        LoadFromPointer(r0, r1) => format!("loadp {}, {}", r(r0.0), r(r1.0)),
+        StoreToPointer(r0, r1) => format!("storep {}, {}", r(r0.0), r(r1.0)),
+        JumpIfLess(reg, addr) => format!("jumpl {}, 0x{:02X}", r(reg.0), addr.0),
+        Push(reg) => format!("push {}", r(reg.0)),
+        Pop(reg) => format!("pop {}", r(reg.0)),
+        Call(addr) => format!("call 0x{:02X}", addr.0),
+        Ret => "ret".to_string(),
+        JumpIfZero(addr) => format!("jz 0x{:02X}", addr.0),
+        JumpIfNotZero(addr) => format!("jnz 0x{:02X}", addr.0),
+        JumpIfCarry(addr) => format!("jc 0x{:02X}", addr.0),
+        JumpIfNoCarry(addr) => format!("jnc 0x{:02X}", addr.0),
+        Custom(..) => unreachable!(),
+    })
+}
+
+/// Turns a memory image back into assembly, one instruction word per line,
+/// commented with its address and encoding. Assembling the result with the
+/// same `isa` gives back `bytes`: reserved encodings and a trailing odd byte
+/// come out as `db`.
+pub fn disassemble(bytes: &[u8], isa: Isa) -> String {
+    let width = if bytes.len() > 256 { 3 } else { 2 };
+    let mut res = String::new();
+    for (i, word) in bytes.chunks(2).enumerate() {
+        let addr = i * 2;
+        let encoding: String = word.iter().map(|it| format!("{it:02X}")).collect();
+        let comment = format!("; 0x{addr:0width$X}: {encoding}");
+        let text = match word {
+            &[i0, i1] => mnemonic(i0, i1, isa),
+            _ => None,
+        };
+        if let Some(text) = text {
+            writeln!(res, "{text:<20}{comment}").unwrap();
+            continue;
+        }
+        for (j, byte) in word.iter().enumerate() {
+            let text = format!("db 0x{byte:02X}");
+            if j == 0 {
+                writeln!(res, "{text:<20}{comment}").unwrap();
+            } else {
+                writeln!(res, "{text}").unwrap();
+            }
+        }
+    }
+    res
+}
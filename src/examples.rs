@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use std::{env, fs, path::PathBuf};
+
+/// A program shipped inside the binary, with what it needs shown to be
+/// followed.
+pub struct Example {
+    /// What `examples show` and `examples run` take.
+    pub name: &'static str,
+    /// One line on what it does, for `examples list`.
+    pub description: &'static str,
+    /// The assembly source.
+    pub source: &'static str,
+    /// Where it expects the text screen, if it draws on one.
+    pub screen: Option<u16>,
+    /// Where it expects the framebuffer, if it draws on one.
+    pub framebuffer: Option<u16>,
+    /// Whether its registers are best read as floats.
+    pub floats: bool,
+}
+
+const PLAIN: Example = Example {
+    name: "",
+    description: "",
+    source: "",
+    screen: None,
+    framebuffer: None,
+    floats: false,
+};
+
+/// Every example, in the order `examples list` shows them.
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        name: "loop",
+        description: "Adds up 1 to 10 with a counted loop",
+        source: include_str!("../examples/loop.asm"),
+        ..PLAIN
+    },
+    Example {
+        name: "fib",
+        description: "Writes the Fibonacci numbers that fit in a byte to memory",
+        source: include_str!("../examples/fib.asm"),
+        ..PLAIN
+    },
+    Example {
+        name: "strcpy",
+        description: "Copies a zero-terminated string onto the text screen",
+        source: include_str!("../examples/strcpy.asm"),
+        screen: Some(0xB0),
+        ..PLAIN
+    },
+    Example {
+        name: "float",
+        description: "Adds floating-point numbers",
+        source: include_str!("../examples/float.asm"),
+        floats: true,
+        ..PLAIN
+    },
+    Example {
+        name: "framebuffer",
+        description: "Draws a box with diagonal stripes on the framebuffer",
+        source: include_str!("../examples/framebuffer.asm"),
+        framebuffer: Some(0xD0),
+        ..PLAIN
+    },
+];
+
+/// The example called `name`, ignoring case.
+pub fn find(name: &str) -> Result<&'static Example> {
+    EXAMPLES
+        .iter()
+        .find(|it| it.name.eq_ignore_ascii_case(name))
+        .context(format!("No example named {name}, see v8-cpu examples list"))
+}
+
+/// Every example with its description, one per line.
+pub fn list() -> String {
+    let width = EXAMPLES.iter().map(|it| it.name.len()).max().unwrap_or(0);
+    EXAMPLES
+        .iter()
+        .map(|it| format!("{:width$}  {}\n", it.name, it.description))
+        .collect()
+}
+
+impl Example {
+    /// Writes the source to a file of its own in the temporary directory, for
+    /// running it like any other program.
+    pub fn write(&self) -> Result<PathBuf> {
+        let dir = env::temp_dir().join("v8-cpu-examples");
+        fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(format!("{}.asm", self.name));
+        fs::write(&path, self.source).context(format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
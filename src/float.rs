@@ -0,0 +1,123 @@
+//! The 8-bit floating-point format of the machine: a sign bit, a 3-bit
+//! exponent in excess-4 notation, and a 4-bit mantissa with the radix point
+//! on its left, so that `SEEEMMMM` is `(-1)^S * 0.MMMM * 2^(EEE - 4)`.
+//!
+//! Every value is a multiple of 2^-8, which is how the arithmetic here
+//! represents them.
+
+/// The largest representable magnitude, 0.1111 * 2^3.
+pub const MAX: f64 = 7.5;
+
+/// Splits `x` into its sign and magnitude in units of 2^-8.
+fn decompose(x: u8) -> (u8, u16) {
+    (x >> 7, ((x & 0xf) as u16) << ((x >> 4) & 0x7))
+}
+
+/// Builds the closest value at or below a magnitude in units of 2^-8,
+/// saturating at `MAX`.
+fn compose(sign: u8, fix: u16) -> u8 {
+    let bits = 16 - fix.leading_zeros();
+    let expo = bits.saturating_sub(4);
+    if expo > 7 {
+        return (sign << 7) | 0x7f;
+    }
+    (sign << 7) | ((expo as u8) << 4) | (fix >> expo) as u8
+}
+
+/// The value of the float `x`.
+pub fn decode(x: u8) -> f64 {
+    let (sign, fix) = decompose(x);
+    let value = fix as f64 / 256.;
+    if sign == 1 {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Encodes `value`, truncating towards zero what does not fit in the
+/// mantissa and saturating at `MAX`.
+pub fn encode(value: f64) -> u8 {
+    let sign = (value < 0.) as u8;
+    let fix = (value.abs().min(MAX) * 256.) as u16;
+    compose(sign, fix)
+}
+
+/// Adds two floats the way `ADDF` does.
+pub fn add(a: u8, b: u8) -> u8 {
+    let (a_sign, a_fix) = decompose(a);
+    let (b_sign, b_fix) = decompose(b);
+    let (sign, fix) = if a_sign == b_sign {
+        (a_sign, a_fix + b_fix)
+    } else if a_fix > b_fix {
+        (a_sign, a_fix - b_fix)
+    } else if b_fix > a_fix {
+        (b_sign, b_fix - a_fix)
+    } else {
+        (0, 0)
+    };
+    compose(sign, fix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for x in 0..=0xffu8 {
+            let value = decode(x);
+            assert_eq!(decode(encode(value)), value, "{x:02X}");
+            // Normalized values, with the top mantissa bit set or the lowest
+            // exponent, have just the one encoding
+            if x & 0x8 != 0 || x & 0x70 == 0 {
+                let expected = if x == 0x80 { 0 } else { x };
+                assert_eq!(encode(value), expected, "{x:02X}");
+            }
+        }
+    }
+
+    #[test]
+    fn sign() {
+        assert_eq!(encode(1.5), 0x5c);
+        assert_eq!(encode(-1.5), 0xdc);
+        assert_eq!(decode(0xdc), -1.5);
+        // There is no negative zero to encode
+        assert_eq!(decode(0x80), 0.);
+        assert_eq!(encode(-0.), 0x00);
+    }
+
+    #[test]
+    fn exponent_bias() {
+        assert_eq!(decode(0x48), 0.5);
+        assert_eq!(decode(0x08), 0.5 / 16.);
+        assert_eq!(decode(0x78), 4.);
+        assert_eq!(encode(0.5), 0x48);
+        assert_eq!(encode(1.), 0x58);
+    }
+
+    #[test]
+    fn truncates_towards_zero() {
+        assert_eq!(encode(1.0625), 0x58);
+        assert_eq!(encode(-1.0625), 0xd8);
+        assert_eq!(encode(1. / 256.), 0x01);
+        assert_eq!(encode(1. / 512.), 0x00);
+        assert_eq!(add(encode(1.), encode(0.0625)), 0x58);
+    }
+
+    #[test]
+    fn saturates() {
+        assert_eq!(decode(0x7f), MAX);
+        assert_eq!(encode(100.), 0x7f);
+        assert_eq!(encode(-100.), 0xff);
+        assert_eq!(add(0x7f, 0x7f), 0x7f);
+        assert_eq!(add(0xff, 0xff), 0xff);
+    }
+
+    #[test]
+    fn adds_opposite_signs() {
+        assert_eq!(add(encode(1.5), encode(-1.5)), 0x00);
+        assert_eq!(add(encode(1.5), encode(-0.5)), encode(1.));
+        assert_eq!(add(encode(-1.5), encode(0.5)), encode(-1.));
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{
+    asm::{assemble_with_map, AsmError, AsmErrorKind},
+    device::Random,
+    vm::Isa,
+};
+use anyhow::{bail, Context, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where generated programs keep their data. The program has to end before
+/// it, and the stack of the extended instruction set grows down from the top
+/// of memory above it.
+pub const DATA: (u8, u8) = (0xA0, 0xDF);
+
+/// The mnemonics `gen` can use with `isa`.
+pub fn mnemonics(isa: Isa) -> Vec<&'static str> {
+    let mut res = vec![
+        "loadm", "loadb", "storem", "move", "addi", "addf", "or", "and", "xor", "rot", "jump",
+        "loadp", "storep", "jumpl",
+    ];
+    if isa.ext {
+        res.extend(["push", "pop", "call"]);
+    }
+    if isa.flags {
+        res.extend(["jz", "jnz", "jc", "jnc"]);
+    }
+    res
+}
+
+/// What closes once enough instructions have been generated after it opened.
+enum Closer {
+    /// The label a forward jump skips to.
+    Label(String),
+    /// The pop undoing a push.
+    Pop,
+    /// The end of a counted loop, jumping back to its label.
+    Loop(String),
+}
+
+struct Gen<'a> {
+    rng: Random,
+    ops: &'a [&'a str],
+    lines: Vec<String>,
+    /// Subroutines, placed after the halt.
+    subs: Vec<String>,
+    labels: usize,
+    /// Nested constructs still open, innermost last, with the instructions
+    /// left until they close.
+    open: Vec<(Closer, usize)>,
+}
+
+impl Gen<'_> {
+    fn below(&mut self, n: usize) -> usize {
+        (self.rng.read() as usize | (self.rng.read() as usize) << 8) % n
+    }
+
+    /// A register for values: RD and RE count loops, and RF is the stack
+    /// pointer of the extended instruction set.
+    fn reg(&mut self) -> String {
+        format!("r{:x}", self.below(0xD))
+    }
+
+    fn data(&mut self) -> String {
+        let addr = DATA.0 as usize + self.below((DATA.1 - DATA.0) as usize + 1);
+        format!("0x{addr:02X}")
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("{kind}{}", self.labels)
+    }
+
+    /// The lines of an instruction that keeps running straight on, with
+    /// whatever they need set up first.
+    fn straight(&mut self, op: &str) -> Vec<String> {
+        let (a, mut b, c) = (self.reg(), self.reg(), self.reg());
+        match op {
+            "loadm" | "storem" => vec![format!("{op} {a}, {}", self.data())],
+            "loadb" => vec![format!("loadb {a}, 0x{:02X}", self.rng.read())],
+            "move" => vec![format!("move {a}, {b}")],
+            "addi" | "addf" | "or" | "and" | "xor" => vec![format!("{op} {a}, {b}, {c}")],
+            "rot" => vec![format!("rot {a}, {}", self.below(8))],
+            "loadp" | "storep" => {
+                // The pointer would be overwritten by a load through itself
+                while b == a {
+                    b = self.reg();
+                }
+                vec![
+                    format!("loadb {b}, {}", self.data()),
+                    format!("{op} {a}, {b}"),
+                ]
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn op(&mut self) {
+        let op = self.ops[self.below(self.ops.len())];
+        let lines = match op {
+            "jump" | "jumpl" | "jz" | "jnz" | "jc" | "jnc" => {
+                let looping = self.open.iter().any(|it| matches!(it.0, Closer::Loop(_)));
+                let len = 1 + self.below(4);
+                if op == "jump" && !looping && self.below(2) == 0 {
+                    let label = self.label("loop");
+                    let lines = vec![
+                        format!("loadb re, {}", 1 + self.below(8)),
+                        "loadb rd, 0xFF".to_string(),
+                        format!("{label}:"),
+                    ];
+                    self.open.push((Closer::Loop(label), len));
+                    self.lines.extend(lines);
+                    return;
+                }
+                let label = self.label("skip");
+                let line = match op {
+                    "jump" | "jumpl" => format!("{op} {}, {label}", self.reg()),
+                    _ => format!("{op} {label}"),
+                };
+                self.open.push((Closer::Label(label), len));
+                vec![line]
+            }
+            "push" | "pop" => {
+                let line = format!("push {}", self.reg());
+                let len = 1 + self.below(3);
+                self.open.push((Closer::Pop, len));
+                vec![line]
+            }
+            "call" => {
+                let label = self.label("sub");
+                self.subs.push(format!("{label}:"));
+                for _ in 0..1 + self.below(3) {
+                    let op = self.pick_straight();
+                    let lines = self.straight(op);
+                    self.subs.extend(lines);
+                }
+                self.subs.push("ret".to_string());
+                vec![format!("call {label}")]
+            }
+            op => self.straight(op),
+        };
+        self.lines.extend(lines);
+        for it in &mut self.open {
+            it.1 = it.1.saturating_sub(1);
+        }
+        while self.open.last().is_some_and(|it| it.1 == 0) {
+            self.close();
+        }
+    }
+
+    /// One of the chosen mnemonics that keep running straight on, or `addi`
+    /// if there are none.
+    fn pick_straight(&mut self) -> &'static str {
+        const STRAIGHT: [&str; 12] = [
+            "loadm", "loadb", "storem", "move", "addi", "addf", "or", "and", "xor", "rot", "loadp",
+            "storep",
+        ];
+        let ops: Vec<_> = STRAIGHT
+            .into_iter()
+            .filter(|it| self.ops.contains(it))
+            .collect();
+        if ops.is_empty() {
+            return "addi";
+        }
+        ops[self.below(ops.len())]
+    }
+
+    fn close(&mut self) {
+        let Some((closer, _)) = self.open.pop() else {
+            return;
+        };
+        match closer {
+            Closer::Label(label) => self.lines.push(format!("{label}:")),
+            Closer::Pop => {
+                let reg = self.reg();
+                self.lines.push(format!("pop {reg}"));
+            }
+            Closer::Loop(label) => {
+                let end = format!("{label}_end");
+                self.lines.extend([
+                    "addi re, re, rd".to_string(),
+                    "loadb r0, 0".to_string(),
+                    format!("jump re, {end}"),
+                    format!("jump r0, {label}"),
+                    format!("{end}:"),
+                ]);
+            }
+        }
+    }
+}
+
+/// A random program of `length` instructions picked from `ops`, besides the
+/// ones setting them up, that always halts: jumps only go forward, except
+/// in counted loops, which do not nest. Its data is kept within `DATA`.
+pub fn generate(ops: &[&str], length: usize, seed: u64) -> Result<String> {
+    if ops.is_empty() {
+        bail!("No instructions to generate from");
+    }
+    let mut gen = Gen {
+        rng: Random::new(0, seed),
+        ops,
+        lines: Vec::new(),
+        subs: Vec::new(),
+        labels: 0,
+        open: Vec::new(),
+    };
+    for _ in 0..4 {
+        let line = format!("loadb {}, 0x{:02X}", gen.reg(), gen.rng.read());
+        gen.lines.push(line);
+    }
+    for _ in 0..length {
+        gen.op();
+    }
+    while !gen.open.is_empty() {
+        gen.close();
+    }
+    gen.lines.push("halt".to_string());
+    let mut res = String::new();
+    for line in gen.lines.iter().chain(&gen.subs) {
+        if line.ends_with(':') {
+            res.push_str(line);
+            res.push('\n');
+        } else {
+            res.push_str(&format!("    {line}\n"));
+        }
+    }
+    Ok(res)
+}
+
+/// A program as `v8-cpu gen` writes it: `generate` picking from `ops`, or all
+/// of `mnemonics` if empty, checked to assemble for `memory` bytes and to end
+/// before its data. A comment on top gives the command generating it again,
+/// with the seed picked from the current time if not given.
+pub fn program(
+    ops: &[String],
+    length: usize,
+    seed: Option<u64>,
+    isa: Isa,
+    memory: usize,
+) -> Result<String> {
+    let available = mnemonics(isa);
+    let given: Vec<&str> = ops.iter().map(|it| it.trim()).collect();
+    let ops = if given.is_empty() {
+        available.clone()
+    } else {
+        given.clone()
+    };
+    if let Some(op) = ops.iter().find(|it| !available.contains(it)) {
+        bail!(
+            "Cannot generate {op}, expected one of {}",
+            available.join(", ")
+        );
+    }
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |it| it.as_nanos() as u64)
+    });
+    let code = generate(&ops, length, seed)?;
+    let fits = match assemble_with_map(&code, isa, memory) {
+        Ok((bytes, _)) => bytes.len() <= DATA.0 as usize,
+        Err(AsmError {
+            kind: AsmErrorKind::TooLarge { .. },
+            ..
+        }) => false,
+        Err(err) => return Err(err).context("Generated a program that does not assemble"),
+    };
+    if !fits {
+        bail!(
+            "The program would overlap its data at 0x{:02X}, lower --length",
+            DATA.0
+        );
+    }
+    let mut command = format!("v8-cpu gen --length {length} --seed {seed}");
+    if !given.is_empty() {
+        command += &format!(" --ops {}", given.join(","));
+    }
+    if isa.ext {
+        command += " --ext";
+    }
+    if isa.flags {
+        command += " --flags";
+    }
+    Ok(format!("; Generated by {command}\n{code}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{builder::VmBuilder, vm::StopReason};
+
+    const ISAS: [Isa; 4] = [
+        Isa {
+            ext: false,
+            flags: false,
+        },
+        Isa {
+            ext: true,
+            flags: false,
+        },
+        Isa {
+            ext: false,
+            flags: true,
+        },
+        Isa {
+            ext: true,
+            flags: true,
+        },
+    ];
+
+    #[test]
+    fn programs_assemble_and_halt() {
+        for isa in ISAS {
+            let ops = mnemonics(isa);
+            for seed in 0..50 {
+                let code = generate(&ops, 24, seed).unwrap();
+                let (bytes, _) = assemble_with_map(&code, isa, 256)
+                    .unwrap_or_else(|err| panic!("seed {seed} does not assemble: {err:?}\n{code}"));
+                let mut vm = VmBuilder::new()
+                    .isa(isa)
+                    .program(&bytes)
+                    .step_limit(Some(100_000))
+                    .build();
+                let stop = vm.run();
+                assert!(
+                    matches!(stop, Ok(StopReason::Halted)),
+                    "seed {seed} stopped with {stop:?}\n{code}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seed_repeats_program() {
+        let ops = mnemonics(Isa::default());
+        assert_eq!(
+            generate(&ops, 16, 42).unwrap(),
+            generate(&ops, 16, 42).unwrap()
+        );
+        assert_ne!(
+            generate(&ops, 16, 42).unwrap(),
+            generate(&ops, 16, 43).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_repeats_command() {
+        let ops = ["addi".to_string(), "xor".to_string()];
+        let code = program(&ops, 8, Some(3), ISAS[3], 256).unwrap();
+        assert!(code.starts_with(
+            "; Generated by v8-cpu gen --length 8 --seed 3 --ops addi,xor --ext --flags\n"
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_ops_and_long_programs() {
+        let err = program(&["push".to_string()], 8, Some(0), Isa::default(), 256).unwrap_err();
+        assert!(err.to_string().starts_with("Cannot generate push"));
+        let err = program(&[], 200, Some(0), Isa::default(), 256).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("The program would overlap its data"));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{
+    asm::Source,
+    builder::VmBuilder,
+    program::{self, InputFormat},
+    spec::Spec,
+    vm::Isa,
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::json;
+use std::{
+    fmt::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// The formats `report` can write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// JUnit XML, as CI servers and autograders read it
+    Junit,
+    /// A JSON object with the totals and every case
+    Json,
+}
+
+/// How a case of a spec went.
+pub struct Graded {
+    /// The name of the case.
+    pub name: String,
+    /// Every way the result differs from the expectation, empty if it passed.
+    pub failures: Vec<String>,
+    /// How long the case took to run.
+    pub time: Duration,
+}
+
+/// Runs `program` against every case of `spec`, each from a VM made by
+/// `builder`, or fails if a case is not valid.
+pub fn grade(
+    spec: &Spec,
+    builder: impl Fn() -> VmBuilder,
+    program: &[u8],
+    source: Option<&Source>,
+) -> Result<Vec<Graded>> {
+    spec.cases
+        .iter()
+        .map(|case| {
+            let start = Instant::now();
+            let failures = case
+                .run(builder(), program, source, spec.max_steps)
+                .context(format!("Invalid case {}", case.name))?;
+            Ok(Graded {
+                name: case.name.clone(),
+                failures,
+                time: start.elapsed(),
+            })
+        })
+        .collect()
+}
+
+/// Runs the program in `file`, in `format`, against every case of the spec in
+/// `spec`, each on `memory` bytes with `isa`.
+pub fn cases(
+    file: &Path,
+    spec: &Path,
+    format: InputFormat,
+    memory: usize,
+    isa: Isa,
+) -> Result<Vec<Graded>> {
+    let (bytes, source) = program::load(file, format, memory, isa)?;
+    let spec = Spec::load(spec)?;
+    let builder = || VmBuilder::new().memory_size(memory).isa(isa);
+    grade(&spec, builder, &bytes, source.as_ref())
+}
+
+/// Whether every case passed.
+pub fn passed(results: &[Graded]) -> bool {
+    results.iter().all(|it| it.failures.is_empty())
+}
+
+/// Every case marked as passing or failing, with how it failed, and the
+/// totals after them, as `v8-cpu test` prints them.
+pub fn summary(results: &[Graded]) -> String {
+    let mut out = String::new();
+    let mut failed = 0;
+    for result in results {
+        if result.failures.is_empty() {
+            writeln!(out, "PASS {}", result.name).unwrap();
+        } else {
+            failed += 1;
+            writeln!(out, "FAIL {}", result.name).unwrap();
+            for failure in &result.failures {
+                writeln!(out, "  {failure}").unwrap();
+            }
+        }
+    }
+    writeln!(out, "{} passed, {failed} failed", results.len() - failed).unwrap();
+    out
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            c if c.is_control() && !matches!(c, '\n' | '\t') => {
+                write!(res, "&#x{:X};", c as u32).unwrap()
+            }
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// The results of grading `program` as a report in `format`.
+pub fn report(program: &str, results: &[Graded], format: ReportFormat) -> String {
+    let failed = results.iter().filter(|it| !it.failures.is_empty()).count();
+    let time: Duration = results.iter().map(|it| it.time).sum();
+    match format {
+        ReportFormat::Junit => {
+            let mut out = String::new();
+            writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+            writeln!(
+                out,
+                r#"<testsuite name="{}" tests="{}" failures="{failed}" errors="0" time="{:.6}">"#,
+                escape(program),
+                results.len(),
+                time.as_secs_f64()
+            )
+            .unwrap();
+            for result in results {
+                let open = format!(
+                    r#"  <testcase name="{}" classname="{}" time="{:.6}""#,
+                    escape(&result.name),
+                    escape(program),
+                    result.time.as_secs_f64()
+                );
+                if result.failures.is_empty() {
+                    writeln!(out, "{open}/>").unwrap();
+                    continue;
+                }
+                writeln!(out, "{open}>").unwrap();
+                writeln!(
+                    out,
+                    r#"    <failure message="{}">{}</failure>"#,
+                    escape(&result.failures[0]),
+                    escape(&result.failures.join("\n"))
+                )
+                .unwrap();
+                writeln!(out, "  </testcase>").unwrap();
+            }
+            writeln!(out, "</testsuite>").unwrap();
+            out
+        }
+        ReportFormat::Json => {
+            let cases: Vec<_> = results
+                .iter()
+                .map(|it| {
+                    json!({
+                        "name": it.name,
+                        "passed": it.failures.is_empty(),
+                        "failures": it.failures,
+                        "time": it.time.as_secs_f64(),
+                    })
+                })
+                .collect();
+            let report = json!({
+                "program": program,
+                "passed": results.len() - failed,
+                "failed": failed,
+                "time": time.as_secs_f64(),
+                "cases": cases,
+            });
+            format!("{report:#}\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_map;
+
+    fn results() -> Vec<Graded> {
+        vec![
+            Graded {
+                name: "ok".to_string(),
+                failures: Vec::new(),
+                time: Duration::from_millis(1),
+            },
+            Graded {
+                name: "<bad>".to_string(),
+                failures: vec![
+                    "R1 is 0x02, expected 0x03".to_string(),
+                    "did not halt".to_string(),
+                ],
+                time: Duration::from_millis(2),
+            },
+        ]
+    }
+
+    #[test]
+    fn grades_every_case() {
+        let (program, _) = assemble_with_map("loadb r1, 2\nhalt", Default::default(), 256).unwrap();
+        let spec: Spec = serde_json::from_str(
+            r#"{"cases": [
+                {"name": "two", "expect": {"regs": {"r1": 2}}},
+                {"name": "three", "expect": {"regs": {"r1": 3}}}
+            ]}"#,
+        )
+        .unwrap();
+        let results = grade(&spec, VmBuilder::new, &program, None).unwrap();
+        assert!(!passed(&results));
+        assert!(results[0].failures.is_empty());
+        assert_eq!(results[1].failures, ["R1 is 0x02, expected 0x03"]);
+    }
+
+    #[test]
+    fn summarizes() {
+        assert_eq!(
+            summary(&results()),
+            "PASS ok\nFAIL <bad>\n  R1 is 0x02, expected 0x03\n  did not halt\n1 passed, 1 failed\n"
+        );
+    }
+
+    #[test]
+    fn junit_report() {
+        let report = report("a&b.asm", &results(), ReportFormat::Junit);
+        assert!(report.contains(
+            r#"<testsuite name="a&amp;b.asm" tests="2" failures="1" errors="0" time="0.003000">"#
+        ));
+        assert!(report.contains(r#"<testcase name="ok" classname="a&amp;b.asm" time="0.001000"/>"#));
+        assert!(report.contains(
+            "<failure message=\"R1 is 0x02, expected 0x03\">R1 is 0x02, expected 0x03\ndid not halt</failure>"
+        ));
+        assert!(report.contains(r#"name="&lt;bad&gt;""#));
+    }
+
+    #[test]
+    fn json_report() {
+        let report: serde_json::Value =
+            serde_json::from_str(&report("a.asm", &results(), ReportFormat::Json)).unwrap();
+        assert_eq!(report["passed"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["cases"][1]["passed"], false);
+        assert_eq!(report["cases"][1]["failures"][1], "did not halt");
+    }
+}
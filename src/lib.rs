@@ -0,0 +1,58 @@
+//! The v8-cpu simulator as a library, for web front-ends, graders and other
+//! tools that run programs without the command line.
+//!
+//! [`program`] reads program files, [`asm`] assembles source into a program,
+//! [`builder::VmBuilder`] sets up a [`vm::VM`] to run it, and [`dis`] turns
+//! machine code back into assembly.
+//!
+//! ```
+//! use v8_cpu::{asm, builder::VmBuilder, vm::Isa};
+//!
+//! let program = asm::assemble("loadb r0, 42\nhalt", Isa::default(), 256).unwrap();
+//! let mut vm = VmBuilder::new().program(&program).build();
+//! vm.run().unwrap();
+//! assert_eq!(vm.regs[0], 42);
+//! ```
+
+#![warn(missing_docs)]
+
+/// The assembler, and the map from memory back to source lines.
+pub mod asm;
+/// Timing runs of a program, by instruction.
+pub mod bench;
+/// Setting up a VM before it runs.
+pub mod builder;
+/// Several VMs running side by side on partly shared memory.
+pub mod cluster;
+/// Memory-mapped devices: ports, a screen, a framebuffer, a timer and banks.
+pub mod device;
+/// Errors located in program files, and how to report them.
+pub mod diagnostic;
+/// Running a program twice and comparing how the runs ended.
+pub mod diff;
+/// The disassembler.
+pub mod dis;
+/// The example programs shipped inside the binary.
+pub mod examples;
+/// The 8-bit floating-point format.
+pub mod float;
+/// Random programs for exercising the simulator.
+pub mod gen;
+/// Scoring a program against the cases of a spec.
+pub mod grade;
+/// Finding likely mistakes in assembly source.
+pub mod lint;
+/// The storage a VM runs on.
+pub mod memory;
+/// Hooks into everything a VM does.
+pub mod observer;
+/// Counting where a run spends its steps.
+pub mod profile;
+/// Reading programs as assembly, hex or binary.
+pub mod program;
+/// Test cases of a program, with what it should leave behind.
+pub mod spec;
+/// Logs of executed steps.
+pub mod trace;
+/// The machine and its instruction set.
+pub mod vm;
@@ -0,0 +1,267 @@
+use crate::{
+    asm::assemble_with_map,
+    vm::{Const, Instr, Isa, Reg},
+};
+use anyhow::Context;
+use std::{fmt::Display, path::Path};
+
+/// How bad a `Diagnostic` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Likely a mistake, though the program still assembles.
+    Warning,
+    /// The program does not assemble, or is sure to go wrong when run.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A problem found by `lint`, on the line of the source it is about if any.
+pub struct Diagnostic {
+    /// The line, counting from 1.
+    pub line: Option<usize>,
+    /// How bad it is.
+    pub severity: Severity,
+    /// What is wrong.
+    pub message: String,
+}
+
+/// The mnemonic of a line of assembly, lowercased, without its label and
+/// comment.
+fn mnemonic(line: &str) -> String {
+    let line = line.split(';').next().unwrap_or_default();
+    let line = line.split_once(':').map_or(line, |it| it.1);
+    let word = line.split_whitespace().next().unwrap_or_default();
+    word.to_ascii_lowercase()
+}
+
+/// Assembles `code` and checks it without running it: for errors, jumps past
+/// the program or into the middle of an instruction, instructions no path
+/// from 0x00 reaches, paths running off the end of the program, and whether
+/// any path halts at all. Control flow is followed statically, so code only
+/// reached through the timer interrupt or written by the program itself is
+/// reported as unreachable.
+pub fn lint(code: &str, isa: Isa, size: usize) -> Vec<Diagnostic> {
+    let (bytes, map) = match assemble_with_map(code, isa, size) {
+        Ok(it) => it,
+        Err(err) => {
+            return vec![Diagnostic {
+                line: Some(err.line),
+                severity: Severity::Error,
+                message: err.kind.to_string(),
+            }]
+        }
+    };
+    let mut diagnostics = Vec::new();
+    let mut report = |addr: usize, severity, message| {
+        diagnostics.push(Diagnostic {
+            line: map.line(addr as u16),
+            severity,
+            message,
+        })
+    };
+    let len = bytes.len();
+    let mut reached = vec![false; len];
+    let mut queue = if len > 0 { vec![0] } else { Vec::new() };
+    let mut halts = false;
+    while let Some(addr) = queue.pop() {
+        if std::mem::replace(&mut reached[addr], true) {
+            continue;
+        }
+        let instr = Instr::new(bytes[addr], bytes.get(addr + 1).copied().unwrap_or(0), isa);
+        let target = |it: Const| addr & !0xFF | it.0 as usize;
+        let (next, jump) = match instr {
+            Instr::Halt => {
+                halts = true;
+                (false, None)
+            }
+            Instr::Ret => (false, None),
+            Instr::JumpIfEqual(Reg(0), to) => (false, Some(target(to))),
+            Instr::JumpIfLess(Reg(0), _) => (true, None),
+            Instr::JumpIfEqual(_, to)
+            | Instr::JumpIfLess(_, to)
+            | Instr::Call(to)
+            | Instr::JumpIfZero(to)
+            | Instr::JumpIfNotZero(to)
+            | Instr::JumpIfCarry(to)
+            | Instr::JumpIfNoCarry(to) => (true, Some(target(to))),
+            _ => (true, None),
+        };
+        if next {
+            if addr + 2 < len {
+                queue.push(addr + 2);
+            } else {
+                report(
+                    addr,
+                    Severity::Warning,
+                    "Runs past the end of the program into unloaded memory, is a halt missing?"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(to) = jump {
+            let line = map.line(to as u16);
+            if to >= len {
+                report(
+                    addr,
+                    Severity::Warning,
+                    format!("Jumps to 0x{to:02X}, past the end of the program"),
+                );
+            } else if to > 0 && line.is_some() && map.line(to as u16 - 1) == line {
+                report(
+                    addr,
+                    Severity::Error,
+                    format!(
+                        "Jumps to 0x{to:02X}, into the middle of the instruction on line {}",
+                        line.unwrap()
+                    ),
+                );
+            } else {
+                queue.push(to);
+            }
+        }
+    }
+    if len > 0 && !halts {
+        diagnostics.push(Diagnostic {
+            line: None,
+            severity: Severity::Error,
+            message: "No path through the program reaches a halt".to_string(),
+        });
+    }
+    // Consecutive unreachable instructions, with data in between or not,
+    // are reported once
+    let mut runs = Vec::new();
+    let mut run: Option<(usize, usize)> = None;
+    for (i, text) in code.split('\n').enumerate() {
+        let line = i + 1;
+        let Some(addr) = map.addr(line) else {
+            continue;
+        };
+        if mnemonic(text) == "db" {
+            continue;
+        }
+        if reached[addr as usize] {
+            runs.extend(run.take());
+        } else {
+            run = Some((run.map_or(line, |it| it.0), line));
+        }
+    }
+    runs.extend(run);
+    for (first, last) in runs {
+        diagnostics.push(Diagnostic {
+            line: Some(first),
+            severity: Severity::Warning,
+            message: if first == last {
+                "Unreachable instruction".to_string()
+            } else {
+                format!("Unreachable instructions up to line {last}")
+            },
+        });
+    }
+    diagnostics.sort_by_key(|it| it.line.unwrap_or(usize::MAX));
+    diagnostics
+}
+
+/// Lints the assembly in `file`, listing what `lint` finds one per line along
+/// with the file and line it is about, and whether none of it is an error.
+pub fn file(file: &Path, isa: Isa, size: usize) -> anyhow::Result<(String, bool)> {
+    let code = std::fs::read_to_string(file)
+        .context(format!("Failed to read file from {}", file.display()))?;
+    let diagnostics = lint(&code, isa, size);
+    let mut out = String::new();
+    for it in &diagnostics {
+        match it.line {
+            Some(line) => out += &format!("{}:{line}: ", file.display()),
+            None => out += &format!("{}: ", file.display()),
+        }
+        out += &format!("{}: {}\n", it.severity, it.message);
+    }
+    let ok = diagnostics.iter().all(|it| it.severity != Severity::Error);
+    Ok((out, ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(code: &str) -> Vec<(Option<usize>, Severity, String)> {
+        lint(code, Isa::default(), 256)
+            .into_iter()
+            .map(|it| (it.line, it.severity, it.message))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        assert!(found("loadb r1, 1\nhalt").is_empty());
+    }
+
+    #[test]
+    fn assembler_error() {
+        let found = found("halt\nbogus r1");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, Some(2));
+        assert_eq!(found[0].1, Severity::Error);
+    }
+
+    #[test]
+    fn missing_halt() {
+        let found = found("loadb r1, 1\nloadb r2, 2");
+        assert_eq!(
+            found,
+            [
+                (
+                    Some(2),
+                    Severity::Warning,
+                    "Runs past the end of the program into unloaded memory, is a halt missing?"
+                        .to_string()
+                ),
+                (
+                    None,
+                    Severity::Error,
+                    "No path through the program reaches a halt".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_run() {
+        let found = found("halt\nloadb r1, 1\ndb 0x42\nloadb r2, 2\nloop: jump r0, loop");
+        assert_eq!(
+            found,
+            [(
+                Some(2),
+                Severity::Warning,
+                "Unreachable instructions up to line 5".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn bad_jumps() {
+        let found = found("jump r1, 0x40\njump r1, 0x03\nhalt");
+        assert_eq!(
+            found,
+            [
+                (
+                    Some(1),
+                    Severity::Warning,
+                    "Jumps to 0x40, past the end of the program".to_string()
+                ),
+                (
+                    Some(2),
+                    Severity::Error,
+                    "Jumps to 0x03, into the middle of the instruction on line 2".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,64 +1,172 @@
-mod asm;
+mod config;
+mod dap;
+mod replay;
+mod serve;
+mod simulate;
+mod state;
 mod term;
-mod vm;
+mod ui;
+mod watch;
 
-use crate::asm::assemble;
-use anyhow::{bail, Context, Result};
-use clap::{Parser, ValueEnum};
-use crossterm::{
-    execute,
-    terminal::{Clear, ClearType},
+use v8_cpu::{
+    asm, bench, builder, cluster, device, diagnostic, diff, dis, examples, float, gen, grade, lint,
+    observer, profile, program, spec, trace, vm,
 };
+
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use device::{Banks, Timer};
+use diagnostic::ErrorFormat;
+use program::{InputFormat, OutputFormat};
 use std::{
-    fmt::{Debug, Display},
-    io::stdout,
+    env,
+    fmt::Debug,
+    fs,
+    io::{stdout, IsTerminal},
     path::PathBuf,
+    process::ExitCode,
+    time::Duration,
 };
-use term::TerminalExt;
-use vm::VM;
-
-fn hex_to_bytes(s: String) -> Result<Vec<u8>> {
-    fn parse_line(s: &str, res: &mut Vec<u8>) -> Result<()> {
-        let mut s = s.trim_start();
-        if let Some(index) = s.find(';') {
-            s = &s[..index];
-        }
-        let s = s.trim_end().to_ascii_lowercase();
-        if s.len() % 2 != 0
-            || s.chars()
-                .any(|c| !('0'..='9').contains(&c) && !('a'..='f').contains(&c))
-        {
-            bail!("Invalid hex string: {s}");
-        }
-        res.extend(
-            (0..s.len())
-                .step_by(2)
-                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()),
-        );
-        Ok(())
+use trace::TraceFormat;
+use ui::Theme;
+use vm::{EndOfMemory, Isa, Reg, Unloaded, MEMORY_SIZES};
+
+fn parse_addr(s: &str) -> Result<u16> {
+    let s = s.to_ascii_lowercase();
+    Ok(if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)?
+    } else {
+        s.parse()?
+    })
+}
+
+/// Parses sizes like `512`, `1K` or `4KB`.
+fn parse_memory_size(s: &str) -> Result<usize> {
+    let s = s.to_ascii_lowercase();
+    let s = s.strip_suffix('b').unwrap_or(&s);
+    let size = match s.strip_suffix('k') {
+        Some(kilo) => kilo.parse::<usize>()? * 1024,
+        None => s.parse()?,
+    };
+    if !MEMORY_SIZES.contains(&size) {
+        bail!("Memory size must be one of {MEMORY_SIZES:?}");
     }
-    let mut res = Vec::new();
-    for (i, line) in s.split('\n').enumerate() {
-        parse_line(line, &mut res).context(format!("On line {i}"))?;
+    Ok(size)
+}
+
+/// Parses durations like `5s`, `500ms` or `2m`, or a number of seconds.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim().to_ascii_lowercase();
+    let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, 60.0)
+    } else {
+        (s.as_str(), 1.0)
+    };
+    let number: f64 = number
+        .parse()
+        .ok()
+        .filter(|it: &f64| it.is_finite() && *it >= 0.0)
+        .context("Expected a duration like 5s, 500ms or 2m")?;
+    Ok(Duration::from_secs_f64(number * scale))
+}
+
+fn parse_timer(s: &str) -> Result<Timer> {
+    let addrs = s.split(',').map(parse_addr).collect::<Result<Vec<_>>>()?;
+    let &[counter, vector, save] = addrs.as_slice() else {
+        bail!("Expected three comma-separated addresses");
+    };
+    Ok(Timer {
+        counter,
+        vector,
+        save,
+    })
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    u8::try_from(parse_addr(s)?).context("Expected a byte")
+}
+
+/// Parses `R1=0x10`.
+fn parse_reg_value(s: &str) -> Result<(Reg, u8)> {
+    let Some((reg, value)) = s.split_once('=') else {
+        bail!("Expected a register and a value like R1=0x10");
+    };
+    let reg = asm::parse_reg(reg).context(format!("Not a register: {reg}"))?;
+    Ok((Reg(reg), parse_byte(value.trim())?))
+}
+
+fn parse_shared(s: &str) -> Result<(u16, u16)> {
+    let addrs = s.split(',').map(parse_addr).collect::<Result<Vec<_>>>()?;
+    let &[start, len] = addrs.as_slice() else {
+        bail!("Expected two comma-separated values");
+    };
+    if len == 0 {
+        bail!("The shared memory must not be empty");
     }
-    Ok(res)
+    Ok((start, len))
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
-pub enum InputFormat {
-    Assembly,
-    Hex,
-    Binary,
+fn parse_banks(s: &str) -> Result<Banks> {
+    let (count, rest) = s
+        .split_once(',')
+        .context("Expected four comma-separated values")?;
+    let count: usize = count.parse()?;
+    if count == 0 {
+        bail!("There must be at least one bank");
+    }
+    let addrs = rest
+        .split(',')
+        .map(parse_addr)
+        .collect::<Result<Vec<_>>>()?;
+    let &[control, start, len] = addrs.as_slice() else {
+        bail!("Expected four comma-separated values");
+    };
+    if len == 0 {
+        bail!("The bank window must not be empty");
+    }
+    // The window may end right at the end of the address space, but not past it
+    if start.checked_add(len - 1).is_none() {
+        bail!("The bank window runs past the end of the address space");
+    }
+    let banks = Banks::new(count, control, start, len);
+    if banks.window().contains(&(control as usize)) {
+        bail!("The bank control port must lie outside the bank window");
+    }
+    Ok(banks)
 }
 
-impl Display for InputFormat {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            InputFormat::to_possible_value(self).unwrap().get_name()
-        )
+/// Parses `0x80=deadbeef`, or `0x80=@file.bin` (or with a `:`) for the
+/// contents of a file.
+fn parse_data(s: &str) -> Result<(u16, Vec<u8>)> {
+    let Some(index) = s.find(['=', ':']) else {
+        bail!("Expected an address and bytes like 0x80=deadbeef or 0x80=@file.bin");
+    };
+    let addr = parse_addr(&s[..index])?;
+    let bytes = &s[index + 1..];
+    let bytes = if bytes.starts_with('@') {
+        read_input(bytes)?
+    } else {
+        let Ok(bytes) = program::hex_to_bytes(bytes.to_string()) else {
+            bail!("Expected hex bytes, got {bytes}");
+        };
+        bytes
+    };
+    if bytes.is_empty() {
+        bail!("Expected at least one byte");
     }
+    Ok((addr, bytes))
+}
+
+/// Reads `@path` as the contents of the file, and anything else literally.
+fn read_input(s: &str) -> Result<Vec<u8>> {
+    Ok(match s.strip_prefix('@') {
+        Some(path) => std::fs::read(path).context(format!("Failed to read input from {path}"))?,
+        None => s.as_bytes().to_vec(),
+    })
 }
 
 #[derive(Parser, Debug)]
@@ -66,43 +174,756 @@ impl Display for InputFormat {
 #[command(author = "Mivik")]
 #[command(version = "0.1")]
 #[command(about = "An interactive terminal UI to simulate v8-cpu programs", long_about = None)]
-struct Args {
-    file: PathBuf,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
+    /// Without a subcommand, the program to debug, or the programs to run with -q
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    args: Args,
+
+    /// How to print errors, including those of the assembler and of the running program
+    #[arg(long, value_name = "format", default_value = "human", global = true)]
+    error_format: ErrorFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Run the program to completion and print the final state. Given several programs, run
+    /// each of them and print a table of the results
+    Run {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Debug the program in the interactive UI
+    Debug {
+        file: PathBuf,
+
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Debug the program in the interactive UI, recording the input, random seed and debugger
+    /// commands to a file
+    Record {
+        file: PathBuf,
+
+        /// The file to write the recording to
+        #[arg(short, long, value_name = "file")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Play back a recording made with record, then continue from where it ended, or from
+    /// --to-step. Pass the options the recording was made with again
+    Replay {
+        recording: PathBuf,
+
+        file: PathBuf,
+
+        #[command(flatten)]
+        args: Args,
+    },
+
+    /// Assemble the program to a hex or binary file
+    Asm {
+        file: PathBuf,
+
+        /// The file to write, the input file with the extension of the format by default, or -
+        /// for stdout, to pipe the program into other tools
+        #[arg(short, long, value_name = "file")]
+        output: Option<PathBuf>,
+
+        /// The format of the output file
+        #[arg(short, long, value_name = "format", default_value = "hex")]
+        format: OutputFormat,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Run the program to completion repeatedly, reporting the steps per second and the time
+    /// every kind of instruction takes
+    Bench {
+        file: PathBuf,
+
+        /// The format of the input file
+        #[arg(short, long, value_name = "format", default_value_t = InputFormat::Assembly)]
+        format: InputFormat,
+
+        /// How many times to run the program
+        #[arg(long, value_name = "runs", default_value_t = 10)]
+        runs: usize,
+
+        /// Give up on a run after this many steps, 0 for no limit
+        #[arg(long, value_name = "steps", default_value_t = 1_000_000)]
+        max_steps: usize,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Run two programs, or one program with two sets of data, to completion and list every
+    /// register and cell that ended up different. Fails if anything did
+    Diff {
+        left: PathBuf,
+
+        /// The other program, the same one if not given
+        right: Option<PathBuf>,
+
+        /// The format of the input files
+        #[arg(short, long, value_name = "format", default_value_t = InputFormat::Assembly)]
+        format: InputFormat,
+
+        /// Store bytes into the memory of the left run, as --data does
+        #[arg(long, value_name = "addr=bytes", value_parser = parse_data)]
+        left_data: Vec<(u16, Vec<u8>)>,
+
+        /// Store bytes into the memory of the right run, as --data does
+        #[arg(long, value_name = "addr=bytes", value_parser = parse_data)]
+        right_data: Vec<(u16, Vec<u8>)>,
+
+        /// Start the left run with a value in a register, as --reg does
+        #[arg(long, value_name = "reg=value", value_parser = parse_reg_value)]
+        left_reg: Vec<(Reg, u8)>,
+
+        /// Start the right run with a value in a register, as --reg does
+        #[arg(long, value_name = "reg=value", value_parser = parse_reg_value)]
+        right_reg: Vec<(Reg, u8)>,
+
+        /// The bytes the left run reads from the input port at 0xFE, as --input takes them
+        #[arg(long, value_name = "input")]
+        left_input: Option<String>,
+
+        /// The bytes the right run reads from the input port at 0xFE, as --input takes them
+        #[arg(long, value_name = "input")]
+        right_input: Option<String>,
+
+        /// Give up on a run after this many steps, 0 for no limit
+        #[arg(long, value_name = "steps", default_value_t = 1_000_000)]
+        max_steps: usize,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Check the program without running it, for jumps past the program or into the middle of
+    /// an instruction, unreachable code and missing halts
+    Lint {
+        file: PathBuf,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Run the program against the cases of a JSON test spec, reporting which pass
+    Test {
+        file: PathBuf,
+
+        /// The test spec, listing cases with the registers, memory and input to start with,
+        /// and the registers, memory and output expected at the end
+        spec: PathBuf,
+
+        /// The format of the input file
+        #[arg(short, long, value_name = "format", default_value_t = InputFormat::Assembly)]
+        format: InputFormat,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Run the program against the cases of a JSON test spec, as test does, writing a JUnit
+    /// XML or JSON report for course infrastructure. Fails if any case does
+    Grade {
+        file: PathBuf,
+
+        /// The test spec, as for test
+        spec: PathBuf,
+
+        /// The format of the input file
+        #[arg(short, long, value_name = "format", default_value_t = InputFormat::Assembly)]
+        format: InputFormat,
+
+        /// The format of the report
+        #[arg(long, value_name = "format", default_value = "junit")]
+        report: grade::ReportFormat,
+
+        /// Write the report here instead of to stdout
+        #[arg(short, long, value_name = "file")]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Generate a random program that always halts, for fuzzing the simulator or as an
+    /// exercise in reading assembly
+    Gen {
+        /// The number of instructions to pick, besides the ones setting them up
+        #[arg(short, long, value_name = "count", default_value_t = 24)]
+        length: usize,
+
+        /// The mnemonics to pick from, separated by commas, all available ones if not given.
+        /// push and pop come in pairs
+        #[arg(long, value_name = "mnemonics", value_delimiter = ',')]
+        ops: Vec<String>,
+
+        /// The seed of the generator, picked from the current time if not given
+        #[arg(long, value_name = "seed")]
+        seed: Option<u64>,
+
+        /// Write the program here instead of to stdout
+        #[arg(short, long, value_name = "file")]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Disassemble a hex or binary file, printing assembly that assembles back to it
+    Dis {
+        file: PathBuf,
+
+        /// The format of the input file
+        #[arg(short, long, value_name = "format", default_value_t = InputFormat::Hex)]
+        format: InputFormat,
+
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// List, print or run the example programs that come with the simulator
+    Examples {
+        #[command(subcommand)]
+        command: ExamplesCmd,
+    },
+
+    /// Serve a JSON API over HTTP to load a program, step and run it, set breakpoints and
+    /// read the state, for web front-ends and classroom dashboards
+    Serve {
+        /// The address and port to listen on
+        #[arg(long, value_name = "addr", default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Give up on a POST to /run after this many steps, 0 for no limit
+        #[arg(long, value_name = "steps", default_value_t = 1_000_000)]
+        max_steps: usize,
 
+        #[command(flatten)]
+        target: Target,
+    },
+
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for debugging from editors such
+    /// as VS Code with breakpoints in the gutter
+    Dap,
+
+    /// Print a completion script for bash, zsh, fish, elvish or PowerShell, as in
+    /// `v8-cpu completions bash > /etc/bash_completion.d/v8-cpu`
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
+
+// Parsed once, so the size of Run does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum ExamplesCmd {
+    /// List the examples
+    List,
+
+    /// Print the source of an example, to save and change it
+    Show { name: String },
+
+    /// Run an example, in the interactive UI unless told otherwise, with the screen,
+    /// framebuffer or floating-point view it needs
+    Run {
+        name: String,
+
+        #[command(flatten)]
+        args: Args,
+    },
+}
+
+/// The machine the subcommands other than `run` and `debug` assemble for.
+#[derive(clap::Args, Debug)]
+struct Target {
+    /// The size of memory, one of 256, 512, 1K and 4K
+    #[arg(short, long, value_name = "size", default_value = "256", value_parser = parse_memory_size)]
+    memory: usize,
+
+    /// Enable the extended instruction set
+    #[arg(long)]
+    ext: bool,
+
+    /// Enable the flags extension
+    #[arg(long)]
+    flags: bool,
+}
+
+impl Target {
+    fn isa(&self) -> Isa {
+        Isa {
+            ext: self.ext,
+            flags: self.flags,
+        }
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct Args {
     /// The format of the input file
     #[arg(short, long, value_name = "format", default_value_t = InputFormat::Assembly)]
     format: InputFormat,
 
+    /// Start with a value in a register, as in R1=0x10, also after a restart. Repeat for more
+    /// registers
+    #[arg(long, value_name = "reg=value", value_parser = parse_reg_value)]
+    reg: Vec<(Reg, u8)>,
+
+    /// Store bytes into memory over the program, given as hex (0x80=deadbeef) or a file
+    /// (0x80=@data.bin), to swap data sets without assembling again. Repeat for more regions
+    #[arg(long, value_name = "addr=bytes", value_parser = parse_data)]
+    data: Vec<(u16, Vec<u8>)>,
+
+    /// Set a breakpoint at an address or label, repeat for more. Quiet mode prints the state
+    /// every time one is reached, then runs on
+    #[arg(long = "break", value_name = "addr")]
+    breakpoints: Vec<String>,
+
+    /// Start executing at this address or label instead of 0x00, also after a restart, to try
+    /// out a subroutine on its own or skip data at the start of an image
+    #[arg(long, value_name = "addr")]
+    entry: Option<String>,
+
+    /// Open the interactive UI with the memory view scrolled to this address or label and its
+    /// cell selected
+    #[arg(long, value_name = "addr")]
+    focus: Option<String>,
+
+    /// Also run until the program counter first reaches --focus, stopping early at halts and
+    /// breakpoints like any run
+    #[arg(long)]
+    run_to_focus: bool,
+
     /// Enable quiet mode, only outputing the final result
     #[arg(short, long)]
     quiet: bool,
+
+    /// Print the final state as plain text, without colors or any other terminal control
+    /// sequences, implying quiet mode
+    #[arg(long, conflicts_with_all = ["json", "hot_reload", "core"])]
+    plain: bool,
+
+    /// Run the program again whenever its file changes, printing the result every time, which
+    /// implies quiet mode
+    #[arg(long, conflicts_with_all = ["hot_reload", "core"])]
+    watch: bool,
+
+    /// Print one row of the summary table of several files instead of the final state,
+    /// padding the file name to this width
+    #[arg(skip)]
+    summary: Option<usize>,
+
+    /// Print the final result as JSON and nothing else, without clearing the screen, implying
+    /// quiet mode
+    #[arg(long, conflicts_with_all = ["hot_reload", "profile", "coverage", "core"])]
+    json: bool,
+
+    /// The size of memory, one of 256, 512, 1K and 4K. 8-bit addresses in instructions refer to
+    /// the 256-byte page of the program counter
+    #[arg(short, long, value_name = "size", default_value = "256", value_parser = parse_memory_size)]
+    memory: usize,
+
+    /// Enable the extended instruction set, adding PUSH, POP, CALL and RET with RF as the stack
+    /// pointer
+    #[arg(long)]
+    ext: bool,
+
+    /// Enable the flags extension: arithmetic and logic set zero and carry flags, and JZ, JNZ,
+    /// JC and JNC branch on them
+    #[arg(long)]
+    flags: bool,
+
+    /// What to do when the program counter runs past the end of the program into zero-filled
+    /// memory
+    #[arg(long, value_name = "action", default_value = "warn")]
+    unloaded: Unloaded,
+
+    /// What happens once the program counter reaches the end of memory
+    #[arg(long, value_name = "action", default_value = "error")]
+    end_of_memory: EndOfMemory,
+
+    /// Stop with a diagnostic once the machine state repeats, since the program would never halt
+    #[arg(long)]
+    detect_loops: bool,
+
+    /// Give up after this many steps in quiet mode, printing the state so far and exiting with
+    /// code 3, so that a program stuck in a loop cannot hang a script. 0 runs without a limit
+    #[arg(long, value_name = "steps", default_value_t = 1_000_000)]
+    max_steps: usize,
+
+    /// Give up after running this long in quiet mode, like 5s or 500ms, printing the state so far
+    /// and exiting with code 4, for runs slowed down by tracing or devices
+    #[arg(long, value_name = "duration", value_parser = parse_duration)]
+    time_limit: Option<Duration>,
+
+    /// The address of the memory-mapped character output port
+    #[arg(long, value_name = "addr", default_value = "0xFF", value_parser = parse_addr)]
+    output_port: u16,
+
+    /// The address of the memory-mapped input port; maps it to 0xFE if only --input is given
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    input_port: Option<u16>,
+
+    /// The bytes read from the input port, either a string or @file. Without it, input is
+    /// prompted for when needed
+    #[arg(long, value_name = "input")]
+    input: Option<String>,
+
+    /// Show the 16x4 bytes of memory starting at this address as a text screen
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    screen: Option<u16>,
+
+    /// Show the 32 bytes of memory starting at this address as a 16x16 monochrome framebuffer
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    framebuffer: Option<u16>,
+
+    /// Enable the timer interrupt, given the addresses of its counter, interrupt vector and
+    /// the cell the program counter is saved to
+    #[arg(long, value_name = "counter,vector,save", value_parser = parse_timer)]
+    timer: Option<Timer>,
+
+    /// The address of a memory-mapped port that reads as pseudo-random bytes
+    #[arg(long, value_name = "addr", value_parser = parse_addr)]
+    random: Option<u16>,
+
+    /// Fill memory past the program with this byte instead of zeros
+    #[arg(long, value_name = "byte", value_parser = parse_byte, conflicts_with = "fill_random")]
+    fill: Option<u8>,
+
+    /// Fill memory past the program with pseudo-random bytes, seeded like the random port
+    #[arg(long)]
+    fill_random: bool,
+
+    /// The seed of the random port and --fill-random, for runs that are the same every time.
+    /// Picked from the current time if not given, and printed with the result in quiet mode
+    #[arg(long, value_name = "seed")]
+    seed: Option<u64>,
+
+    /// Enable bank switching, given the number of banks, the address of the port selecting the
+    /// mapped bank, and the start and length of the window it is mapped to
+    #[arg(long, value_name = "count,control,start,len", value_parser = parse_banks)]
+    banks: Option<Banks>,
+
+    /// Start from the machine state saved in a .v8state file, on top of the program
+    #[arg(long, value_name = "file")]
+    load_state: Option<PathBuf>,
+
+    /// Save the final machine state to a .v8state file
+    #[arg(long, value_name = "file")]
+    dump_state: Option<PathBuf>,
+
+    /// Write the final contents of memory to a binary file
+    #[arg(long, value_name = "file")]
+    dump_mem: Option<PathBuf>,
+
+    /// Write the final registers, program counter and flags to a JSON file
+    #[arg(long, value_name = "file")]
+    dump_regs: Option<PathBuf>,
+
+    /// Write what every executed step did to a file
+    #[arg(long, value_name = "file")]
+    trace: Option<PathBuf>,
+
+    /// The format of the trace file, JSON lines for a .json or .jsonl file and text otherwise
+    #[arg(long, value_name = "format", requires = "trace")]
+    trace_format: Option<TraceFormat>,
+
+    /// Record the input, random seed and debugger commands of this session to a file
+    #[arg(long, value_name = "file")]
+    record: Option<PathBuf>,
+
+    /// Play back a session recorded with --record, then continue from where it ended
+    #[arg(long, value_name = "file", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// Stop the playback of --replay at this step, from which the interactive UI can go on or
+    /// redo the rest
+    #[arg(long, value_name = "step")]
+    to_step: Option<usize>,
+
+    /// Also show every register as an 8-bit float; toggle it with F in the interactive UI
+    #[arg(long)]
+    floats: bool,
+
+    /// How many steps per second play mode (Space in the interactive UI) runs, from 1 to 60;
+    /// adjust it with + and - while playing
+    #[arg(long, value_name = "steps", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=term::MAX_SPEED as i64))]
+    speed: u32,
+
+    /// Move the selected memory cell with H, J, K and L in the interactive UI, typing into the
+    /// program with I and saving and loading points with M and '
+    #[arg(long)]
+    vim: bool,
+
+    /// Draw the interactive UI below the shell prompt instead of taking over the terminal, so
+    /// that it stays in the scrollback and in terminal recordings
+    #[arg(long)]
+    inline: bool,
+
+    /// Use no colors, marking highlighted values with characters instead, as the NO_COLOR
+    /// environment variable does too. Same as --theme mono
+    #[arg(long)]
+    no_color: bool,
+
+    /// Assemble the program again and restart it whenever its source file changes, keeping
+    /// breakpoints, watches and snapshots
+    #[arg(long, conflicts_with = "quiet")]
+    hot_reload: bool,
+
+    /// The colors of the UI, for dark or light terminal backgrounds
+    #[arg(long, value_enum, default_value_t = Theme::Dark)]
+    theme: Theme,
+
+    /// Print how often every instruction ran and every cell was accessed once done
+    #[arg(long)]
+    profile: bool,
+
+    /// Print which instructions of the program were executed once done
+    #[arg(long)]
+    coverage: bool,
+
+    /// Run another program on an extra core, stepped in lockstep with the main one and sharing
+    /// the memory given by --shared. Repeat for more cores. Devices other than the output port
+    /// are only attached to the main core
+    #[arg(long, value_name = "file", requires = "shared", conflicts_with_all = ["record", "replay", "load_state", "dump_state", "dump_mem", "dump_regs"])]
+    core: Vec<PathBuf>,
+
+    /// The start and length of memory shared between cores, which is otherwise private to each
+    #[arg(long, value_name = "start,len", value_parser = parse_shared)]
+    shared: Option<(u16, u16)>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let bytes = std::fs::read(&args.file)
-        .context(format!("Failed to read file from {}", args.file.display()))?;
-    let bytes = match args.format {
-        InputFormat::Assembly => {
-            let s = String::from_utf8(bytes).context("Failed to parse input as string")?;
-            assemble(&s).context("Failed to assemble")?
+fn exit_code(success: bool) -> ExitCode {
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    let cli =
+        Cli::from_arg_matches(&config::matches(Cli::command())?).unwrap_or_else(|err| err.exit());
+    match cli.error_format {
+        ErrorFormat::Human => run(cli),
+        ErrorFormat::Json => run(cli).or_else(|err| {
+            eprintln!("{}", diagnostic::record(&err));
+            Ok(ExitCode::FAILURE)
+        }),
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode> {
+    match cli.command {
+        None => simulate::start(cli.files, cli.args),
+        Some(Cmd::Run { files, mut args }) => {
+            if args.hot_reload {
+                bail!("--hot-reload only applies to the interactive UI");
+            }
+            args.quiet = true;
+            simulate::start(files, args)
         }
-        InputFormat::Hex => {
-            let s = String::from_utf8(bytes).context("Failed to parse input as string")?;
-            hex_to_bytes(s).context("Failed to decode hex string")?
+        Some(Cmd::Debug { file, args }) => {
+            if args.quiet || args.json || args.plain || args.watch {
+                bail!("debug always opens the interactive UI, use run for quiet mode");
+            }
+            simulate::simulate(file, args)
+        }
+        Some(Cmd::Record {
+            file,
+            output,
+            mut args,
+        }) => {
+            if args.replay.is_some() {
+                bail!("record cannot also replay, use replay");
+            }
+            args.record = Some(output);
+            simulate::simulate(file, args)
+        }
+        Some(Cmd::Replay {
+            recording,
+            file,
+            mut args,
+        }) => {
+            if args.record.is_some() {
+                bail!("replay cannot also record, use record");
+            }
+            args.replay = Some(recording);
+            simulate::simulate(file, args)
+        }
+        Some(Cmd::Asm {
+            file,
+            output,
+            format,
+            target,
+        }) => {
+            program::assemble_file(&file, output, format, target.memory, target.isa())?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Diff {
+            left,
+            right,
+            format,
+            left_data,
+            right_data,
+            left_reg,
+            right_reg,
+            left_input,
+            right_input,
+            max_steps,
+            target,
+        }) => {
+            let right = right.unwrap_or_else(|| left.clone());
+            let side = |data, regs, input: Option<String>| {
+                let input = input.as_deref().map(read_input).transpose()?;
+                Ok::<_, anyhow::Error>(diff::Side { data, regs, input })
+            };
+            let sides = [
+                side(&left_data, &left_reg, left_input)?,
+                side(&right_data, &right_reg, right_input)?,
+            ];
+            let [left, right] = diff::files(
+                [&left, &right],
+                sides,
+                format,
+                target.memory,
+                target.isa(),
+                max_steps,
+            )?;
+            let color =
+                stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|it| it.is_empty());
+            Ok(match diff::diff(&left, &right, color) {
+                Some(table) => {
+                    print!("{table}");
+                    ExitCode::FAILURE
+                }
+                None => {
+                    println!("Both runs ended the same");
+                    ExitCode::SUCCESS
+                }
+            })
+        }
+        Some(Cmd::Lint { file, target }) => {
+            let (report, ok) = lint::file(&file, target.isa(), target.memory)?;
+            print!("{report}");
+            Ok(exit_code(ok))
+        }
+        Some(Cmd::Bench {
+            file,
+            format,
+            runs,
+            max_steps,
+            target,
+        }) => {
+            let report = bench::file(&file, format, runs, max_steps, target.memory, target.isa())?;
+            print!("{report}");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Test {
+            file,
+            spec,
+            format,
+            target,
+        }) => {
+            let results = grade::cases(&file, &spec, format, target.memory, target.isa())?;
+            print!("{}", grade::summary(&results));
+            Ok(exit_code(grade::passed(&results)))
+        }
+        Some(Cmd::Grade {
+            file,
+            spec,
+            format,
+            report,
+            output,
+            target,
+        }) => {
+            let results = grade::cases(&file, &spec, format, target.memory, target.isa())?;
+            let report = grade::report(&file.display().to_string(), &results, report);
+            match output {
+                Some(path) => fs::write(&path, report)
+                    .context(format!("Failed to write report to {}", path.display()))?,
+                None => print!("{report}"),
+            }
+            Ok(exit_code(grade::passed(&results)))
+        }
+        Some(Cmd::Dis {
+            file,
+            format,
+            target,
+        }) => {
+            let (bytes, _) = program::load(&file, format, target.memory, target.isa())?;
+            print!("{}", dis::disassemble(&bytes, target.isa()));
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Gen {
+            length,
+            ops,
+            seed,
+            output,
+            target,
+        }) => {
+            let code = gen::program(&ops, length, seed, target.isa(), target.memory)?;
+            match output {
+                Some(path) => fs::write(&path, code)
+                    .context(format!("Failed to write program to {}", path.display()))?,
+                None => print!("{code}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Examples { command }) => {
+            match command {
+                ExamplesCmd::List => print!("{}", examples::list()),
+                ExamplesCmd::Show { name } => print!("{}", examples::find(&name)?.source),
+                ExamplesCmd::Run { name, mut args } => {
+                    let example = examples::find(&name)?;
+                    args.screen = args.screen.or(example.screen);
+                    args.framebuffer = args.framebuffer.or(example.framebuffer);
+                    args.floats |= example.floats;
+                    return simulate::simulate(example.write()?, args);
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Serve {
+            listen,
+            max_steps,
+            target,
+        }) => {
+            let config = serve::Config {
+                memory: target.memory,
+                isa: target.isa(),
+                max_steps,
+            };
+            serve::serve(&listen, config)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Dap) => {
+            dap::serve()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Cmd::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), "v8-cpu", &mut stdout());
+            Ok(ExitCode::SUCCESS)
         }
-        InputFormat::Binary => bytes,
-    };
-    if bytes.len() > 256 {
-        bail!("Input bytecode is too large (> 256)");
-    }
-    let mut vm = VM::new();
-    vm.fill(&bytes);
-    if args.quiet {
-        execute!(stdout(), Clear(ClearType::All))?;
-        while vm.step()? {}
-        vm.print_state()
-    } else {
-        vm.interactive()
     }
 }
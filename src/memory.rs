@@ -0,0 +1,54 @@
+/// The storage a VM runs on, addressed by full 16-bit addresses below `len`.
+///
+/// Plain RAM is a `Vec<u8>`; other backends can wrap one to, say, guard a
+/// region or remap part of the address space. Memory-mapped devices live on
+/// top of this in `Devices`, since loads from them have side effects.
+pub trait Memory {
+    /// The size in bytes.
+    fn len(&self) -> usize;
+
+    /// Whether there are no bytes at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The byte at `addr`, which must be below `len`.
+    fn load(&self, addr: u16) -> u8;
+
+    /// Overwrites the byte at `addr`, which must be below `len`.
+    fn store(&mut self, addr: u16, value: u8);
+
+    /// Copies out the whole contents.
+    fn bytes(&self) -> Vec<u8> {
+        (0..self.len()).map(|addr| self.load(addr as u16)).collect()
+    }
+
+    /// Overwrites the contents starting at address 0.
+    fn copy_from(&mut self, bytes: &[u8]) {
+        for (addr, &value) in bytes.iter().enumerate() {
+            self.store(addr as u16, value);
+        }
+    }
+}
+
+impl Memory for Vec<u8> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn load(&self, addr: u16) -> u8 {
+        self[addr as usize]
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        self[addr as usize] = value;
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn copy_from(&mut self, bytes: &[u8]) {
+        self[..bytes.len()].copy_from_slice(bytes);
+    }
+}
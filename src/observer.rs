@@ -0,0 +1,50 @@
+use crate::vm::{Addr, Const, Reg, Step, VM};
+use std::{cell::RefCell, rc::Rc};
+
+/// Callbacks invoked while a VM executes, registered with `VM::observe`.
+/// Every method does nothing by default.
+///
+/// Writes are reported as they happen during a step, before `on_step` is
+/// called for the step as a whole.
+#[allow(unused_variables)]
+pub trait Observer {
+    /// Called after every step, with what it did.
+    fn on_step(&mut self, vm: &VM, step: &Step) {}
+    /// Called for every write to a register.
+    fn on_reg_write(&mut self, reg: Reg, old: Const, new: Const) {}
+    /// Called for every write to memory.
+    fn on_mem_write(&mut self, addr: Addr, old: Const, new: Const) {}
+    /// Called for every load the program performs, including from devices.
+    fn on_mem_read(&mut self, addr: Addr, value: Const) {}
+    /// Called once the program halts.
+    fn on_halt(&mut self, vm: &VM) {}
+    /// Called after the last step was undone.
+    fn on_undo(&mut self, vm: &VM) {}
+}
+
+/// Lets the registering side keep a handle to inspect the observer.
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn on_step(&mut self, vm: &VM, step: &Step) {
+        self.borrow_mut().on_step(vm, step);
+    }
+
+    fn on_reg_write(&mut self, reg: Reg, old: Const, new: Const) {
+        self.borrow_mut().on_reg_write(reg, old, new);
+    }
+
+    fn on_mem_write(&mut self, addr: Addr, old: Const, new: Const) {
+        self.borrow_mut().on_mem_write(addr, old, new);
+    }
+
+    fn on_mem_read(&mut self, addr: Addr, value: Const) {
+        self.borrow_mut().on_mem_read(addr, value);
+    }
+
+    fn on_halt(&mut self, vm: &VM) {
+        self.borrow_mut().on_halt(vm);
+    }
+
+    fn on_undo(&mut self, vm: &VM) {
+        self.borrow_mut().on_undo(vm);
+    }
+}
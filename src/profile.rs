@@ -0,0 +1,139 @@
+use crate::{
+    observer::Observer,
+    vm::{Addr, Const, Step, VM},
+};
+use std::fmt::Write;
+
+/// How many of the hottest instructions and cells a report lists.
+const TOP: usize = 10;
+
+/// Counts how often every address is executed, read and written. Steps that
+/// are later undone still count.
+pub struct Profiler {
+    /// How many instructions started at every address.
+    pub executed: Vec<u64>,
+    /// How many loads there were from every address.
+    pub reads: Vec<u64>,
+    /// How many stores there were to every address.
+    pub writes: Vec<u64>,
+}
+
+impl Profiler {
+    /// Counts for memory of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            executed: vec![0; size],
+            reads: vec![0; size],
+            writes: vec![0; size],
+        }
+    }
+
+    /// Summarizes the counts, treating the first `code_len` bytes of memory
+    /// as the program.
+    pub fn report(&self, vm: &VM, code_len: usize) -> String {
+        let width = if self.executed.len() > 256 { 3 } else { 2 };
+        let mut res = String::new();
+        let total: u64 = self.executed.iter().sum();
+        writeln!(res, "Executed {total} steps").unwrap();
+
+        let mut hottest: Vec<_> = (0..self.executed.len())
+            .filter(|&addr| self.executed[addr] != 0)
+            .collect();
+        hottest.sort_by_key(|&addr| std::cmp::Reverse(self.executed[addr]));
+        writeln!(res, "\nHottest instructions:").unwrap();
+        for &addr in hottest.iter().take(TOP) {
+            writeln!(
+                res,
+                "  0x{addr:0width$X} {:>8}  {:?}",
+                self.executed[addr],
+                vm.dis(Addr(addr as u16))
+            )
+            .unwrap();
+        }
+
+        // Instructions are two bytes wide, so code can only start at even
+        // addresses unless jumped into
+        let mut never = Vec::new();
+        for addr in (0..code_len.min(self.executed.len())).step_by(2) {
+            if self.executed[addr] != 0 {
+                continue;
+            }
+            match never.last_mut() {
+                Some((_, end)) if *end == addr => *end = addr + 2,
+                _ => never.push((addr, addr + 2)),
+            }
+        }
+        if !never.is_empty() {
+            writeln!(res, "\nNever executed:").unwrap();
+            for (start, end) in never {
+                writeln!(res, "  0x{start:0width$X}-0x{:0width$X}", end - 1).unwrap();
+            }
+        }
+
+        let mut accessed: Vec<_> = (0..self.reads.len())
+            .filter(|&addr| self.reads[addr] + self.writes[addr] != 0)
+            .collect();
+        accessed.sort_by_key(|&addr| std::cmp::Reverse(self.reads[addr] + self.writes[addr]));
+        if !accessed.is_empty() {
+            writeln!(res, "\nMost accessed cells:").unwrap();
+            for &addr in accessed.iter().take(TOP) {
+                writeln!(
+                    res,
+                    "  0x{addr:0width$X} {:>8} reads {:>8} writes",
+                    self.reads[addr], self.writes[addr]
+                )
+                .unwrap();
+            }
+        }
+        res
+    }
+}
+
+impl Profiler {
+    /// Reports which of the instruction words in the first `code_len` bytes
+    /// of memory were executed, as a percentage and a map with one character
+    /// per word.
+    pub fn coverage(&self, code_len: usize) -> String {
+        const ROW: usize = 32;
+        let width = if self.executed.len() > 256 { 3 } else { 2 };
+        let words: Vec<_> = (0..code_len.min(self.executed.len()))
+            .step_by(2)
+            .map(|addr| self.executed[addr] != 0)
+            .collect();
+        let covered = words.iter().filter(|&&it| it).count();
+        let mut res = String::new();
+        writeln!(
+            res,
+            "Coverage: {covered}/{} instructions ({:.1}%)",
+            words.len(),
+            if words.is_empty() {
+                100.
+            } else {
+                covered as f64 * 100. / words.len() as f64
+            }
+        )
+        .unwrap();
+        for (row, chunk) in words.chunks(ROW / 2).enumerate() {
+            let map: String = chunk.iter().map(|&it| if it { '#' } else { '.' }).collect();
+            writeln!(res, "0x{:0width$X}: {map}", row * ROW).unwrap();
+        }
+        res
+    }
+}
+
+impl Observer for Profiler {
+    fn on_step(&mut self, _vm: &VM, step: &Step) {
+        // Halting past the end of memory executes nothing
+        if let Some(count) = self.executed.get_mut(step.pc.0 as usize) {
+            *count += 1;
+        }
+    }
+
+    fn on_mem_read(&mut self, addr: Addr, _value: Const) {
+        self.reads[addr.0 as usize] += 1;
+    }
+
+    fn on_mem_write(&mut self, addr: Addr, _old: Const, _new: Const) {
+        self.writes[addr.0 as usize] += 1;
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    asm::{assemble_with_map, Source},
+    diagnostic::Located,
+    vm::Isa,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    io::{stdout, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+/// The formats a program file can be in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum InputFormat {
+    /// Assembly source
+    Assembly,
+    /// Bytecode as hex digits, with `;` comments
+    Hex,
+    /// Raw bytecode
+    Binary,
+}
+
+/// The formats `assemble_file` can write a program in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Bytecode as hex digits, one instruction per line
+    Hex,
+    /// Raw bytecode
+    Binary,
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            InputFormat::to_possible_value(self).unwrap().get_name()
+        )
+    }
+}
+
+/// Decodes hex bytecode, ignoring whitespace and `;` comments at the end of
+/// lines.
+pub fn hex_to_bytes(s: String) -> Result<Vec<u8>> {
+    fn parse_line(s: &str, res: &mut Vec<u8>) -> Result<()> {
+        let mut s = s.trim_start();
+        if let Some(index) = s.find(';') {
+            s = &s[..index];
+        }
+        let s = s.trim_end().to_ascii_lowercase();
+        if !s.len().is_multiple_of(2) || s.chars().any(|c| !c.is_ascii_hexdigit()) {
+            bail!("Invalid hex string: {s}");
+        }
+        res.extend(
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()),
+        );
+        Ok(())
+    }
+    let mut res = Vec::new();
+    for (i, line) in s.split('\n').enumerate() {
+        parse_line(line, &mut res).context(format!("On line {i}"))?;
+    }
+    Ok(res)
+}
+
+/// Writes `bytes` as hex, one instruction word per line, as `hex_to_bytes`
+/// reads it.
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes
+        .chunks(2)
+        .map(|word| {
+            word.iter()
+                .map(|it| format!("{it:02X}"))
+                .collect::<String>()
+                + "\n"
+        })
+        .collect()
+}
+
+/// Reads the program in `path` as bytecode, along with its source if it is
+/// assembly. Errors are `Located` in `path`.
+pub fn load(
+    path: &Path,
+    format: InputFormat,
+    memory: usize,
+    isa: Isa,
+) -> Result<(Vec<u8>, Option<Source>)> {
+    let load = || {
+        let bytes =
+            std::fs::read(path).context(format!("Failed to read file from {}", path.display()))?;
+        decode(bytes, format, memory, isa)
+    };
+    load().map_err(|err| Located::new(path, None, err).into())
+}
+
+/// Assembles or decodes the contents of a program file, as `load` does.
+pub fn decode(
+    bytes: Vec<u8>,
+    format: InputFormat,
+    memory: usize,
+    isa: Isa,
+) -> Result<(Vec<u8>, Option<Source>)> {
+    let (bytes, source) = match format {
+        InputFormat::Assembly => {
+            let s = String::from_utf8(bytes).context("Failed to parse input as string")?;
+            let (bytes, map) = assemble_with_map(&s, isa, memory).context("Failed to assemble")?;
+            let lines = s.lines().map(str::to_string).collect();
+            (bytes, Some(Source { lines, map }))
+        }
+        InputFormat::Hex => {
+            let s = String::from_utf8(bytes).context("Failed to parse input as string")?;
+            (
+                hex_to_bytes(s).context("Failed to decode hex string")?,
+                None,
+            )
+        }
+        InputFormat::Binary => (bytes, None),
+    };
+    if bytes.len() > memory {
+        bail!("Input bytecode is too large (> {memory})");
+    }
+    Ok((bytes, source))
+}
+
+/// Assembles the source in `file` and writes it in `format` to `output`, to
+/// stdout if that is `-`, or next to `file` with the extension of `format`.
+pub fn assemble_file(
+    file: &Path,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+    memory: usize,
+    isa: Isa,
+) -> Result<()> {
+    let (bytes, _) = load(file, InputFormat::Assembly, memory, isa)?;
+    let (contents, extension) = match format {
+        OutputFormat::Hex => (bytes_to_hex(&bytes).into_bytes(), "hex"),
+        OutputFormat::Binary => (bytes, "bin"),
+    };
+    if output.as_deref() == Some(Path::new("-")) {
+        if format == OutputFormat::Binary && stdout().is_terminal() {
+            bail!("Refusing to write binary to a terminal, redirect it or use -f hex");
+        }
+        let mut out = stdout().lock();
+        return out
+            .write_all(&contents)
+            .and_then(|()| out.flush())
+            .context("Failed to write to stdout");
+    }
+    let output = output.unwrap_or_else(|| file.with_extension(extension));
+    if output == file {
+        bail!("Refusing to overwrite the source file, pass -o to pick another output");
+    }
+    std::fs::write(&output, contents).context(format!("Failed to write {}", output.display()))
+}
@@ -0,0 +1,412 @@
+use crate::{
+    asm::{assemble_with_map, Source},
+    state::SessionFile,
+    vm::{Action, Addr, Const, Instr, Reg, Snapshot, StopReason, VmError, SP, VM},
+    watch::{Expr, Watch},
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+const VERSION: u32 = 1;
+
+/// Something the user did to the machine in a debugging session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Command {
+    Step,
+    /// Runs a call of the extended instruction set until it returns, or
+    /// steps like `Step` over any other instruction.
+    StepOver,
+    /// Runs until the machine halts.
+    Run,
+    /// Runs up to this many steps, stopping early if the machine halts.
+    Steps(usize),
+    /// Runs up to `steps` steps until the program counter reaches `pc`, with
+    /// the stack pointer at `sp` if given, stopping early if the machine
+    /// halts.
+    RunTo {
+        pc: u16,
+        sp: Option<u8>,
+        steps: usize,
+    },
+    Undo,
+    /// Redoes the last undone step.
+    Redo,
+    /// Undoes every step back to the start.
+    Rewind,
+    /// Redoes every undone step.
+    Forward,
+    Reset,
+    /// Resets and also reloads the program into memory.
+    ResetHard,
+    SavePoint,
+    LoadPoint,
+    /// Starts forwarding keys to the input port instead of prompting.
+    Keyboard,
+    /// Goes back to prompting for input.
+    Debugger,
+    /// A key forwarded to the input port.
+    Key(u8),
+    /// Sets or clears a breakpoint at this address.
+    Breakpoint(u16),
+    /// Sets a register to a value, undoably.
+    SetReg(u8, u8),
+    /// Stores a value to a memory cell, undoably.
+    SetMem(u16, u8),
+    /// Queues a line of text, and a newline, for the input port.
+    Input(String),
+}
+
+/// Everything nondeterministic about a session: the seed of the random port,
+/// the answers to input prompts, and the commands the user gave.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Recording {
+    version: u32,
+    pub seed: Option<u64>,
+    pub prompts: Vec<Vec<u8>>,
+    pub commands: Vec<Command>,
+}
+
+/// A recording shared between the input prompt and the debugger.
+pub type Recorder = Rc<RefCell<Recording>>;
+
+impl Recording {
+    pub fn new() -> Self {
+        Self {
+            version: VERSION,
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, command: Command) {
+        // Keyboard mode runs in many small batches
+        if let (Command::Steps(n), Some(Command::Steps(last))) =
+            (&command, self.commands.last_mut())
+        {
+            *last += n;
+            return;
+        }
+        self.commands.push(command);
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+            .context(format!("Failed to write recording to {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = fs::read_to_string(path)
+            .context(format!("Failed to read recording from {}", path.display()))?;
+        let recording: Self = serde_json::from_str(&s).context("Malformed recording")?;
+        if recording.version != VERSION {
+            bail!(
+                "Unsupported recording version {} (expected {VERSION})",
+                recording.version
+            );
+        }
+        Ok(recording)
+    }
+}
+
+/// The debugger state commands act on besides the machine.
+#[derive(Default)]
+pub struct Session {
+    pub save_point: Option<Snapshot>,
+    /// Whether keys are being forwarded to the input port.
+    pub keyboard: bool,
+    saved_prompt: Option<Box<dyn FnMut() -> Option<Vec<u8>>>>,
+    /// The assembly source the program came from, if any.
+    pub source: Option<Source>,
+    /// The file `source` was read from, to assemble again after it changes.
+    pub path: Option<PathBuf>,
+    /// Reassemble as soon as the source file changes.
+    pub hot_reload: bool,
+    /// When the source file was last modified, as of the last check.
+    modified: Option<SystemTime>,
+    pub watches: Vec<Watch>,
+    /// Snapshots taken with `:snap`, by name, to compare the machine with.
+    pub snapshots: Vec<(String, Snapshot)>,
+}
+
+/// Where stepping over the call at the program counter stops: the return
+/// address once the stack is back where it was, and not in a recursive call
+/// returning there. `None` if the program counter is not at a call.
+pub fn over(vm: &VM) -> Option<(u16, u8)> {
+    matches!(vm.dis(vm.pc), Instr::Call(_)).then(|| (vm.pc.0.wrapping_add(2), vm.getr(SP).0))
+}
+
+impl Session {
+    /// Carries out `command`, returning whether the machine is still running.
+    pub fn perform(&mut self, vm: &mut VM, command: &Command) -> Result<bool, VmError> {
+        match *command {
+            Command::Step => return vm.step(),
+            Command::StepOver => {
+                let Some((ret, sp)) = over(vm) else {
+                    return vm.step();
+                };
+                let stop = vm.run_until(|vm| vm.pc.0 == ret && vm.getr(SP).0 == sp)?;
+                return Ok(stop != StopReason::Halted);
+            }
+            Command::Run => return Ok(vm.run()? != StopReason::Halted),
+            Command::Steps(n) => return Ok(vm.run_steps(n)? != StopReason::Halted),
+            Command::RunTo { pc, sp, steps } => {
+                let stop = vm.run_steps_until(steps, |vm| {
+                    vm.pc.0 == pc && sp.is_none_or(|sp| vm.getr(SP).0 == sp)
+                })?;
+                return Ok(stop != StopReason::Halted);
+            }
+            Command::Undo => vm.undo(),
+            Command::Redo => return Ok(vm.redo()),
+            Command::Rewind => {
+                while !vm.history.is_empty() {
+                    vm.undo();
+                }
+            }
+            Command::Forward => {
+                while vm.redo_len() > 0 {
+                    if !vm.redo() {
+                        return Ok(false);
+                    }
+                }
+            }
+            Command::Reset => vm.reset(),
+            Command::ResetHard => vm.reset_hard(),
+            Command::SavePoint => self.save_point = Some(vm.snapshot()),
+            Command::LoadPoint => {
+                if let Some(snapshot) = &self.save_point {
+                    vm.restore(snapshot);
+                }
+            }
+            // Reads from an empty port must not block on a prompt while keys
+            // are being forwarded
+            Command::Keyboard => {
+                if let Some(input) = &mut vm.devices.input {
+                    self.saved_prompt = input.prompt.take();
+                    self.keyboard = true;
+                }
+            }
+            Command::Debugger => {
+                if let Some(input) = &mut vm.devices.input {
+                    input.prompt = self.saved_prompt.take();
+                    self.keyboard = false;
+                }
+            }
+            Command::Key(byte) => {
+                if let Some(input) = &mut vm.devices.input {
+                    input.data.push(byte);
+                }
+            }
+            Command::Input(ref line) => {
+                if let Some(input) = &mut vm.devices.input {
+                    input.data.extend(line.bytes().chain([b'\n']));
+                }
+            }
+            Command::Breakpoint(addr) => {
+                if !vm.breakpoints.remove(&Addr(addr)) {
+                    vm.breakpoints.insert(Addr(addr));
+                }
+            }
+            Command::SetReg(reg, value) => vm.edit(Action::SetReg(Reg(reg), Const(value))),
+            Command::SetMem(addr, value) => vm.edit(Action::SetMem(Addr(addr), Const(value))),
+        }
+        Ok(true)
+    }
+
+    /// Performs `commands` in order, returning whether the machine is still
+    /// running after them.
+    pub fn replay(&mut self, vm: &mut VM, commands: &[Command]) -> Result<bool, VmError> {
+        for command in commands {
+            if !self.perform(vm, command)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The session, with the state of `vm`, as saved to a file.
+    pub fn to_file(&self, vm: &VM) -> SessionFile {
+        let mut breakpoints: Vec<_> = vm.breakpoints.iter().copied().collect();
+        breakpoints.sort_by_key(|it| it.0);
+        SessionFile {
+            snapshot: vm.snapshot(),
+            breakpoints,
+            watches: self.watches.iter().map(|it| it.text.clone()).collect(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    /// Goes back to a session saved with `to_file`.
+    pub fn restore(&mut self, vm: &mut VM, file: SessionFile) -> Result<()> {
+        if file.snapshot.memory.len() != vm.memory.len() {
+            bail!(
+                "The session has {} bytes of memory, but the machine has {}",
+                file.snapshot.memory.len(),
+                vm.memory.len()
+            );
+        }
+        vm.restore(&file.snapshot);
+        vm.breakpoints = file.breakpoints.into_iter().collect();
+        self.watch(vm, file.watches);
+        self.snapshots = file.snapshots;
+        Ok(())
+    }
+
+    /// Replaces the watches with the expressions in `texts`, resolving labels
+    /// in the current source.
+    fn watch(&mut self, vm: &VM, texts: Vec<String>) {
+        let map = self.source.as_ref().map(|it| &it.map);
+        self.watches = texts
+            .into_iter()
+            .filter_map(|text| {
+                let expr = Expr::parse(&text, map)?;
+                Some(Watch::new(text, expr, vm))
+            })
+            .collect();
+    }
+
+    /// Whether the source file was modified since the last check. The first
+    /// check only takes note of when it was.
+    pub fn source_changed(&mut self) -> bool {
+        let modified = self
+            .path
+            .as_ref()
+            .and_then(|it| fs::metadata(it).ok())
+            .and_then(|it| it.modified().ok());
+        let changed = self.modified.is_some() && modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+
+    /// Assembles the source file again and restarts `vm` with the new
+    /// program. Breakpoints stay on their labels, or else their lines.
+    pub fn reassemble(&mut self, vm: &mut VM) -> Result<()> {
+        let Some(path) = &self.path else {
+            bail!("Only assembly programs can be reassembled");
+        };
+        let s = fs::read_to_string(path)
+            .context(format!("Failed to read file from {}", path.display()))?;
+        let (bytes, map) =
+            assemble_with_map(&s, vm.isa, vm.memory.len()).context("Failed to assemble")?;
+        if let Some(old) = &self.source {
+            vm.breakpoints = vm
+                .breakpoints
+                .iter()
+                .filter_map(|&Addr(addr)| match old.map.label_at(addr) {
+                    Some(label) => map.label(label),
+                    None => map.addr(old.map.line(addr)?),
+                })
+                .map(Addr)
+                .collect();
+        }
+        self.source_changed();
+        let lines = s.lines().map(str::to_string).collect();
+        self.source = Some(Source { lines, map });
+        vm.reset();
+        vm.fill(&bytes);
+        let texts = self.watches.iter().map(|it| it.text.clone()).collect();
+        self.watch(vm, texts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builder::VmBuilder,
+        device::{Devices, InputPort, OutputPort, Random},
+    };
+
+    /// Adds a byte of input to a random byte twice, printing both sums.
+    fn machine() -> VM {
+        let source = "loadm r1, 0xFE\nloadm r2, 0xF0\naddi r3, r1, r2\nstorem r3, 0xFF\n\
+                      loadm r1, 0xFE\nloadm r2, 0xF0\naddi r3, r1, r2\nstorem r3, 0xFF\nhalt";
+        let (program, _) = assemble_with_map(source, Default::default(), 256).unwrap();
+        VmBuilder::new()
+            .program(&program)
+            .devices(Devices {
+                output: Some(OutputPort::new(0xFF)),
+                input: Some(InputPort::new(0xFE, Vec::new())),
+                random: Some(Random::new(0xF0, 7)),
+                ..Default::default()
+            })
+            .build()
+    }
+
+    fn state(vm: &VM) -> (Vec<u8>, u16, Vec<u8>, Vec<u8>) {
+        let output = vm.devices.output.as_ref().unwrap().buffer.clone();
+        (
+            vm.regs.to_vec(),
+            vm.pc.0,
+            vm.memory.bytes().to_vec(),
+            output,
+        )
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let mut recording = Recording::new();
+        for command in [
+            Command::Input("a".into()),
+            Command::Step,
+            Command::Steps(2),
+            Command::Steps(1),
+            Command::Undo,
+            Command::SetReg(0xA, 1),
+            Command::Redo,
+            Command::Input("b".into()),
+            Command::Run,
+        ] {
+            recording.push(command);
+        }
+        let recording: Recording =
+            serde_json::from_str(&serde_json::to_string(&recording).unwrap()).unwrap();
+        let run = || {
+            let mut vm = machine();
+            let running = Session::default()
+                .replay(&mut vm, &recording.commands)
+                .unwrap();
+            assert!(!running);
+            state(&vm)
+        };
+        let first = run();
+        assert_eq!(first, run());
+        assert_eq!(first.0[0xA], 1);
+        assert_eq!(first.3.len(), 2);
+    }
+
+    #[test]
+    fn pushes_merge_steps() {
+        let mut recording = Recording::new();
+        recording.push(Command::Steps(2));
+        recording.push(Command::Steps(3));
+        recording.push(Command::Step);
+        assert!(matches!(
+            recording.commands[..],
+            [Command::Steps(5), Command::Step]
+        ));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let path = std::env::temp_dir().join("v8-cpu-replay-version.json");
+        fs::write(
+            &path,
+            r#"{"version": 2, "seed": null, "prompts": [], "commands": []}"#,
+        )
+        .unwrap();
+        let err = Recording::load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported recording version 2 (expected 1)"
+        );
+    }
+}
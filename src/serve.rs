@@ -0,0 +1,381 @@
+use crate::{
+    asm::{parse_location, Source},
+    builder::VmBuilder,
+    device::{Devices, InputPort, OutputPort},
+    program::{self, InputFormat},
+    spec::{INPUT_PORT, OUTPUT_PORT},
+    vm::{Addr, Isa, StopReason, VM},
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+/// The largest request body read, which is far more than any program needs.
+const MAX_BODY: usize = 1 << 20;
+
+/// How long a client may take to send its request or read the response,
+/// since every other client waits for it meanwhile.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The machine programs are loaded into.
+pub struct Config {
+    pub memory: usize,
+    pub isa: Isa,
+    /// The steps after which `/run` gives up and the most `/step` runs at
+    /// once, 0 for no limit.
+    pub max_steps: usize,
+}
+
+/// The program being run, if one has been loaded.
+struct Session {
+    vm: VM,
+    source: Option<Source>,
+    halted: bool,
+}
+
+/// A failed request, with its HTTP status.
+struct Error(u16, String);
+
+type Response = std::result::Result<Value, Error>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Load {
+    source: String,
+    #[serde(default)]
+    format: Format,
+    /// The text read from the input port at 0xFE.
+    input: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Assembly,
+    Hex,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    #[serde(default = "one")]
+    count: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Breakpoints {
+    /// Addresses or labels.
+    breakpoints: Vec<String>,
+}
+
+fn state(session: &Session) -> Value {
+    let vm = &session.vm;
+    let mut breakpoints: Vec<_> = vm.breakpoints.iter().map(|it| it.0).collect();
+    breakpoints.sort_unstable();
+    json!({
+        "regs": vm.regs,
+        "flags": vm.flags,
+        "pc": vm.pc.0,
+        "steps": vm.history.iter().filter(|it| !it.edit).count(),
+        "halted": session.halted,
+        "mem": vm.memory.bytes().iter().map(|it| format!("{it:02X}")).collect::<String>(),
+        "output": vm.devices.output.as_ref().map(OutputPort::text).unwrap_or_default(),
+        "breakpoints": breakpoints,
+    })
+}
+
+fn body<'a, T: Deserialize<'a>>(body: &'a str) -> std::result::Result<T, Error> {
+    let body = if body.trim().is_empty() { "{}" } else { body };
+    serde_json::from_str(body).map_err(|err| Error(400, format!("Malformed request: {err}")))
+}
+
+fn loaded(session: &mut Option<Session>) -> std::result::Result<&mut Session, Error> {
+    session
+        .as_mut()
+        .ok_or_else(|| Error(409, "No program loaded, POST one to /load".to_string()))
+}
+
+/// Runs `session` with `run`, reporting why it stopped along with the state.
+fn run(
+    session: &mut Session,
+    run: impl FnOnce(&mut VM) -> crate::vm::Result<StopReason>,
+) -> Response {
+    if session.halted {
+        return Err(Error(
+            409,
+            "The program has halted, POST to /reset to run it again".to_string(),
+        ));
+    }
+    let (stop, error) = match run(&mut session.vm) {
+        Ok(StopReason::Halted) => {
+            session.halted = true;
+            ("halted", None)
+        }
+        Ok(StopReason::Breakpoint(_)) => ("breakpoint", None),
+        Ok(StopReason::Predicate) => ("limit", None),
+        Ok(StopReason::Budget) => ("paused", None),
+        Err(err) => ("error", Some(err.to_string())),
+    };
+    Ok(json!({ "stop": stop, "error": error, "state": state(session) }))
+}
+
+fn handle(
+    config: &Config,
+    session: &mut Option<Session>,
+    method: &str,
+    path: &str,
+    text: &str,
+) -> Response {
+    match (method, path) {
+        ("GET", "/state") => Ok(state(loaded(session)?)),
+        ("POST", "/load") => {
+            let load: Load = body(text)?;
+            let (program, source) = program::decode(
+                load.source.into_bytes(),
+                match load.format {
+                    Format::Assembly => InputFormat::Assembly,
+                    Format::Hex => InputFormat::Hex,
+                },
+                config.memory,
+                config.isa,
+            )
+            .map_err(|err| Error(400, format!("{err:#}")))?;
+            let vm = VmBuilder::new()
+                .memory_size(config.memory)
+                .isa(config.isa)
+                .program(&program)
+                .devices(Devices {
+                    output: Some(OutputPort::new(OUTPUT_PORT)),
+                    input: load
+                        .input
+                        .map(|it| InputPort::new(INPUT_PORT, it.into_bytes())),
+                    ..Default::default()
+                })
+                .build();
+            let session = session.insert(Session {
+                vm,
+                source,
+                halted: false,
+            });
+            Ok(state(session))
+        }
+        ("POST", "/reset") => {
+            let session = loaded(session)?;
+            session.vm.reset_hard();
+            session.halted = false;
+            Ok(state(session))
+        }
+        ("POST", "/step") => {
+            let step: Step = body(text)?;
+            let count = match config.max_steps {
+                0 => step.count,
+                limit => step.count.min(limit),
+            };
+            run(loaded(session)?, |vm| vm.run_steps(count))
+        }
+        ("POST", "/run") => {
+            let session = loaded(session)?;
+            let limit = config.max_steps;
+            let start = session.vm.history.len();
+            run(session, |vm| {
+                vm.run_until(|vm| limit > 0 && vm.history.len() - start >= limit)
+            })
+        }
+        ("GET", "/breakpoints") => Ok(state(loaded(session)?)["breakpoints"].take()),
+        ("PUT", "/breakpoints") => {
+            let breakpoints: Breakpoints = body(text)?;
+            let session = loaded(session)?;
+            let addrs = breakpoints
+                .breakpoints
+                .iter()
+                .map(|it| {
+                    parse_location(it, session.source.as_ref())
+                        .filter(|&addr| (addr as usize) < session.vm.memory.len())
+                        .map(Addr)
+                        .ok_or_else(|| Error(400, format!("Not an address or label: {it}")))
+                })
+                .collect::<std::result::Result<_, _>>()?;
+            session.vm.breakpoints = addrs;
+            Ok(state(session)["breakpoints"].take())
+        }
+        (_, "/state" | "/load" | "/reset" | "/step" | "/run" | "/breakpoints") => {
+            Err(Error(405, format!("{method} is not allowed on {path}")))
+        }
+        _ => Err(Error(404, format!("No such endpoint: {path}"))),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Error",
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: GET, POST, PUT, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Content-Type\r\n\
+         Connection: close\r\n\r\n{body}",
+        reason(status),
+        body.len()
+    )
+}
+
+fn connection(
+    config: &Config,
+    session: &mut Option<Session>,
+    mut stream: TcpStream,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let path = path.split('?').next().unwrap_or_default().to_string();
+    let method = method.to_string();
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if method == "OPTIONS" {
+        return respond(&mut stream, 204, "");
+    }
+    if length > MAX_BODY {
+        let body = json!({ "error": "The request is too large" }).to_string();
+        return respond(&mut stream, 413, &body);
+    }
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes)?;
+    let text = String::from_utf8_lossy(&bytes);
+    let (status, body) = match handle(config, session, &method, &path, &text) {
+        Ok(value) => (200, value),
+        Err(Error(status, message)) => (status, json!({ "error": message })),
+    };
+    respond(&mut stream, status, &body.to_string())
+}
+
+/// Serves the JSON API on `addr` until the process is stopped, handling one
+/// request at a time on a single machine shared by every client. A client
+/// that stalls is dropped after `TIMEOUT`.
+pub fn serve(addr: &str, config: Config) -> Result<()> {
+    let listener = TcpListener::bind(addr).context(format!("Failed to listen on {addr}"))?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    let mut session = None;
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        // A client hanging up halfway is its own problem
+        let _ = connection(&config, &mut session, stream);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        memory: 256,
+        isa: Isa {
+            ext: false,
+            flags: false,
+        },
+        max_steps: 100,
+    };
+
+    /// The status `handle` answers a request with, along with the body.
+    fn status(session: &mut Option<Session>, method: &str, path: &str, text: &str) -> (u16, Value) {
+        match handle(&CONFIG, session, method, path, text) {
+            Ok(value) => (200, value),
+            Err(Error(status, message)) => (status, json!(message)),
+        }
+    }
+
+    fn load(source: &str) -> Option<Session> {
+        let mut session = None;
+        let text = json!({ "source": source }).to_string();
+        assert_eq!(status(&mut session, "POST", "/load", &text).0, 200);
+        session
+    }
+
+    #[test]
+    fn needs_a_program() {
+        let mut session = None;
+        assert_eq!(status(&mut session, "GET", "/state", "").0, 409);
+        assert_eq!(status(&mut session, "POST", "/step", "").0, 409);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut session = None;
+        assert_eq!(status(&mut session, "POST", "/load", "{").0, 400);
+        assert_eq!(
+            status(&mut session, "POST", "/load", r#"{"source": "nop r1"}"#).0,
+            400
+        );
+        assert_eq!(status(&mut session, "DELETE", "/state", "").0, 405);
+        assert_eq!(status(&mut session, "GET", "/nope", "").0, 404);
+        let mut session = load("halt");
+        let text = r#"{"breakpoints": ["nowhere"]}"#;
+        assert_eq!(status(&mut session, "PUT", "/breakpoints", text).0, 400);
+    }
+
+    #[test]
+    fn runs_until_halted() {
+        let mut session = load("loadb r1, 7\nhalt");
+        let (code, body) = status(&mut session, "POST", "/run", "");
+        assert_eq!(code, 200);
+        assert_eq!(body["stop"], "halted");
+        assert_eq!(body["state"]["regs"][1], 7);
+        assert_eq!(status(&mut session, "POST", "/step", "").0, 409);
+        assert_eq!(status(&mut session, "POST", "/reset", "").0, 200);
+        assert_eq!(status(&mut session, "POST", "/step", "").0, 200);
+    }
+
+    #[test]
+    fn limits_steps() {
+        let mut session = load("loop: jump r0, loop");
+        let (_, body) = status(&mut session, "POST", "/run", "");
+        assert_eq!(body["stop"], "limit");
+        assert_eq!(body["state"]["steps"], 100);
+        let (_, body) = status(&mut session, "POST", "/step", r#"{"count": 1000}"#);
+        assert_eq!(body["state"]["steps"], 200);
+    }
+}
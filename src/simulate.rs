@@ -0,0 +1,496 @@
+//! Running programs from the command line, quietly or in the interactive
+//! UI, and what is left to print and save once they stop.
+
+use crate::{
+    asm::parse_location,
+    builder::VmBuilder,
+    cluster::{Cluster, Window},
+    device::{Devices, Framebuffer, InputPort, OutputPort, Random, Screen},
+    diagnostic::Located,
+    profile::Profiler,
+    program, read_input,
+    replay::{Command, Recording, Session},
+    state, term,
+    term::{Focus, TerminalExt, View},
+    trace::{TraceFormat, Tracer},
+    ui::{self, Theme},
+    vm::{Addr, FillPattern, Isa, VmError, VM},
+    Args,
+};
+use anyhow::{bail, Context, Result};
+use crossterm::{
+    cursor::MoveTo,
+    execute,
+    terminal::{Clear, ClearType},
+};
+use std::{
+    cell::RefCell,
+    env, fs,
+    io::{stdin, stdout},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The exit code of a quiet run that ran out of `--max-steps`, telling it
+/// apart from errors.
+const EXIT_STEP_LIMIT: u8 = 3;
+/// The exit code of a quiet run that ran out of `--time-limit`.
+const EXIT_TIME_LIMIT: u8 = 4;
+
+/// Prints the final state with `print`, given the limit the run ran out of
+/// if it did, unless `res` is another error. Running out of steps or time
+/// gives its own exit code.
+fn finish(
+    res: Result<(), VmError>,
+    explain: bool,
+    print: impl FnOnce(Option<&VmError>) -> Result<()>,
+) -> Result<ExitCode> {
+    let limit = match res {
+        Ok(()) => None,
+        Err(err @ (VmError::StepLimit { .. } | VmError::TimeLimit { .. })) => Some(err),
+        Err(err) => return Err(err.into()),
+    };
+    print(limit.as_ref())?;
+    Ok(match limit {
+        Some(err) => {
+            let (option, code) = match err {
+                VmError::StepLimit { .. } => ("--max-steps", EXIT_STEP_LIMIT),
+                _ => ("--time-limit", EXIT_TIME_LIMIT),
+            };
+            if explain {
+                eprintln!("{err}, raise {option} to run longer");
+            }
+            ExitCode::from(code)
+        }
+        None => ExitCode::SUCCESS,
+    })
+}
+
+/// Runs the program in `files`, or every one of them quietly.
+pub fn start(files: Vec<PathBuf>, args: Args) -> Result<ExitCode> {
+    match <[PathBuf; 1]>::try_from(files) {
+        Ok([file]) => simulate(file, args),
+        Err(files) => batch(files, args),
+    }
+}
+
+/// A row of the table `batch` prints: the steps a program ran, whether it
+/// halted or ran out of `limit`, and its final registers.
+fn summary(file: &Path, width: usize, vm: &VM, halted: bool, limit: Option<&VmError>) -> String {
+    let steps = vm.history.iter().filter(|it| !it.edit).count();
+    let status = match limit {
+        _ if halted => "halted",
+        Some(VmError::StepLimit { .. }) => "gave up",
+        Some(_) => "timeout",
+        None => "stopped",
+    };
+    let regs: String = vm.regs.iter().map(|it| format!(" {it:02X}")).collect();
+    format!("{:width$}  {steps:>7}  {status:7}{regs}", file.display())
+}
+
+/// Runs every program in `files` quietly, printing a table of how each of
+/// them ended. Fails if any of them did, or gives the exit code of the first
+/// that ran out of steps or time.
+fn batch(files: Vec<PathBuf>, mut args: Args) -> Result<ExitCode> {
+    if !args.quiet {
+        bail!("Only quiet mode runs several programs, pass -q or use run");
+    }
+    if args.json || args.watch {
+        bail!("--json and --watch take a single program");
+    }
+    let width = files
+        .iter()
+        .map(|it| it.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let regs: String = (0..16).map(|i| format!(" R{i:X}")).collect();
+    println!("{:width$}  {:>7}  {:7}{regs}", "File", "Steps", "Status");
+    args.summary = Some(width);
+    let mut code = ExitCode::SUCCESS;
+    for file in files {
+        match simulate(file.clone(), args.clone()) {
+            Ok(res) if res == ExitCode::SUCCESS => {}
+            Ok(res) => {
+                if code == ExitCode::SUCCESS {
+                    code = res;
+                }
+            }
+            Err(err) => {
+                println!("{:width$}  {:>7}  error: {err:#}", file.display(), "");
+                code = ExitCode::FAILURE;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Undoes the steps of a replay past `step`, returning whether there were
+/// any.
+fn rewind(vm: &mut VM, step: usize) -> bool {
+    if vm.history.len() < step {
+        eprintln!(
+            "Warning: the recording ends at step {}, before step {step}",
+            vm.history.len()
+        );
+    }
+    let rewound = vm.history.len() > step;
+    while vm.history.len() > step {
+        vm.undo();
+    }
+    rewound
+}
+
+/// How often `--watch` checks the file for changes.
+const WATCH_POLL: Duration = Duration::from_millis(250);
+
+/// Runs the program in `file` quietly, and again every time it changes, until
+/// interrupted.
+fn watch(file: PathBuf, args: Args) -> Result<ExitCode> {
+    let modified = || fs::metadata(&file).and_then(|it| it.modified()).ok();
+    loop {
+        let last = modified();
+        if let Err(err) = simulate(file.clone(), args.clone()) {
+            eprintln!("Error: {err:?}");
+        }
+        eprintln!(
+            "Watching {} for changes, press Ctrl+C to stop",
+            file.display()
+        );
+        // Editors may replace the file rather than write it, leaving it
+        // missing for a moment
+        while modified().is_none_or(|it| Some(it) == last) {
+            thread::sleep(WATCH_POLL);
+        }
+    }
+}
+
+/// Runs the program in `file`, quietly or in the interactive UI.
+pub fn simulate(file: PathBuf, mut args: Args) -> Result<ExitCode> {
+    if args.watch {
+        args.watch = false;
+        args.quiet = true;
+        return watch(file, args);
+    }
+    args.quiet |= args.json || args.plain;
+    if args.to_step.is_some() && args.replay.is_none() {
+        bail!("--to-step only applies to a replay");
+    }
+    let isa = Isa {
+        ext: args.ext,
+        flags: args.flags,
+    };
+    let (bytes, source) = program::load(&file, args.format, args.memory, isa)?;
+    let entry = match &args.entry {
+        Some(s) => Addr(parse_location(s, source.as_ref()).context(format!(
+            "Expected an address or label as the entry point, got {s}"
+        ))?),
+        None => Addr(0),
+    };
+    let cores = args
+        .core
+        .iter()
+        .map(|path| Ok(program::load(path, args.format, args.memory, isa)?.0))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some((start, len)) = args.shared {
+        if start as usize + len as usize > args.memory {
+            bail!("The shared memory at 0x{start:02X} would exceed memory bounds");
+        }
+    }
+    let replay = args.replay.as_deref().map(Recording::load).transpose()?;
+    let recorder = args
+        .record
+        .is_some()
+        .then(|| Rc::new(RefCell::new(Recording::new())));
+    let mut devices = Devices {
+        output: Some(OutputPort::new(args.output_port)),
+        screen: args.screen.map(|base| Screen { base }),
+        framebuffer: args.framebuffer.map(|base| Framebuffer { base }),
+        timer: args.timer,
+        banks: args.banks.clone(),
+        ..Default::default()
+    };
+    if args.input_port.is_some() || args.input.is_some() {
+        let addr = args.input_port.unwrap_or(0xFE);
+        let mut prompt: Box<dyn FnMut() -> Option<Vec<u8>>> = if let Some(replay) = &replay {
+            let mut answers = replay.prompts.clone().into_iter();
+            Box::new(move || answers.next())
+        } else if args.quiet {
+            Box::new(|| {
+                let mut line = String::new();
+                match stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line.into_bytes()),
+                }
+            })
+        } else {
+            Box::new(|| {
+                let line = term::prompt("Input: ").ok().flatten()?;
+                Some((line + "\n").into_bytes())
+            })
+        };
+        if let Some(recorder) = &recorder {
+            let recorder = recorder.clone();
+            let mut inner = prompt;
+            prompt = Box::new(move || {
+                let answer = inner();
+                if let Some(answer) = &answer {
+                    recorder.borrow_mut().prompts.push(answer.clone());
+                }
+                answer
+            });
+        }
+        devices.input = Some(match &args.input {
+            Some(input) => InputPort::new(addr, read_input(input)?),
+            None => InputPort::new(addr, Vec::new()).with_prompt(prompt),
+        });
+    }
+    let mut fill = FillPattern::Byte(args.fill.unwrap_or(0));
+    if args.random.is_some() || args.fill_random {
+        let seed = replay.as_ref().and_then(|it| it.seed).or(args.seed);
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |it| it.as_nanos() as u64)
+        });
+        if let Some(addr) = args.random {
+            devices.random = Some(Random::new(addr, seed));
+        }
+        if args.fill_random {
+            fill = FillPattern::Random(seed);
+        }
+        if let Some(recorder) = &recorder {
+            recorder.borrow_mut().seed = Some(seed);
+        }
+    }
+    let window = args.shared.map(|(start, len)| Window::new(start, len));
+    let builder = || {
+        let builder = VmBuilder::new()
+            .memory_size(args.memory)
+            .isa(isa)
+            .fill(fill)
+            .detect_loops(args.detect_loops)
+            .step_limit(Some(args.max_steps).filter(|&it| args.quiet && it > 0))
+            .time_limit(args.time_limit.filter(|_| args.quiet))
+            .unloaded(args.unloaded)
+            .end_of_memory(args.end_of_memory);
+        match &window {
+            Some(window) => builder.memory(Box::new(window.memory(args.memory))),
+            None => builder,
+        }
+    };
+    let main = args
+        .reg
+        .iter()
+        .fold(builder(), |builder, &(reg, value)| builder.reg(reg, value));
+    let mut vm = args
+        .data
+        .iter()
+        .fold(main, |builder, (addr, bytes)| builder.data(*addr, bytes))
+        .program(&bytes)
+        .entry(entry)
+        .devices(devices)
+        .try_build()?;
+    vm.tracer = match &args.trace {
+        Some(path) => Some(
+            Tracer::create(
+                path,
+                args.trace_format
+                    .unwrap_or_else(|| TraceFormat::guess(path)),
+            )
+            .context(format!("Failed to create trace file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let focus = match &args.focus {
+        Some(_) if args.quiet => bail!("--focus only applies to the interactive UI"),
+        Some(s) => {
+            let addr = parse_location(s, source.as_ref())
+                .context(format!("Expected an address or label to focus on, got {s}"))?;
+            if addr as usize >= args.memory {
+                bail!("The focus 0x{addr:02X} would exceed memory bounds");
+            }
+            Some(Focus {
+                addr,
+                run: args.run_to_focus,
+            })
+        }
+        None if args.run_to_focus => bail!("--run-to-focus needs --focus"),
+        None => None,
+    };
+    for s in &args.breakpoints {
+        let addr = parse_location(s, source.as_ref())
+            .context(format!("Expected an address or label to break at, got {s}"))?;
+        if addr as usize >= args.memory {
+            bail!("The breakpoint at 0x{addr:02X} would exceed memory bounds");
+        }
+        vm.breakpoints.insert(Addr(addr));
+    }
+    let profiler =
+        (args.profile || args.coverage).then(|| Rc::new(RefCell::new(Profiler::new(args.memory))));
+    if let Some(profiler) = &profiler {
+        vm.observe(profiler.clone());
+    }
+    if let Some(path) = &args.load_state {
+        let snapshot = state::load(path)?;
+        if snapshot.memory.len() != vm.memory.len() {
+            bail!(
+                "The state has {} bytes of memory, but the machine has {}",
+                snapshot.memory.len(),
+                vm.memory.len()
+            );
+        }
+        vm.restore(&snapshot);
+    }
+    let view = View {
+        floats: args.floats,
+        speed: args.speed,
+        theme: if args.no_color || env::var_os("NO_COLOR").is_some_and(|it| !it.is_empty()) {
+            Theme::Mono
+        } else {
+            args.theme
+        },
+        vim: args.vim,
+        inline: args.inline,
+        ..Default::default()
+    };
+    if let Some(window) = window.as_ref().filter(|_| !cores.is_empty()) {
+        let shared: Vec<_> = window
+            .range()
+            .map(|addr| vm.memory.load(addr as u16))
+            .collect();
+        let mut vms = vec![vm];
+        for bytes in &cores {
+            let devices = Devices {
+                output: Some(OutputPort::new(args.output_port)),
+                ..Default::default()
+            };
+            vms.push(builder().program(bytes).devices(devices).build());
+        }
+        // Loading the other programs cleared the shared memory, which the
+        // main program initializes
+        for (addr, value) in window.range().zip(shared) {
+            vms[0].memory.store(addr as u16, value);
+        }
+        let mut cluster = Cluster::new(vms, window);
+        return if args.quiet {
+            execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+            let res = cluster.run();
+            finish(res, true, |_| term::print_cluster(&cluster, view))
+        } else {
+            term::interactive_cluster(&mut cluster, view)?;
+            Ok(ExitCode::SUCCESS)
+        };
+    }
+    let mut session = Session::default();
+    session.path = source.is_some().then(|| file.clone());
+    session.hot_reload = args.hot_reload;
+    session.source = source;
+    let res = if args.quiet {
+        if !args.json && !args.plain && args.summary.is_none() {
+            execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0))?;
+        }
+        let print = |vm: &VM, halted: bool, limit: Option<&VmError>| -> Result<()> {
+            if let Some(width) = args.summary {
+                println!("{}", summary(&file, width, vm, halted, limit));
+            } else if args.json {
+                println!("{}", state::outcome(vm, halted, limit)?);
+            } else if args.plain {
+                print!("{}", ui::report(vm, halted));
+            } else {
+                vm.print_state(view)?;
+                if let Some(seed) = vm.seed() {
+                    println!("Seed {seed}, pass --seed {seed} to run the same way again");
+                }
+            }
+            Ok(())
+        };
+        // Every breakpoint stops the run to print the state so far, except in
+        // the table of several programs
+        let pause = |vm: &VM| -> Result<()> {
+            if args.summary.is_some() {
+                return Ok(());
+            }
+            if !args.json {
+                let steps = vm.history.iter().filter(|it| !it.edit).count();
+                println!("Breakpoint at {:?}, step {steps}", vm.pc);
+            }
+            print(vm, false, None)
+        };
+        let mut run = |vm: &mut VM| -> Result<ExitCode> {
+            let res = match &replay {
+                Some(replay) => session
+                    .replay(vm, &replay.commands)
+                    .map(|running| args.to_step.is_some_and(|step| rewind(vm, step)) || running),
+                None => {
+                    if vm.breakpoints.contains(&vm.pc) {
+                        pause(vm)?;
+                    }
+                    loop {
+                        if let Some(recorder) = &recorder {
+                            recorder.borrow_mut().push(Command::Run);
+                        }
+                        match session.perform(vm, &Command::Run) {
+                            Ok(true) => pause(vm)?,
+                            res => break res,
+                        }
+                    }
+                }
+            };
+            let halted = matches!(res, Ok(false));
+            // Running off into memory without source is blamed on the
+            // instruction that jumped or stepped there
+            let last = vm.history.iter().rev().find(|it| !it.edit);
+            let line = session
+                .source
+                .as_ref()
+                .and_then(|it| it.map.line(vm.pc.0).or_else(|| it.map.line(last?.pc.0)));
+            finish(res.map(drop), args.summary.is_none(), |limit| {
+                print(vm, halted, limit)
+            })
+            .map_err(|err| Located::new(&file, line, err).into())
+        };
+        run(&mut vm)
+    } else {
+        let commands = replay.as_ref().map_or(&[][..], |it| &it.commands);
+        let mut run = |vm: &mut VM| -> Result<ExitCode> {
+            session.replay(vm, commands)?;
+            if let Some(step) = args.to_step {
+                rewind(vm, step);
+            }
+            vm.interactive(view, &mut session, recorder.as_ref(), focus)?;
+            Ok(ExitCode::SUCCESS)
+        };
+        run(&mut vm)
+    };
+    if let (Some(path), Some(recorder)) = (&args.record, &recorder) {
+        recorder.borrow().save(path)?;
+    }
+    if let Some(path) = &args.dump_state {
+        state::save(path, vm.snapshot())?;
+    }
+    if let Some(path) = &args.dump_mem {
+        state::dump_memory(path, &vm)?;
+    }
+    if let Some(path) = &args.dump_regs {
+        state::dump_registers(path, &vm)?;
+    }
+    if let Some((step, pc)) = vm.ran_off {
+        eprintln!(
+            "Warning: the program counter ran past the end of the program to {pc:?} at step {step}, is a halt missing?"
+        );
+    }
+    if let Some(profiler) = &profiler {
+        let profiler = profiler.borrow();
+        if args.profile {
+            print!("{}", profiler.report(&vm, bytes.len()));
+        }
+        if args.coverage {
+            print!("{}", profiler.coverage(bytes.len()));
+        }
+    }
+    res
+}
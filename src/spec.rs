@@ -0,0 +1,302 @@
+use crate::{
+    asm::{parse_location, parse_reg, Source},
+    builder::VmBuilder,
+    device::{Devices, InputPort, OutputPort},
+    vm::{Reg, StopReason, VmError, VM},
+};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// The address of the output port every case prints to.
+pub const OUTPUT_PORT: u16 = 0xFF;
+/// The address of the input port a case with `input` reads from.
+pub const INPUT_PORT: u16 = 0xFE;
+
+/// Bytes given as text, or as a list of numbers for bytes that are not
+/// printable, as in `[1, 2, 255]`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Bytes {
+    /// Text, written as its UTF-8 bytes
+    Text(String),
+    /// Bytes as they are
+    Raw(Vec<u8>),
+}
+
+impl Bytes {
+    fn to_vec(&self) -> Vec<u8> {
+        match self {
+            Bytes::Text(text) => text.as_bytes().to_vec(),
+            Bytes::Raw(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// The cases `v8-cpu test` runs a program against, read from a JSON file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    /// The steps after which a case fails, unless it gives its own.
+    #[serde(default = "default_max_steps")]
+    pub max_steps: usize,
+    /// The cases, run in order.
+    pub cases: Vec<Case>,
+}
+
+fn default_max_steps() -> usize {
+    1_000_000
+}
+
+/// One run of a program, set up and checked as a spec says.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    /// What the case is called in reports.
+    pub name: String,
+    /// Registers to start with, by name, as in `"R1": 16`.
+    #[serde(default)]
+    pub regs: BTreeMap<String, u8>,
+    /// Bytes to store over the program, as hex by address or label, as in
+    /// `"0x80": "dead"`.
+    #[serde(default)]
+    pub memory: BTreeMap<String, String>,
+    /// The text or bytes read from the input port.
+    pub input: Option<Bytes>,
+    /// The steps after which this case fails, instead of the spec's.
+    pub max_steps: Option<usize>,
+    /// What the program must leave behind.
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+/// What a case checks once the program stops. Anything left out is not
+/// checked, except that the program must halt.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expect {
+    /// Registers by name, as in `Case::regs`.
+    pub regs: BTreeMap<String, u8>,
+    /// Bytes by address or label, as in `Case::memory`.
+    pub memory: BTreeMap<String, String>,
+    /// The text or bytes written to the output port.
+    pub output: Option<Bytes>,
+    /// Whether the program halts, rather than running out of steps.
+    pub halted: bool,
+}
+
+impl Default for Expect {
+    fn default() -> Self {
+        Self {
+            regs: BTreeMap::new(),
+            memory: BTreeMap::new(),
+            output: None,
+            halted: true,
+        }
+    }
+}
+
+impl Spec {
+    /// Reads a spec from the JSON file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = fs::read_to_string(path)
+            .context(format!("Failed to read test spec from {}", path.display()))?;
+        serde_json::from_str(&s).context("Malformed test spec")
+    }
+}
+
+fn reg(name: &str) -> Result<Reg> {
+    parse_reg(name)
+        .map(Reg)
+        .context(format!("Not a register: {name}"))
+}
+
+/// Parses the memory of a case into the bytes from every address on, which
+/// must fit in `size` bytes of memory.
+fn memory(
+    memory: &BTreeMap<String, String>,
+    source: Option<&Source>,
+    size: usize,
+) -> Result<Vec<(u16, Vec<u8>)>> {
+    memory
+        .iter()
+        .map(|(addr, bytes)| {
+            let addr = parse_location(addr, source)
+                .context(format!("Expected an address or label, got {addr}"))?;
+            let Ok(bytes) = crate::program::hex_to_bytes(bytes.clone()) else {
+                bail!("Expected hex bytes, got {bytes}");
+            };
+            if addr as usize + bytes.len() > size {
+                bail!("The bytes at 0x{addr:02X} would exceed memory bounds");
+            }
+            Ok((addr, bytes))
+        })
+        .collect()
+}
+
+impl Case {
+    /// Runs `program` from a VM made by `builder`, returning every way its
+    /// result differs from the expectation, or fails if the case itself is
+    /// not valid.
+    pub fn run(
+        &self,
+        builder: VmBuilder,
+        program: &[u8],
+        source: Option<&Source>,
+        max_steps: usize,
+    ) -> Result<Vec<String>> {
+        let mut builder = builder
+            .program(program)
+            .step_limit(Some(self.max_steps.unwrap_or(max_steps)).filter(|&it| it > 0))
+            .devices(Devices {
+                output: Some(OutputPort::new(OUTPUT_PORT)),
+                input: self
+                    .input
+                    .as_ref()
+                    .map(|it| InputPort::new(INPUT_PORT, it.to_vec())),
+                ..Default::default()
+            });
+        for (name, &value) in &self.regs {
+            builder = builder.reg(reg(name)?, value);
+        }
+        let mut vm = builder.build();
+        vm.data = memory(&self.memory, source, vm.memory.len())?;
+        vm.fill(program);
+        let expected = memory(&self.expect.memory, source, vm.memory.len())?;
+        let halted = match vm.run() {
+            Ok(stop) => stop == StopReason::Halted,
+            Err(VmError::StepLimit { .. }) if !self.expect.halted => false,
+            Err(err) => return Ok(vec![err.to_string()]),
+        };
+        self.compare(&vm, halted, &expected)
+    }
+
+    fn compare(&self, vm: &VM, halted: bool, memory: &[(u16, Vec<u8>)]) -> Result<Vec<String>> {
+        let expect = &self.expect;
+        let mut failures = Vec::new();
+        if halted != expect.halted {
+            failures.push(if halted {
+                "halted, expected it not to".to_string()
+            } else {
+                "did not halt".to_string()
+            });
+        }
+        for (name, &value) in &expect.regs {
+            let actual = vm.getr(reg(name)?).0;
+            if actual != value {
+                failures.push(format!(
+                    "{} is 0x{actual:02X}, expected 0x{value:02X}",
+                    name.to_ascii_uppercase()
+                ));
+            }
+        }
+        for (addr, bytes) in memory {
+            for (i, &value) in bytes.iter().enumerate() {
+                let addr = addr + i as u16;
+                let actual = vm.memory.load(addr);
+                if actual != value {
+                    failures.push(format!(
+                        "mem[0x{addr:02X}] is 0x{actual:02X}, expected 0x{value:02X}"
+                    ));
+                }
+            }
+        }
+        let output = vm.devices.output.as_ref();
+        match &expect.output {
+            Some(Bytes::Text(text)) => {
+                let actual = output.map(OutputPort::text).unwrap_or_default();
+                if &actual != text {
+                    failures.push(format!("printed {actual:?}, expected {text:?}"));
+                }
+            }
+            Some(Bytes::Raw(bytes)) => {
+                let actual = output.map_or(&[][..], |it| &it.buffer);
+                if actual != bytes {
+                    failures.push(format!("wrote {actual:02X?}, expected {bytes:02X?}"));
+                }
+            }
+            None => {}
+        }
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_with_map;
+
+    /// How the program in `code` fails the case in `case`, as JSON.
+    fn failures(code: &str, case: &str) -> Result<Vec<String>> {
+        let (program, map) = assemble_with_map(code, Default::default(), 256).unwrap();
+        let source = Source {
+            lines: code.lines().map(str::to_string).collect(),
+            map,
+        };
+        let case: Case = serde_json::from_str(case).unwrap();
+        case.run(VmBuilder::new(), &program, Some(&source), 1000)
+    }
+
+    /// Prints the byte of input it reads, plus one.
+    const ECHO: &str = "loadb r2, 1\nloadm r1, 0xFE\naddi r1, r1, r2\nstorem r1, 0xFF\nhalt";
+
+    #[test]
+    fn passes() {
+        let case = r#"{"name": "b", "input": "a", "expect": {"regs": {"r1": 98}, "output": "b"}}"#;
+        assert!(failures(ECHO, case).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fails_with_every_difference() {
+        let case = r#"{
+            "name": "c",
+            "input": [1],
+            "expect": {"regs": {"R1": 3}, "memory": {"0x00": "2202"}, "output": [3]}
+        }"#;
+        assert_eq!(
+            failures(ECHO, case).unwrap(),
+            [
+                "R1 is 0x02, expected 0x03",
+                "mem[0x01] is 0x01, expected 0x02",
+                "wrote [02], expected [03]",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_halting() {
+        let code = "loop: jump r0, loop";
+        let case = r#"{"name": "spins", "max_steps": 10, "expect": {"halted": false}}"#;
+        assert!(failures(code, case).unwrap().is_empty());
+        let case = r#"{"name": "spins", "max_steps": 10}"#;
+        assert_eq!(
+            failures(code, case).unwrap(),
+            ["Gave up after 10 steps without halting"]
+        );
+        let case = r#"{"name": "halts", "expect": {"halted": false}}"#;
+        assert_eq!(
+            failures("halt", case).unwrap(),
+            ["halted, expected it not to"]
+        );
+    }
+
+    #[test]
+    fn sets_up_registers_and_memory() {
+        let code = "storem r1, value\nhalt\nvalue: db 0";
+        let case = r#"{
+            "name": "store",
+            "regs": {"r1": 7},
+            "memory": {"value": "05"},
+            "expect": {"memory": {"value": "07"}}
+        }"#;
+        assert!(failures(code, case).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_cases() {
+        let case = r#"{"name": "bad", "regs": {"rz": 1}}"#;
+        assert!(failures("halt", case).is_err());
+        let case = r#"{"name": "bad", "memory": {"0xFF": "0102"}}"#;
+        assert!(failures("halt", case).is_err());
+    }
+}
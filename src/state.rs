@@ -0,0 +1,149 @@
+use crate::vm::{Addr, Snapshot, VmError, VM};
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+const VERSION: u32 = 1;
+
+/// A file format, a versioned JSON encoding of `T`. The `.v8state` format
+/// holds a `Snapshot`, and the `.v8session` format a `SessionFile`.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    #[serde(flatten)]
+    inner: T,
+}
+
+/// A debugging session: the machine state with the breakpoints, watches and
+/// named snapshots set up around it.
+#[derive(Serialize, Deserialize)]
+pub struct SessionFile {
+    pub snapshot: Snapshot,
+    pub breakpoints: Vec<Addr>,
+    /// The text of every watched expression.
+    pub watches: Vec<String>,
+    pub snapshots: Vec<(String, Snapshot)>,
+}
+
+fn write<T: Serialize>(path: &Path, what: &str, inner: T) -> Result<()> {
+    let file = Versioned {
+        version: VERSION,
+        inner,
+    };
+    fs::write(path, serde_json::to_string(&file)?)
+        .context(format!("Failed to write {what} to {}", path.display()))
+}
+
+fn read<T: DeserializeOwned>(path: &Path, what: &str) -> Result<T> {
+    let s = fs::read_to_string(path)
+        .context(format!("Failed to read {what} from {}", path.display()))?;
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    let Version { version } = serde_json::from_str(&s).context(format!("Not a {what} file"))?;
+    if version != VERSION {
+        bail!("Unsupported {what} file version {version} (expected {VERSION})");
+    }
+    let file: Versioned<T> = serde_json::from_str(&s).context(format!("Malformed {what} file"))?;
+    Ok(file.inner)
+}
+
+pub fn save(path: &Path, snapshot: Snapshot) -> Result<()> {
+    write(path, "state", snapshot)
+}
+
+pub fn load(path: &Path) -> Result<Snapshot> {
+    read(path, "state")
+}
+
+pub fn save_session(path: &Path, session: SessionFile) -> Result<()> {
+    write(path, "session", session)
+}
+
+pub fn load_session(path: &Path) -> Result<SessionFile> {
+    read(path, "session")
+}
+
+/// The registers as `dump_registers` writes them, by name.
+#[derive(Serialize)]
+struct Registers {
+    #[serde(flatten)]
+    regs: BTreeMap<String, u8>,
+    pc: u16,
+    flags: u8,
+}
+
+/// Writes the contents of memory as raw bytes.
+pub fn dump_memory(path: &Path, vm: &VM) -> Result<()> {
+    fs::write(path, vm.memory.bytes())
+        .context(format!("Failed to write memory to {}", path.display()))
+}
+
+/// Writes the registers, program counter and flags as a JSON object, such as
+/// `{"R0":0,...,"RF":240,"pc":8,"flags":0}`.
+pub fn dump_registers(path: &Path, vm: &VM) -> Result<()> {
+    let regs = Registers {
+        regs: (0..16).map(|i| (format!("R{i:X}"), vm.regs[i])).collect(),
+        pc: vm.pc.0,
+        flags: vm.flags,
+    };
+    fs::write(path, serde_json::to_string(&regs)?)
+        .context(format!("Failed to write registers to {}", path.display()))
+}
+
+/// The result of a run as `outcome` prints it.
+#[derive(Serialize)]
+struct Outcome {
+    regs: [u8; 16],
+    /// All of memory as hex.
+    mem: String,
+    pc: u16,
+    steps: usize,
+    halted: bool,
+    /// The seed of the random port and fill, if the run used them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// The limit the run gave up at, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<Limit>,
+}
+
+/// A limit a run gave up at, as `VmError::StepLimit` or `VmError::TimeLimit`.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Limit {
+    Steps { steps: usize, message: String },
+    Time { seconds: f64, message: String },
+}
+
+/// The final state of a run as a line of JSON, with the limit it ran out
+/// of, if it did.
+pub fn outcome(vm: &VM, halted: bool, limit: Option<&VmError>) -> Result<String> {
+    let limit = limit.and_then(|err| match *err {
+        VmError::StepLimit { limit } => Some(Limit::Steps {
+            steps: limit,
+            message: err.to_string(),
+        }),
+        VmError::TimeLimit { limit } => Some(Limit::Time {
+            seconds: limit.as_secs_f64(),
+            message: err.to_string(),
+        }),
+        _ => None,
+    });
+    let outcome = Outcome {
+        regs: vm.regs,
+        mem: vm
+            .memory
+            .bytes()
+            .iter()
+            .map(|it| format!("{it:02X}"))
+            .collect(),
+        pc: vm.pc.0,
+        steps: vm.history.iter().filter(|it| !it.edit).count(),
+        halted,
+        seed: vm.seed(),
+        limit,
+    };
+    Ok(serde_json::to_string(&outcome)?)
+}
@@ -1,145 +1,1279 @@
-use crate::vm::{Action, Const, Reg, VM};
-use anyhow::Result;
+use crate::{
+    asm::{parse_location, parse_reg, Source},
+    cluster::Cluster,
+    float,
+    observer::Observer,
+    replay::{self, Command, Recorder, Session},
+    state::{self, SessionFile},
+    ui::{self, Base, Target, Targets, Theme},
+    vm::{Addr, Const, Reg, Step, SP, VM},
+    watch::{Expr, Watch},
+};
+use anyhow::{bail, Context, Result};
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, MouseButton, MouseEvent,
+        MouseEventKind,
+    },
     execute,
     terminal::{
         disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
         LeaveAlternateScreen,
     },
 };
-use std::io::stdout;
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::Rect,
+    style::{Modifier, Style},
+    Terminal, TerminalOptions, Viewport,
+};
+use std::{
+    cell::{Cell, RefCell},
+    io::{stdout, BufWriter, Stdout},
+    path::Path,
+    process,
+    rc::Rc,
+    time::Duration,
+};
 
-pub trait TerminalExt {
-    fn print_state(&self) -> Result<()>;
-    fn interactive(&mut self) -> Result<()>;
+/// How many instructions run between keyboard polls in keyboard mode.
+const KEYBOARD_BATCH: usize = 64;
+
+/// How many instructions run between frames while running until a halt.
+const RUN_BATCH: usize = 10_000;
+
+/// The status shown while the program is halted.
+const HALTED: &str = "Halted, press Z or Home to step back, R to reset or Q to quit";
+
+/// How often the source file is checked for changes with `--hot-reload`.
+const RELOAD_POLL: Duration = Duration::from_millis(250);
+
+/// The fastest play mode can step, in steps per second.
+pub const MAX_SPEED: u32 = 60;
+
+thread_local! {
+    /// Set when something was printed behind the back of the terminal
+    /// widgets, which then have to redraw everything.
+    static SCRIBBLED: Cell<bool> = const { Cell::new(false) };
 }
 
-impl TerminalExt for VM {
-    fn print_state(&self) -> Result<()> {
-        use crossterm::style::*;
-        execute!(
-            stdout(),
-            cursor::MoveTo(0, 0),
-            SetForegroundColor(Color::Yellow)
-        )?;
-        for i in 0..16 {
-            execute!(stdout(), Print(format!("R{i:X} ")),)?;
-        }
-        execute!(stdout(), cursor::MoveToNextLine(1), ResetColor,)?;
-        for i in 0..16 {
-            if matches!(self.actions.last(), Some(Action::SetReg(Reg(j), _)) if i == *j) {
-                execute!(stdout(), SetBackgroundColor(Color::DarkMagenta))?;
-            }
-            execute!(
-                stdout(),
-                Print(format!("{:02X}", self.getr(Reg(i)).0)),
-                ResetColor,
-                Print(' '),
-            )?;
-        }
-        execute!(stdout(), ResetColor, cursor::MoveToNextLine(1))?;
-        let s = format!("{:?}", self.dis(self.pc));
-        let index = s.find('(').unwrap_or(s.len());
+/// The terminal of the interactive UI, which writes each frame at once.
+type Tui = Terminal<CrosstermBackend<BufWriter<Stdout>>>;
+
+/// How many bytes of a frame are buffered before they are written out, which
+/// is enough for a whole frame of a large terminal.
+const FRAME_BUFFER: usize = 1 << 16;
+
+/// Takes over the terminal for the interactive UI: the alternate screen, or
+/// with `inline`, the rows below the shell prompt, scrolling what is above
+/// them up as needed.
+fn enter(inline: bool) -> Result<Tui> {
+    enable_raw_mode()?;
+    execute!(stdout(), cursor::Hide)?;
+    let backend = CrosstermBackend::new(BufWriter::with_capacity(FRAME_BUFFER, stdout()));
+    if inline {
+        let (_, rows) = crossterm::terminal::size()?;
+        let viewport = Viewport::Inline(rows);
+        Ok(Terminal::with_options(
+            backend,
+            TerminalOptions { viewport },
+        )?)
+    } else {
+        execute!(stdout(), EnterAlternateScreen)?;
+        Ok(Terminal::new(backend)?)
+    }
+}
+
+/// Gives the terminal back, leaving the last frame on the screen with
+/// `inline`.
+fn leave(terminal: &mut Tui, inline: bool) -> Result<()> {
+    if inline {
+        let bottom = terminal.get_frame().area().bottom();
+        execute!(stdout(), cursor::MoveTo(0, bottom.saturating_sub(1)))?;
+        println!("\r");
+    } else {
+        execute!(stdout(), LeaveAlternateScreen)?;
+    }
+    execute!(stdout(), cursor::Show)?;
+    disable_raw_mode()?;
+    Ok(())
+}
+
+/// Draws `sections` over the whole terminal, after a full redraw if a prompt
+/// wrote to it since the last time, and returns where their targets are.
+fn redraw(terminal: &mut Tui, sections: Vec<ui::Section>, theme: Theme) -> Result<Targets> {
+    if SCRIBBLED.take() {
+        // The prompt only wrote over the bottom row, so make just that row
+        // differ from the last frame instead of repainting everything
+        terminal.draw(|frame| {
+            let area = frame.area();
+            ui::render(sections.clone(), theme, area, frame.buffer_mut());
+            let row = Rect {
+                y: area.bottom().saturating_sub(1),
+                height: 1,
+                ..area
+            };
+            let hidden = Style::new().add_modifier(Modifier::HIDDEN);
+            frame
+                .buffer_mut()
+                .set_string(row.x, row.y, " ".repeat(row.width as usize), hidden);
+        })?;
+    }
+    let mut targets = Vec::new();
+    terminal
+        .draw(|frame| targets = ui::render(sections, theme, frame.area(), frame.buffer_mut()))?;
+    Ok(targets)
+}
+
+/// Reads a line of text on the bottom row of the screen, returning `None` if
+/// the user cancels with Esc. Expects the terminal to be in raw mode.
+pub fn prompt(message: &str) -> Result<Option<String>> {
+    use crossterm::style::*;
+    let (_, rows) = crossterm::terminal::size()?;
+    let mut line = String::new();
+    SCRIBBLED.set(true);
+    execute!(stdout(), cursor::Show)?;
+    let res = loop {
         execute!(
             stdout(),
-            cursor::MoveToNextLine(1),
-            SetForegroundColor(Color::DarkGrey),
+            cursor::MoveTo(0, rows.saturating_sub(1)),
             Clear(ClearType::CurrentLine),
-            Print("Current: "),
             SetForegroundColor(Color::Yellow),
-            Print(&s[..index]),
-            SetForegroundColor(Color::Red),
-            Print(&s[index..]),
+            Print(message),
             ResetColor,
-            cursor::MoveToNextLine(1)
+            Print(&line),
         )?;
-        for i in 0..=255 {
-            if i % 16 == 0 {
-                execute!(
-                    stdout(),
-                    cursor::MoveToNextLine(1),
-                    SetForegroundColor(Color::DarkGrey),
-                    Print(format!("0x{i:02X}:")),
-                    ResetColor,
-                )?;
+        if let Event::Key(event) = event::read()? {
+            match event.code {
+                KeyCode::Enter => break Some(line),
+                KeyCode::Esc => break None,
+                KeyCode::Backspace => {
+                    line.pop();
+                }
+                KeyCode::Char(c) => line.push(c),
+                _ => {}
             }
-            execute!(stdout(), Print(' '))?;
-            if matches!(self.actions.last(), Some(Action::SetMem(Const(j), _)) if i == *j) {
-                execute!(stdout(), SetBackgroundColor(Color::DarkMagenta))?;
+        }
+    };
+    execute!(
+        stdout(),
+        cursor::Hide,
+        cursor::MoveTo(0, rows.saturating_sub(1)),
+        Clear(ClearType::CurrentLine),
+    )?;
+    Ok(res)
+}
+
+/// Asks for a source line, or an address without source, to toggle a
+/// breakpoint at.
+fn breakpoint_prompt(source: Option<&Source>) -> Result<Option<u16>> {
+    let message = if source.is_some() {
+        "Breakpoint at line: "
+    } else {
+        "Breakpoint at address: "
+    };
+    let Some(line) = prompt(message)? else {
+        return Ok(None);
+    };
+    Ok(match source {
+        Some(source) => line.trim().parse().ok().and_then(|it| source.map.addr(it)),
+        None => parse_location(&line, None),
+    })
+}
+
+/// Asks for a register and its new value, as in `R1=4F`. Values with a point
+/// in them are encoded as 8-bit floats.
+fn register_prompt() -> Result<Option<(u8, u8)>> {
+    let Some(line) = prompt("Set register (e.g. R1=4F): ")? else {
+        return Ok(None);
+    };
+    let line = line.trim().to_ascii_lowercase();
+    let parse = || {
+        let (reg, value) = line.split_once('=')?;
+        Some((parse_reg(reg)?, parse_hex(value)?))
+    };
+    Ok(parse())
+}
+
+/// What a `:` command asks the interactive loop to do.
+enum Colon {
+    Perform(Command),
+    Quit,
+    /// The command was carried out on the spot.
+    Done,
+    /// Goes back to a saved session.
+    Restore(Box<SessionFile>),
+    /// Opens the source in an editor, then assembles it again.
+    Edit,
+}
+
+/// Parses a byte typed at a prompt: hex with or without `0x`, or an 8-bit
+/// float if it has a point in it.
+fn parse_hex(s: &str) -> Option<u8> {
+    let s = s.trim();
+    if s.contains('.') {
+        return parse_value(s);
+    }
+    u8::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Parses a value for a register: hex with `0x`, decimal, or an 8-bit float
+/// if it has a point in it.
+fn parse_value(s: &str) -> Option<u8> {
+    let s = s.to_ascii_lowercase();
+    if s.contains('.') {
+        return s.parse().ok().map(float::encode);
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s
+            .parse::<i16>()
+            .ok()
+            .filter(|it| (-128..256).contains(it))
+            .map(|it| it as u8),
+    }
+}
+
+/// Carries out a line typed after `:`, such as `break 0x20`, `set r3 0xff`,
+/// `run 100` or `save state.v8state`.
+fn colon_command(
+    line: &str,
+    vm: &VM,
+    view: &mut View,
+    session: &mut Session,
+    highlights: &Highlights,
+) -> std::result::Result<Colon, String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(Colon::Done);
+    };
+    let args: Vec<_> = words.collect();
+    let source = session.source.as_ref();
+    let location = |s: &str| {
+        parse_location(s, source)
+            .filter(|&it| (it as usize) < vm.memory.len())
+            .ok_or_else(|| format!("Not an address or label: {s}"))
+    };
+    let count = |s: &str| s.parse::<usize>().map_err(|_| format!("Not a count: {s}"));
+    let command = match (name, args.as_slice()) {
+        ("q" | "quit", []) => return Ok(Colon::Quit),
+        ("b" | "break", [addr]) => Command::Breakpoint(location(addr)?),
+        ("set", [reg, value]) => {
+            let Some(Expr::Reg(reg)) = Expr::parse(reg, None) else {
+                return Err(format!("Not a register: {reg}"));
+            };
+            let value = parse_value(value).ok_or_else(|| format!("Not a byte: {value}"))?;
+            Command::SetReg(reg.0, value)
+        }
+        ("s" | "step", []) => Command::Step,
+        ("s" | "step", [n]) => Command::Steps(count(n)?),
+        ("o" | "over", []) => Command::StepOver,
+        ("r" | "run", []) => Command::Run,
+        ("r" | "run", [n]) => Command::Steps(count(n)?),
+        ("undo", []) => Command::Undo,
+        ("redo", []) => Command::Redo,
+        ("reset", []) => Command::Reset,
+        ("reload", []) => Command::ResetHard,
+        ("save", [path]) => {
+            state::save(Path::new(path), vm.snapshot()).map_err(|err| format!("{err:#}"))?;
+            return Ok(Colon::Done);
+        }
+        ("g" | "goto", [addr]) => {
+            view.goto(vm, location(addr)?, 1);
+            return Ok(Colon::Done);
+        }
+        ("e" | "edit", []) => return Ok(Colon::Edit),
+        ("export", [path]) => {
+            let sections = ui::debugger(vm, *view, highlights, Some(session), None);
+            ui::export(Path::new(path), sections, view.theme, ui::columns())
+                .map_err(|err| format!("{err:#}"))?;
+            return Ok(Colon::Done);
+        }
+        ("save-session", [path]) => {
+            state::save_session(Path::new(path), session.to_file(vm))
+                .map_err(|err| format!("{err:#}"))?;
+            return Ok(Colon::Done);
+        }
+        ("load-session", [path]) => {
+            let file = state::load_session(Path::new(path)).map_err(|err| format!("{err:#}"))?;
+            return Ok(Colon::Restore(Box::new(file)));
+        }
+        ("snap", [name]) => {
+            let snapshot = vm.snapshot();
+            match session.snapshots.iter_mut().find(|(it, _)| it == name) {
+                Some((_, it)) => *it = snapshot,
+                None => session.snapshots.push((name.to_string(), snapshot)),
             }
-            if i == self.pc.0 {
-                execute!(stdout(), SetBackgroundColor(Color::Blue))?;
+            return Ok(Colon::Done);
+        }
+        ("diff", []) => {
+            view.diff = None;
+            return Ok(Colon::Done);
+        }
+        ("diff", [name]) => {
+            let index = session.snapshots.iter().position(|(it, _)| it == name);
+            view.diff = Some(index.ok_or_else(|| format!("No snapshot named {name}"))?);
+            return Ok(Colon::Done);
+        }
+        ("watch", [_, ..]) => {
+            let text = args.join(" ");
+            let expr = Expr::parse(&text, source.map(|it| &it.map))
+                .ok_or_else(|| format!("Not an expression: {text}"))?;
+            session.watches.push(Watch::new(text, expr, vm));
+            return Ok(Colon::Done);
+        }
+        _ => return Err(format!("Unknown command: {}", line.trim())),
+    };
+    Ok(Colon::Perform(command))
+}
+
+/// Opens the source file in `$EDITOR`, leaving the interactive UI meanwhile,
+/// and then restarts `vm` with the program assembled again.
+fn edit(terminal: &mut Tui, vm: &mut VM, session: &mut Session, inline: bool) -> Result<()> {
+    let Some(path) = &session.path else {
+        bail!("Only assembly programs can be edited");
+    };
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    if !inline {
+        execute!(stdout(), LeaveAlternateScreen)?;
+    }
+    execute!(stdout(), DisableMouseCapture, cursor::Show)?;
+    disable_raw_mode()?;
+    // The editor is a command line, which may come with arguments
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(path)
+        .status();
+    enable_raw_mode()?;
+    if !inline {
+        execute!(stdout(), EnterAlternateScreen)?;
+    }
+    execute!(stdout(), EnableMouseCapture, cursor::Hide)?;
+    terminal.clear()?;
+    let status = status.context(format!("Failed to run {editor}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    session.reassemble(vm)
+}
+
+/// Where the interactive UI starts out looking.
+#[derive(Clone, Copy)]
+pub struct Focus {
+    /// The memory cell selected and scrolled to.
+    pub addr: u16,
+    /// Also run until the program counter first reaches `addr`, which Esc
+    /// interrupts like any run.
+    pub run: bool,
+}
+
+pub trait TerminalExt {
+    fn print_state(&self, view: View) -> Result<()>;
+    fn interactive(
+        &mut self,
+        view: View,
+        session: &mut Session,
+        recorder: Option<&Recorder>,
+        focus: Option<Focus>,
+    ) -> Result<()>;
+}
+
+/// How the machine state is displayed.
+#[derive(Clone, Copy)]
+pub struct View {
+    /// Also show every register as an 8-bit float.
+    pub floats: bool,
+    /// How many steps per second play mode runs.
+    pub speed: u32,
+    /// The address of the first memory row shown.
+    pub origin: u16,
+    /// The memory cell selected with `G`, or the first of a search match.
+    pub cursor: Option<u16>,
+    /// How many cells from `cursor` on are selected.
+    pub selection: u16,
+    pub theme: Theme,
+    /// The base of register values and the selected cells in the memory
+    /// pane title.
+    pub base: Base,
+    /// Also show registers as two's complement signed numbers.
+    pub signed: bool,
+    /// Also mark every register and cell that differs from the state the
+    /// session started in.
+    pub changes: bool,
+    /// Show the latest steps in a pane below memory.
+    pub history: bool,
+    /// How many of the latest steps the history pane is scrolled back past.
+    pub history_offset: usize,
+    /// Scroll the memory view to the program counter whenever it moves.
+    pub follow: bool,
+    /// The program counter the memory view last scrolled to.
+    pub followed: Option<u16>,
+    /// The snapshot in `Session::snapshots` the machine is compared with.
+    pub diff: Option<usize>,
+    /// Draw the interactive UI below the shell prompt instead of on the
+    /// alternate screen.
+    pub inline: bool,
+    /// The register whose latest values are shown below the registers.
+    pub register: Option<u8>,
+    /// How many of the latest output lines the console is scrolled back past.
+    pub console_offset: usize,
+    /// How many cells a memory row shows, which `fit` picks.
+    pub row_len: u16,
+    /// Move the selection with hjkl, which moves the keys they would take
+    /// elsewhere (see `vim_alias`).
+    pub vim: bool,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self {
+            floats: false,
+            speed: 5,
+            origin: 0,
+            cursor: None,
+            selection: 1,
+            theme: Theme::default(),
+            base: Base::default(),
+            signed: false,
+            vim: false,
+            changes: false,
+            history: false,
+            history_offset: 0,
+            follow: true,
+            followed: None,
+            diff: None,
+            inline: false,
+            register: None,
+            console_offset: 0,
+            row_len: 16,
+        }
+    }
+}
+
+impl View {
+    /// Shows 8 cells per memory row instead of 16 on a terminal narrower
+    /// than `COMPACT_WIDTH`, keeping the rows aligned.
+    pub fn fit(&mut self, vm: &VM, columns: u16) {
+        self.row_len = if columns < COMPACT_WIDTH { 8 } else { 16 };
+        self.origin -= self.origin % self.row_len;
+        self.origin = self.origin.min(self.last_origin(vm));
+        if self.follow && self.followed != Some(vm.pc.0) {
+            self.followed = Some(vm.pc.0);
+            self.show(vm, vm.pc.0);
+        }
+    }
+
+    /// How many memory rows are shown.
+    pub fn rows(&self, vm: &VM) -> u16 {
+        (vm.memory.len() as u16 / self.row_len).min(MEMORY_ROWS)
+    }
+
+    /// The address of the first row of the memory view scrolled all the way
+    /// down.
+    fn last_origin(&self, vm: &VM) -> u16 {
+        (vm.memory.len() as u16).saturating_sub(self.rows(vm) * self.row_len)
+    }
+
+    /// Selects `len` cells from `addr` on, and shows them.
+    fn goto(&mut self, vm: &VM, addr: u16, len: u16) {
+        self.cursor = Some(addr);
+        self.selection = len;
+        self.show(vm, addr);
+    }
+
+    /// Centers the row of `addr` in the memory view unless it is already
+    /// shown.
+    fn show(&mut self, vm: &VM, addr: u16) {
+        let row = addr - addr % self.row_len;
+        let shown = self.rows(vm) * self.row_len;
+        if row < self.origin || row >= self.origin.saturating_add(shown) {
+            self.origin = row.saturating_sub(shown / 2).min(self.last_origin(vm));
+        }
+    }
+
+    /// Scrolls the memory view by `rows` rows, down if positive.
+    fn scroll(&mut self, vm: &VM, rows: i32) {
+        let origin = self.origin as i32 + rows * self.row_len as i32;
+        self.origin = origin.clamp(0, self.last_origin(vm) as i32) as u16;
+    }
+
+    /// Moves the selected cell by `delta` cells, starting from the program
+    /// counter, and scrolls just enough to keep it in view.
+    fn move_cursor(&mut self, vm: &VM, delta: i32) {
+        let from = self.cursor.unwrap_or(vm.pc.0) as i32;
+        let addr = (from + delta).clamp(0, vm.memory.len() as i32 - 1) as u16;
+        self.cursor = Some(addr);
+        self.selection = 1;
+        let row = addr - addr % self.row_len;
+        let rows = self.rows(vm);
+        if row < self.origin {
+            self.origin = row;
+        } else if row >= self.origin + rows * self.row_len {
+            self.origin = row - (rows - 1) * self.row_len;
+        }
+    }
+
+    /// Scrolls the history pane back by `steps` steps, forward if negative.
+    fn scroll_history(&mut self, vm: &VM, steps: isize) {
+        let offset = self.history_offset.saturating_add_signed(steps);
+        self.history_offset = offset.min(vm.history.len().saturating_sub(1));
+    }
+
+    fn scroll_console(&mut self, vm: &VM, lines: isize) {
+        let offset = self.console_offset.saturating_add_signed(lines);
+        self.console_offset = offset.min(ui::output_len(vm).saturating_sub(1));
+    }
+
+    pub fn selected(&self, addr: u16) -> bool {
+        self.cursor
+            .is_some_and(|start| addr.wrapping_sub(start) < self.selection)
+    }
+}
+
+/// The key that does in vim mode what `c` does otherwise: `I` to type into
+/// the program, and `M` and `'` to set and jump back to a save point.
+fn vim_alias(c: char) -> char {
+    match c {
+        'i' => 'k',
+        'm' => 'p',
+        '\'' => 'l',
+        _ => c,
+    }
+}
+
+/// Parses bytes to search memory for, either a quoted string or hex bytes
+/// such as `de ad be ef`.
+fn parse_pattern(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if let Some(text) = s.strip_prefix('"') {
+        return Some(text.strip_suffix('"').unwrap_or(text).as_bytes().to_vec());
+    }
+    let hex: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Finds the first occurrence of `pattern` in memory after `from`, wrapping
+/// around at the end.
+fn search(vm: &VM, pattern: &[u8], from: Option<u16>) -> Option<u16> {
+    let len = vm.memory.len();
+    let start = from.map_or(0, |it| it as usize + 1);
+    (0..len)
+        .map(|i| (start + i) % len)
+        .find(|&addr| {
+            addr + pattern.len() <= len
+                && pattern
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &byte)| vm.memory.load((addr + i) as u16) == byte)
+        })
+        .map(|it| it as u16)
+}
+
+/// How many rows the memory view shows at most.
+pub const MEMORY_ROWS: u16 = 16;
+
+/// How many steps the history pane shows at once.
+pub const HISTORY_ROWS: u16 = 8;
+
+/// Terminals narrower than this get the compact layout.
+pub const COMPACT_WIDTH: u16 = 70;
+
+/// Handles a click or a turn of the wheel over the debugger. Clicking a
+/// memory cell selects it, and clicking it again asks for a new value.
+fn click(
+    vm: &VM,
+    view: &mut View,
+    targets: &Targets,
+    event: MouseEvent,
+) -> Result<Option<Command>> {
+    let Some(&(_, target)) = targets
+        .iter()
+        .rev()
+        .find(|(rect, _)| rect.contains(ratatui::layout::Position::new(event.column, event.row)))
+    else {
+        return Ok(None);
+    };
+    match (event.kind, target) {
+        (MouseEventKind::ScrollUp, Target::Memory | Target::Cell(_)) => view.scroll(vm, -1),
+        (MouseEventKind::ScrollDown, Target::Memory | Target::Cell(_)) => view.scroll(vm, 1),
+        (MouseEventKind::ScrollUp, Target::History) => view.scroll_history(vm, 1),
+        (MouseEventKind::ScrollDown, Target::History) => view.scroll_history(vm, -1),
+        (MouseEventKind::ScrollUp, Target::Console) => view.scroll_console(vm, 1),
+        (MouseEventKind::ScrollDown, Target::Console) => view.scroll_console(vm, -1),
+        (MouseEventKind::Down(MouseButton::Left), Target::Cell(addr)) => {
+            if view.cursor != Some(addr) || view.selection != 1 {
+                view.cursor = Some(addr);
+                view.selection = 1;
+                return Ok(None);
             }
-            execute!(
-                stdout(),
-                Print(format!("{:02X}", self.memory[i as usize])),
-                ResetColor
-            )?;
-        }
-        execute!(stdout(), cursor::MoveToNextLine(2))?;
-        for (key, desc) in [
-            ("Q", "Quit"),
-            ("S", "Step"),
-            ("Z", "Redo"),
-            ("R", "Reset"),
-            ("Enter", "Run All"),
-        ] {
-            execute!(
-                stdout(),
-                SetBackgroundColor(Color::DarkGreen),
-                SetForegroundColor(Color::White),
-                Print(format!("[{key}]")),
-                ResetColor,
-                SetForegroundColor(Color::DarkGreen),
-                Print(format!(" {desc} ")),
-                ResetColor,
-            )?;
-        }
-        execute!(stdout(), cursor::MoveToNextLine(1))?;
-        Ok(())
+            let value = prompt(&format!("Set 0x{addr:02X} to: "))?.and_then(|it| parse_hex(&it));
+            return Ok(value.map(|value| Command::SetMem(addr, value)));
+        }
+        (MouseEventKind::Down(MouseButton::Left), Target::Gutter(addr)) => {
+            return Ok(Some(Command::Breakpoint(addr)));
+        }
+        _ => {}
+    }
+    Ok(None)
+}
+
+/// Where keypresses go in interactive mode.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Debugger,
+    /// Keys are fed into the input port while the program runs freely.
+    Keyboard,
+    /// The program is stepped automatically at `View::speed`.
+    Play,
+    /// The program runs until it halts or reaches a breakpoint, showing
+    /// progress as it goes.
+    Run,
+    /// A line of input for the input port is being typed.
+    Input,
+}
+
+/// Tracks what every step wrote, so that the last step's writes can be
+/// highlighted even after undoing, and the state the session started in.
+#[derive(Default)]
+pub struct Highlights {
+    pending: Vec<Write>,
+    steps: Vec<Vec<Write>>,
+    initial_regs: [u8; 16],
+    /// Empty unless `remember` was called.
+    initial_memory: Vec<u8>,
+}
+
+enum Write {
+    Reg(Reg),
+    Mem(Addr),
+}
+
+impl Highlights {
+    fn last(&self) -> &[Write] {
+        self.steps.last().map_or(&[], |it| it.as_slice())
+    }
+
+    pub fn reg(&self, reg: Reg) -> bool {
+        self.last()
+            .iter()
+            .any(|it| matches!(it, Write::Reg(r) if r.0 == reg.0))
+    }
+
+    pub fn mem(&self, addr: Addr) -> bool {
+        self.last()
+            .iter()
+            .any(|it| matches!(it, Write::Mem(a) if *a == addr))
+    }
+
+    /// Takes the current state of `vm` as the one to compare against.
+    fn remember(&mut self, vm: &VM) {
+        self.initial_regs = vm.regs;
+        self.initial_memory = vm.memory.bytes();
+    }
+
+    /// Whether `reg` differs from the remembered state.
+    pub fn reg_changed(&self, vm: &VM, reg: Reg) -> bool {
+        !self.initial_memory.is_empty() && vm.getr(reg).0 != self.initial_regs[reg.0 as usize]
+    }
+
+    /// Whether the cell at `addr` differs from the remembered state.
+    pub fn mem_changed(&self, vm: &VM, addr: Addr) -> bool {
+        self.initial_memory
+            .get(addr.0 as usize)
+            .is_some_and(|&it| it != vm.memory.load(addr.0))
     }
 
-    fn interactive(&mut self) -> Result<()> {
-        enable_raw_mode()?;
-        execute!(stdout(), cursor::Hide, EnterAlternateScreen)?;
-        fn inner(vm: &mut VM) -> Result<()> {
+    /// Forgets what the steps so far wrote.
+    fn clear(&mut self) {
+        self.pending.clear();
+        self.steps.clear();
+    }
+}
+
+impl Observer for Highlights {
+    fn on_step(&mut self, _vm: &VM, _step: &Step) {
+        self.steps.push(std::mem::take(&mut self.pending));
+    }
+
+    fn on_reg_write(&mut self, reg: Reg, _old: Const, _new: Const) {
+        self.pending.push(Write::Reg(reg));
+    }
+
+    fn on_mem_write(&mut self, addr: Addr, _old: Const, _new: Const) {
+        self.pending.push(Write::Mem(addr));
+    }
+
+    fn on_undo(&mut self, _vm: &VM) {
+        self.steps.pop();
+    }
+}
+
+impl TerminalExt for VM {
+    fn print_state(&self, mut view: View) -> Result<()> {
+        view.fit(self, ui::columns());
+        let mut sections = ui::debugger(self, view, &Highlights::default(), None, None);
+        sections.push(ui::hints(self, Mode::Debugger, view));
+        ui::print(sections, view.theme)
+    }
+
+    fn interactive(
+        &mut self,
+        mut view: View,
+        session: &mut Session,
+        recorder: Option<&Recorder>,
+        focus: Option<Focus>,
+    ) -> Result<()> {
+        let mut terminal = enter(view.inline)?;
+        execute!(stdout(), EnableMouseCapture)?;
+        let highlights = Rc::new(RefCell::new(Highlights::default()));
+        highlights.borrow_mut().remember(self);
+        self.observe(highlights.clone());
+        fn inner(
+            terminal: &mut Tui,
+            vm: &mut VM,
+            view: &mut View,
+            session: &mut Session,
+            recorder: Option<&Recorder>,
+            highlights: &RefCell<Highlights>,
+            focus: Option<Focus>,
+        ) -> Result<()> {
+            let mut playing = false;
+            // Where the run of `run_all` stops besides halts and breakpoints:
+            // the program counter, and the stack pointer if given
+            let mut run_to: Option<(u16, Option<u8>)> = focus
+                .filter(|it| it.run && it.addr != vm.pc.0)
+                .map(|it| (it.addr, None));
+            // Whether the program runs, a batch of steps per frame, until it
+            // halts or reaches a breakpoint
+            let mut run_all = run_to.is_some();
+            if let Some(focus) = focus {
+                // Scroll to the program counter first, so that following it
+                // does not scroll away from the focus
+                view.fit(vm, terminal.size()?.width);
+                view.goto(vm, focus.addr, 1);
+            }
+            // The last thing searched for, to find the next match
+            let mut pattern: Option<Vec<u8>> = None;
+            // An error to show until the next key
+            let mut status: Option<String> = None;
+            // The line being typed for the input port
+            let mut typing: Option<String> = None;
+            // Whether the last step halted, until going back or loading another
+            // program undoes it
+            let mut halted = false;
             loop {
-                vm.print_state()?;
-                if let Event::Key(event) = event::read()? {
+                let mode = if typing.is_some() {
+                    Mode::Input
+                } else if session.keyboard {
+                    Mode::Keyboard
+                } else if playing {
+                    Mode::Play
+                } else if run_all {
+                    Mode::Run
+                } else {
+                    Mode::Debugger
+                };
+                view.fit(vm, terminal.size()?.width);
+                let mut sections = ui::debugger(
+                    vm,
+                    *view,
+                    &highlights.borrow(),
+                    Some(session),
+                    typing.as_deref(),
+                );
+                sections.push(ui::Section::fill());
+                sections.push(ui::hints(vm, mode, *view));
+                let shown = status.take().or_else(|| halted.then(|| HALTED.to_string()));
+                sections.push(ui::status(shown.as_deref(), view.theme));
+                let targets = redraw(terminal, sections, view.theme)?;
+                let command = if let Some(line) = &mut typing {
+                    let Event::Key(event) = event::read()? else {
+                        continue;
+                    };
                     match event.code {
+                        KeyCode::Esc => {
+                            typing = None;
+                            continue;
+                        }
                         KeyCode::Enter => {
-                            while vm.step()? {}
-                            break;
+                            let line = std::mem::take(line);
+                            typing = None;
+                            view.console_offset = 0;
+                            Command::Input(line)
                         }
-                        KeyCode::Char(c) => match c {
-                            's' => {
-                                if !vm.step()? {
-                                    break;
-                                }
+                        KeyCode::Backspace => {
+                            line.pop();
+                            continue;
+                        }
+                        KeyCode::Char(c) => {
+                            line.push(c);
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                } else if mode == Mode::Run {
+                    if event::poll(Duration::ZERO)? {
+                        if let Event::Key(event) = event::read()? {
+                            run_all = event.code != KeyCode::Esc;
+                            if !run_all {
+                                run_to = None;
+                            }
+                        }
+                        continue;
+                    }
+                    match run_to {
+                        Some((pc, sp)) => Command::RunTo {
+                            pc,
+                            sp,
+                            steps: RUN_BATCH,
+                        },
+                        None => Command::Steps(RUN_BATCH),
+                    }
+                } else if mode == Mode::Keyboard {
+                    // Let the program run freely, but stay responsive to keys
+                    if !event::poll(Duration::from_millis(20))? {
+                        Command::Steps(KEYBOARD_BATCH)
+                    } else if let Event::Key(event) = event::read()? {
+                        match event.code {
+                            KeyCode::Esc => Command::Debugger,
+                            KeyCode::Enter => Command::Key(b'\n'),
+                            KeyCode::Backspace => Command::Key(8),
+                            KeyCode::Tab => Command::Key(b'\t'),
+                            KeyCode::Char(c) if c.is_ascii() => Command::Key(c as u8),
+                            _ => continue,
+                        }
+                    } else {
+                        continue;
+                    }
+                } else if mode == Mode::Play {
+                    let delay = Duration::from_secs(1) / view.speed;
+                    if !event::poll(delay)? {
+                        Command::Step
+                    } else if let Event::Key(event) = event::read()? {
+                        match event.code {
+                            KeyCode::Esc | KeyCode::Char(' ') => playing = false,
+                            KeyCode::Char('+' | '=') => {
+                                view.speed = (view.speed + 1).min(MAX_SPEED)
                             }
-                            'q' => {
-                                break;
+                            KeyCode::Char('-') => view.speed = (view.speed - 1).max(1),
+                            _ => {}
+                        }
+                        continue;
+                    } else {
+                        continue;
+                    }
+                } else {
+                    // Wait for a key, or for the source to change
+                    let mut changed = false;
+                    while session.hot_reload && !changed && !event::poll(RELOAD_POLL)? {
+                        changed = session.source_changed();
+                    }
+                    if changed {
+                        status = Some(match session.reassemble(vm) {
+                            Ok(()) => {
+                                halted = false;
+                                "Reassembled the changed source".to_string()
                             }
-                            'r' => {
-                                vm.reset();
+                            Err(err) => format!("{err:#}"),
+                        });
+                        highlights.borrow_mut().clear();
+                        continue;
+                    }
+                    match event::read()? {
+                        Event::Key(event) => match event.code {
+                            KeyCode::Enter => {
+                                run_all = true;
+                                continue;
                             }
-                            'z' => {
-                                vm.undo();
+                            KeyCode::Home => Command::Rewind,
+                            KeyCode::End => Command::Forward,
+                            KeyCode::PageUp | KeyCode::PageDown if view.history => {
+                                let rows = HISTORY_ROWS as isize;
+                                let steps = if event.code == KeyCode::PageUp {
+                                    rows
+                                } else {
+                                    -rows
+                                };
+                                view.scroll_history(vm, steps);
+                                continue;
                             }
-                            _ => {}
+                            KeyCode::Char(c) if view.vim && "hjkl".contains(c) => {
+                                let delta = match c {
+                                    'h' => -1,
+                                    'l' => 1,
+                                    'k' => -(view.row_len as i32),
+                                    _ => view.row_len as i32,
+                                };
+                                view.move_cursor(vm, delta);
+                                continue;
+                            }
+                            KeyCode::Char(c) => match if view.vim { vim_alias(c) } else { c } {
+                                's' => Command::Step,
+                                'o' => Command::StepOver,
+                                'N' => {
+                                    let Some(line) = prompt("Steps to run: ")? else {
+                                        continue;
+                                    };
+                                    match line.trim().parse() {
+                                        Ok(n) => Command::Steps(n),
+                                        Err(_) => {
+                                            status = Some(format!("Not a count: {}", line.trim()));
+                                            continue;
+                                        }
+                                    }
+                                }
+                                'q' => break,
+                                ':' => {
+                                    let Some(line) = prompt(":")? else {
+                                        continue;
+                                    };
+                                    let colon = colon_command(
+                                        &line,
+                                        vm,
+                                        view,
+                                        session,
+                                        &highlights.borrow(),
+                                    );
+                                    match colon {
+                                        Ok(Colon::Perform(Command::Run)) => {
+                                            run_all = true;
+                                            continue;
+                                        }
+                                        Ok(Colon::Perform(command)) => command,
+                                        Ok(Colon::Quit) => break,
+                                        Ok(Colon::Done) => continue,
+                                        Ok(Colon::Edit) => {
+                                            match edit(terminal, vm, session, view.inline) {
+                                                Ok(()) => halted = false,
+                                                Err(err) => status = Some(format!("{err:#}")),
+                                            }
+                                            highlights.borrow_mut().clear();
+                                            continue;
+                                        }
+                                        Ok(Colon::Restore(file)) => {
+                                            match session.restore(vm, *file) {
+                                                Ok(()) => {
+                                                    halted = false;
+                                                    view.diff = None;
+                                                    highlights.borrow_mut().clear();
+                                                }
+                                                Err(err) => status = Some(format!("{err:#}")),
+                                            }
+                                            continue;
+                                        }
+                                        Err(message) => {
+                                            status = Some(message);
+                                            continue;
+                                        }
+                                    }
+                                }
+                                'r' => Command::Reset,
+                                'R' => Command::ResetHard,
+                                'z' => Command::Undo,
+                                'y' => Command::Redo,
+                                'p' => Command::SavePoint,
+                                'l' => Command::LoadPoint,
+                                'f' => {
+                                    view.floats = !view.floats;
+                                    continue;
+                                }
+                                'a' => {
+                                    view.follow = !view.follow;
+                                    view.followed = None;
+                                    continue;
+                                }
+                                'v' => {
+                                    if let Some(line) = prompt("Values of register: ")? {
+                                        view.register = parse_reg(&line);
+                                    }
+                                    continue;
+                                }
+                                'S' => {
+                                    view.signed = !view.signed;
+                                    continue;
+                                }
+                                'd' => {
+                                    view.base = view.base.next();
+                                    continue;
+                                }
+                                'c' => {
+                                    view.changes = !view.changes;
+                                    continue;
+                                }
+                                'H' => {
+                                    view.history = !view.history;
+                                    continue;
+                                }
+                                'k' if vm.devices.input.is_some() => Command::Keyboard,
+                                'I' if vm.devices.input.is_some() => {
+                                    typing = Some(String::new());
+                                    continue;
+                                }
+                                ' ' => {
+                                    playing = true;
+                                    continue;
+                                }
+                                'g' => {
+                                    let source = session.source.as_ref();
+                                    let message = if source.is_some() {
+                                        "Go to address or label: "
+                                    } else {
+                                        "Go to address: "
+                                    };
+                                    let addr = prompt(message)?
+                                        .and_then(|it| parse_location(&it, source))
+                                        .filter(|&it| (it as usize) < vm.memory.len());
+                                    if let Some(addr) = addr {
+                                        view.goto(vm, addr, 1);
+                                    }
+                                    continue;
+                                }
+                                '/' => {
+                                    let Some(query) =
+                                        prompt("Search for bytes, \"text\" or a label: ")?
+                                    else {
+                                        continue;
+                                    };
+                                    let label = session
+                                        .source
+                                        .as_ref()
+                                        .and_then(|it| it.map.label(query.trim()));
+                                    if let Some(addr) = label {
+                                        view.goto(vm, addr, 1);
+                                        continue;
+                                    }
+                                    pattern = parse_pattern(&query).or(pattern.take());
+                                    if let Some(pattern) = &pattern {
+                                        if let Some(addr) = search(vm, pattern, None) {
+                                            view.goto(vm, addr, pattern.len() as u16);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                'n' => {
+                                    if let Some(pattern) = &pattern {
+                                        if let Some(addr) = search(vm, pattern, view.cursor) {
+                                            view.goto(vm, addr, pattern.len() as u16);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                'w' => {
+                                    let map = session.source.as_ref().map(|it| &it.map);
+                                    let watch = prompt("Watch (e.g. R3, mem[0x80], mem[R1]): ")?
+                                        .and_then(|text| {
+                                            let expr = Expr::parse(&text, map)?;
+                                            Some(Watch::new(text.trim().to_string(), expr, vm))
+                                        });
+                                    session.watches.extend(watch);
+                                    continue;
+                                }
+                                'W' => {
+                                    session.watches.clear();
+                                    continue;
+                                }
+                                'b' => match breakpoint_prompt(session.source.as_ref())? {
+                                    Some(addr) => Command::Breakpoint(addr),
+                                    None => continue,
+                                },
+                                'e' => match register_prompt()? {
+                                    Some((reg, value)) => Command::SetReg(reg, value),
+                                    None => continue,
+                                },
+                                _ => continue,
+                            },
+                            _ => continue,
+                        },
+                        Event::Mouse(event) => match click(vm, view, &targets, event)? {
+                            Some(command) => command,
+                            None => continue,
                         },
-                        _ => {}
+                        _ => continue,
                     }
+                };
+                if halted
+                    && matches!(
+                        command,
+                        Command::Step
+                            | Command::StepOver
+                            | Command::Run
+                            | Command::Steps(_)
+                            | Command::RunTo { .. }
+                    )
+                {
+                    playing = false;
+                    run_all = false;
+                    run_to = None;
+                    continue;
+                }
+                // A call may take long to return, or never do, so step over it
+                // in batches like a run, which Esc interrupts
+                if let (Command::StepOver, Some((pc, sp))) = (&command, replay::over(vm)) {
+                    run_to = Some((pc, Some(sp)));
+                    run_all = true;
+                    continue;
+                }
+                if let Some(recorder) = recorder {
+                    recorder.borrow_mut().push(command.clone());
+                }
+                // An error, like a detected loop, stops a run as a breakpoint
+                // does, leaving the machine where it happened
+                let running = session.perform(vm, &command).unwrap_or_else(|err| {
+                    status = Some(format!("{err:#}"));
+                    playing = false;
+                    run_all = false;
+                    true
+                });
+                for watch in &mut session.watches {
+                    watch.update(vm);
+                }
+                if matches!(
+                    command,
+                    Command::Reset | Command::ResetHard | Command::LoadPoint
+                ) {
+                    highlights.borrow_mut().clear();
+                }
+                if !running {
+                    halted = true;
+                    playing = false;
+                    run_all = false;
+                } else if matches!(
+                    command,
+                    Command::Undo
+                        | Command::Rewind
+                        | Command::Reset
+                        | Command::ResetHard
+                        | Command::LoadPoint
+                ) {
+                    halted = false;
+                }
+                let reached = run_to.is_some_and(|(pc, sp)| {
+                    vm.pc.0 == pc && sp.is_none_or(|sp| vm.getr(SP).0 == sp)
+                });
+                if vm.breakpoints.contains(&vm.pc) || reached {
+                    playing = false;
+                    run_all = false;
+                }
+                if !run_all {
+                    run_to = None;
                 }
             }
             Ok(())
         }
-        let res = inner(self);
-        self.print_state()?;
-        execute!(stdout(), cursor::Show, LeaveAlternateScreen)?;
-        disable_raw_mode()?;
+        let res = inner(
+            &mut terminal,
+            self,
+            &mut view,
+            session,
+            recorder,
+            &highlights,
+            focus,
+        );
+        execute!(stdout(), DisableMouseCapture)?;
+        leave(&mut terminal, view.inline)?;
         res
     }
 }
+
+/// Prints the final state of every VM in `cluster`.
+pub fn print_cluster(cluster: &Cluster, mut view: View) -> Result<()> {
+    view.fit(&cluster.vms[0], ui::columns());
+    let highlights: Vec<_> = cluster.vms.iter().map(|_| Rc::default()).collect();
+    let mut sections = ui::cluster(cluster, 0, &highlights, view);
+    sections.push(ui::cluster_hints(false, view.theme));
+    ui::print(sections, view.theme)
+}
+
+/// Steps the VMs of `cluster` in lockstep, showing them side by side along
+/// with the memory of one of them.
+pub fn interactive_cluster(cluster: &mut Cluster, mut view: View) -> Result<()> {
+    let mut terminal = enter(view.inline)?;
+    let highlights: Vec<_> = cluster
+        .vms
+        .iter_mut()
+        .map(|vm| {
+            let highlights = Rc::new(RefCell::new(Highlights::default()));
+            vm.observe(highlights.clone());
+            highlights
+        })
+        .collect();
+    let mut focus = 0;
+    // Whether Enter started a run, which goes on in batches until Esc
+    let mut run_all = false;
+    // An error to show until the next key
+    let mut status: Option<String> = None;
+    let mut inner = || -> Result<()> {
+        loop {
+            view.fit(&cluster.vms[focus], terminal.size()?.width);
+            let mut sections = ui::cluster(cluster, focus, &highlights, view);
+            sections.push(ui::Section::fill());
+            sections.push(ui::cluster_hints(run_all, view.theme));
+            sections.push(ui::status(status.as_deref(), view.theme));
+            redraw(&mut terminal, sections, view.theme)?;
+            if run_all {
+                if event::poll(Duration::ZERO)? {
+                    if let Event::Key(event) = event::read()? {
+                        run_all = event.code != KeyCode::Esc;
+                    }
+                    continue;
+                }
+                // A round steps every core, so a batch is split among them
+                for _ in 0..(RUN_BATCH / cluster.vms.len()).max(1) {
+                    match cluster.step() {
+                        Ok(true) => {}
+                        Ok(false) => run_all = false,
+                        Err(err) => {
+                            status = Some(format!("{err:#}"));
+                            run_all = false;
+                        }
+                    }
+                    if !run_all {
+                        break;
+                    }
+                }
+                continue;
+            }
+            let Event::Key(event) = event::read()? else {
+                continue;
+            };
+            status = None;
+            match event.code {
+                KeyCode::Enter => run_all = true,
+                KeyCode::Tab => focus = (focus + 1) % cluster.vms.len(),
+                KeyCode::Char('s') => {
+                    if let Err(err) = cluster.step() {
+                        status = Some(format!("{err:#}"));
+                    }
+                }
+                KeyCode::Char('z') => cluster.undo(),
+                KeyCode::Char(c @ ('r' | 'R')) => {
+                    if c == 'r' {
+                        cluster.reset();
+                    } else {
+                        cluster.reset_hard();
+                    }
+                    for it in &highlights {
+                        it.borrow_mut().clear();
+                    }
+                }
+                KeyCode::Char('q') => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+    let res = inner();
+    leave(&mut terminal, view.inline)?;
+    res
+}
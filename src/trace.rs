@@ -0,0 +1,287 @@
+use crate::vm::{Action, Addr, Const, Instr, Reg, VM};
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// A register or memory cell changed by a step.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Change {
+    /// A register was written.
+    Reg {
+        /// Which one.
+        reg: Reg,
+        /// What it held before.
+        old: Const,
+        /// What it holds now.
+        new: Const,
+    },
+    /// A memory cell was written.
+    Mem {
+        /// Where.
+        addr: Addr,
+        /// What it held before.
+        old: Const,
+        /// What it holds now.
+        new: Const,
+    },
+    /// The flags changed.
+    Flags {
+        /// The flags before.
+        old: Const,
+        /// The flags now.
+        new: Const,
+    },
+}
+
+/// What a single step did.
+#[derive(Serialize)]
+pub struct TraceEntry {
+    /// How many steps ran before, counting from 0.
+    pub step: usize,
+    /// Where the instruction was.
+    pub pc: Addr,
+    /// The instruction.
+    #[serde(serialize_with = "debug_string")]
+    pub instr: Instr,
+    /// Everything it wrote, in order.
+    pub changes: Vec<Change>,
+    /// The program counter after the step.
+    pub next: Addr,
+    /// Cells written to that held already-executed instructions.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub code_writes: Vec<Addr>,
+    /// Whether the step was an edit made in the debugger rather than an
+    /// executed instruction.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub edit: bool,
+}
+
+/// The registers, flags and memory of a VM as they were before some of its
+/// steps, worked out by undoing them on top of the current state.
+struct Past<'a> {
+    vm: &'a VM,
+    regs: HashMap<u8, Const>,
+    mem: HashMap<Addr, Const>,
+    flags: Option<Const>,
+}
+
+impl<'a> Past<'a> {
+    fn new(vm: &'a VM) -> Self {
+        Self {
+            vm,
+            regs: HashMap::new(),
+            mem: HashMap::new(),
+            flags: None,
+        }
+    }
+
+    fn reg(&self, reg: Reg) -> Const {
+        self.regs.get(&reg.0).copied().unwrap_or(self.vm.getr(reg))
+    }
+
+    fn mem(&self, addr: Addr) -> Const {
+        self.mem.get(&addr).copied().unwrap_or(self.vm.load(addr))
+    }
+
+    fn flags(&self) -> Const {
+        self.flags.unwrap_or(Const(self.vm.flags))
+    }
+
+    /// Goes back to before the step `undo` is the inverse of.
+    fn undo(&mut self, undo: &Action) {
+        match undo {
+            &Action::SetReg(reg, old) => {
+                self.regs.insert(reg.0, old);
+            }
+            &Action::SetMem(addr, old) => {
+                self.mem.insert(addr, old);
+            }
+            &Action::SetFlags(old) => self.flags = Some(old),
+            Action::Batch(actions) => {
+                for action in actions {
+                    self.undo(action);
+                }
+            }
+            Action::None | Action::Jump(_) => {}
+        }
+    }
+}
+
+impl TraceEntry {
+    /// Describes the last step of `vm`, which executed `instr`.
+    pub fn last(vm: &VM, instr: Instr) -> Option<Self> {
+        let index = vm.history.len().checked_sub(1)?;
+        Some(Self::describe(&Past::new(vm), index, instr, vm.pc))
+    }
+
+    /// Describes the last `n` steps of `vm`, oldest first. Instructions are
+    /// disassembled from memory as it is now.
+    pub fn recent(vm: &VM, n: usize) -> Vec<Self> {
+        let mut past = Past::new(vm);
+        let mut next = vm.pc;
+        let mut entries = Vec::new();
+        for index in (vm.history.len().saturating_sub(n)..vm.history.len()).rev() {
+            let step = &vm.history[index];
+            entries.push(Self::describe(&past, index, vm.dis(step.pc), next));
+            past.undo(&step.undo);
+            next = step.pc;
+        }
+        entries.reverse();
+        entries
+    }
+
+    /// Describes step `index`, after which the machine was in state `past`
+    /// with the program counter at `next`.
+    fn describe(past: &Past, index: usize, instr: Instr, next: Addr) -> Self {
+        fn collect(past: &Past, action: &Action, changes: &mut Vec<Change>) {
+            match action {
+                &Action::SetReg(reg, old) => {
+                    changes.retain(|it| !matches!(it, Change::Reg { reg: r, .. } if r.0 == reg.0));
+                    changes.push(Change::Reg {
+                        reg,
+                        old,
+                        new: past.reg(reg),
+                    });
+                }
+                &Action::SetMem(addr, old) => {
+                    changes.retain(|it| !matches!(it, Change::Mem { addr: a, .. } if *a == addr));
+                    changes.push(Change::Mem {
+                        addr,
+                        old,
+                        new: past.mem(addr),
+                    });
+                }
+                &Action::SetFlags(old) => {
+                    changes.retain(|it| !matches!(it, Change::Flags { .. }));
+                    changes.push(Change::Flags {
+                        old,
+                        new: past.flags(),
+                    });
+                }
+                // Inverses are in reverse order, so the earliest old value of
+                // a location comes last
+                Action::Batch(actions) => {
+                    for action in actions {
+                        collect(past, action, changes);
+                    }
+                }
+                Action::None | Action::Jump(_) => {}
+            }
+        }
+        let vm = past.vm;
+        let step = &vm.history[index];
+        let mut changes = Vec::new();
+        collect(past, &step.undo, &mut changes);
+        changes.reverse();
+        Self {
+            step: index,
+            pc: step.pc,
+            instr,
+            changes,
+            next,
+            edit: step.edit,
+            code_writes: vm
+                .code_writes
+                .iter()
+                .filter(|&&(at, _)| at == index)
+                .map(|&(_, addr)| addr)
+                .collect(),
+        }
+    }
+}
+
+/// The values `reg` of `vm` held before each of its last `n` steps, and the
+/// value it holds now, oldest first.
+pub fn reg_history(vm: &VM, reg: Reg, n: usize) -> Vec<Const> {
+    let mut past = Past::new(vm);
+    let mut values = vec![past.reg(reg)];
+    for step in vm.history.iter().rev().take(n) {
+        past.undo(&step.undo);
+        values.push(past.reg(reg));
+    }
+    values.reverse();
+    values
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.edit {
+            write!(f, "[{}] edit", self.step)?;
+        } else {
+            write!(f, "[{}] {:?}: {:?}", self.step, self.pc, self.instr)?;
+        }
+        for change in &self.changes {
+            match change {
+                Change::Reg { reg, old, new } => write!(f, " | {reg:?} {old:?} -> {new:?}")?,
+                Change::Mem { addr, old, new } => write!(f, " | [{addr:?}] {old:?} -> {new:?}")?,
+                Change::Flags { old, new } => write!(f, " | Flags {old:?} -> {new:?}")?,
+            }
+        }
+        if !self.edit && self.next.0 != self.pc.0 + 2 {
+            write!(f, " | PC -> {:?}", self.next)?;
+        }
+        for addr in &self.code_writes {
+            write!(f, " | modifies code at {addr:?}")?;
+        }
+        Ok(())
+    }
+}
+
+fn debug_string<S: Serializer>(value: &Instr, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{value:?}"))
+}
+
+/// How a trace file is written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    /// One human-readable line per step
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl TraceFormat {
+    /// The format a trace file is meant to be in, by its extension.
+    pub fn guess(path: &Path) -> Self {
+        match path.extension().and_then(|it| it.to_str()) {
+            Some("json" | "jsonl") => TraceFormat::Json,
+            _ => TraceFormat::Text,
+        }
+    }
+}
+
+/// Writes a line per executed step to a file.
+pub struct Tracer {
+    out: BufWriter<File>,
+    format: TraceFormat,
+}
+
+impl Tracer {
+    /// Writes the trace to `path`, replacing the file if it exists.
+    pub fn create(path: &Path, format: TraceFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            format,
+        })
+    }
+
+    /// Writes out `entry`.
+    pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{entry}")?,
+            TraceFormat::Json => {
+                serde_json::to_writer(&mut self.out, entry)?;
+                writeln!(self.out)?;
+            }
+        }
+        Ok(())
+    }
+}
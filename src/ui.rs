@@ -0,0 +1,1488 @@
+use crate::{
+    asm::Source,
+    cluster::Cluster,
+    device::{Framebuffer, Screen},
+    float,
+    replay::Session,
+    term::{Highlights, Mode, View, HISTORY_ROWS},
+    trace::{reg_history, TraceEntry},
+    vm::{Addr, Const, Instr, Reg, Snapshot, CARRY, SP, VM, ZERO},
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use crossterm::{queue, style};
+use ratatui::{
+    buffer::{Buffer, Cell},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Widget, Wrap},
+};
+use std::{
+    cell::RefCell,
+    fs,
+    io::{stdout, BufWriter, Write},
+    ops::Range,
+    path::Path,
+    rc::Rc,
+};
+
+/// A set of colors for the UI.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Theme {
+    /// For terminals with a dark background
+    #[default]
+    Dark,
+    /// For terminals with a light background
+    Light,
+    /// Bold colors on black, whatever the terminal palette
+    HighContrast,
+    /// No colors, marking highlighted values with characters instead
+    Mono,
+}
+
+impl Theme {
+    pub fn palette(self) -> &'static Palette {
+        match self {
+            Theme::Dark => &DARK,
+            Theme::Light => &LIGHT,
+            Theme::HighContrast => &HIGH_CONTRAST,
+            Theme::Mono => &MONO,
+        }
+    }
+}
+
+/// The styles of everything the UI draws.
+pub struct Palette {
+    /// Under everything else.
+    base: Style,
+    /// Labels, borders and other text in the background.
+    dim: Style,
+    /// Register names, mnemonics and the current listing line.
+    name: Style,
+    operand: Style,
+    /// Warnings about the current instruction.
+    note: Style,
+    /// What the last step wrote.
+    written: Style,
+    /// What differs from the state the session started in.
+    changed: Style,
+    /// The cell the program counter points to.
+    pc: Style,
+    /// Cells written to after they were executed.
+    modified: Style,
+    /// Memory seen through a bank window or shared between cores.
+    mapped: Style,
+    breakpoint: Style,
+    key: Style,
+    hint: Style,
+    error: Style,
+    screen: Style,
+    framebuffer: Style,
+    selected: Style,
+    /// Mark highlighted registers and cells with a character next to them:
+    /// `>` for the program counter, `*` for what the last step wrote, `!` for
+    /// modified code and `+` for other changes.
+    markers: bool,
+}
+
+const fn fg(color: Color) -> Style {
+    Style::new().fg(color)
+}
+
+const fn on(fg: Color, bg: Color) -> Style {
+    Style::new().fg(fg).bg(bg)
+}
+
+static DARK: Palette = Palette {
+    base: Style::new(),
+    dim: fg(Color::DarkGray),
+    name: fg(Color::LightYellow),
+    operand: fg(Color::LightRed),
+    note: fg(Color::LightMagenta),
+    written: Style::new().bg(Color::Magenta),
+    changed: fg(Color::Magenta),
+    pc: Style::new().bg(Color::LightBlue),
+    modified: fg(Color::LightRed),
+    mapped: fg(Color::LightCyan),
+    breakpoint: fg(Color::LightRed),
+    key: on(Color::White, Color::Green),
+    hint: fg(Color::Green),
+    error: fg(Color::LightRed),
+    screen: fg(Color::LightGreen),
+    framebuffer: fg(Color::White),
+    selected: Style::new().add_modifier(Modifier::REVERSED),
+    markers: false,
+};
+
+static LIGHT: Palette = Palette {
+    base: Style::new(),
+    dim: fg(Color::DarkGray),
+    name: fg(Color::Blue),
+    operand: fg(Color::Red),
+    note: fg(Color::Magenta),
+    written: on(Color::Black, Color::LightYellow),
+    changed: fg(Color::Magenta),
+    pc: on(Color::Black, Color::LightCyan),
+    modified: fg(Color::Red),
+    mapped: fg(Color::Cyan),
+    breakpoint: fg(Color::Red),
+    key: on(Color::White, Color::Blue),
+    hint: fg(Color::Blue),
+    error: fg(Color::Red),
+    screen: fg(Color::Green),
+    framebuffer: fg(Color::Black),
+    selected: Style::new().add_modifier(Modifier::REVERSED),
+    markers: false,
+};
+
+static HIGH_CONTRAST: Palette = Palette {
+    base: on(Color::White, Color::Black),
+    dim: fg(Color::White),
+    name: fg(Color::LightYellow).add_modifier(Modifier::BOLD),
+    operand: fg(Color::LightCyan),
+    note: on(Color::Black, Color::LightYellow),
+    written: on(Color::Black, Color::LightMagenta),
+    changed: fg(Color::LightMagenta).add_modifier(Modifier::UNDERLINED),
+    pc: on(Color::Black, Color::White),
+    modified: fg(Color::LightRed).add_modifier(Modifier::BOLD),
+    mapped: fg(Color::LightCyan).add_modifier(Modifier::BOLD),
+    breakpoint: fg(Color::LightRed).add_modifier(Modifier::BOLD),
+    key: on(Color::Black, Color::White),
+    hint: fg(Color::White),
+    error: on(Color::White, Color::Red).add_modifier(Modifier::BOLD),
+    screen: fg(Color::LightGreen),
+    framebuffer: fg(Color::White),
+    selected: Style::new().add_modifier(Modifier::REVERSED.union(Modifier::UNDERLINED)),
+    markers: false,
+};
+
+static MONO: Palette = Palette {
+    base: Style::new(),
+    dim: Style::new(),
+    name: Style::new(),
+    operand: Style::new(),
+    note: Style::new(),
+    written: Style::new(),
+    changed: Style::new(),
+    pc: Style::new(),
+    modified: Style::new(),
+    mapped: Style::new(),
+    breakpoint: Style::new(),
+    key: Style::new().add_modifier(Modifier::REVERSED),
+    hint: Style::new(),
+    error: Style::new(),
+    screen: Style::new(),
+    framebuffer: Style::new(),
+    selected: Style::new().add_modifier(Modifier::REVERSED),
+    markers: true,
+};
+
+/// How register values and the selected cells are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Base {
+    #[default]
+    Hex,
+    Dec,
+    Bin,
+}
+
+impl Base {
+    pub fn next(self) -> Self {
+        match self {
+            Base::Hex => Base::Dec,
+            Base::Dec => Base::Bin,
+            Base::Bin => Base::Hex,
+        }
+    }
+
+    /// How many columns `digits` takes.
+    fn width(self) -> usize {
+        match self {
+            Base::Hex => 2,
+            Base::Dec => 3,
+            Base::Bin => 8,
+        }
+    }
+
+    /// `value` padded to `width`.
+    fn digits(self, value: u8) -> String {
+        match self {
+            Base::Hex => format!("{value:02X}"),
+            Base::Dec => format!("{value:3}"),
+            Base::Bin => format!("{value:08b}"),
+        }
+    }
+
+    /// `value` with a prefix saying which base it is in.
+    fn format(self, value: u8) -> String {
+        match self {
+            Base::Hex => format!("0x{value:02X}"),
+            Base::Dec => value.to_string(),
+            Base::Bin => format!("0b{value:08b}"),
+        }
+    }
+}
+
+/// Something on the screen that reacts to the mouse.
+#[derive(Clone, Copy)]
+pub enum Target {
+    /// The memory pane, which scrolls.
+    Memory,
+    /// The memory cell at this address.
+    Cell(u16),
+    /// The history pane, which scrolls.
+    History,
+    /// The console pane, which scrolls.
+    Console,
+    /// The margin of a listing line, where clicks toggle a breakpoint at
+    /// this address.
+    Gutter(u16),
+}
+
+/// Where every target ended up on the screen, later ones on top.
+pub type Targets = Vec<(Rect, Target)>;
+
+/// A box of text on the screen, bordered if it has a title.
+#[derive(Clone)]
+pub struct Pane {
+    /// The width the pane takes, or `None` for whatever is left.
+    width: Option<u16>,
+    title: Option<String>,
+    lines: Vec<Line<'static>>,
+    /// Wraps long lines instead of cutting them off.
+    wrap: bool,
+    /// Relative to the top left of the text.
+    targets: Targets,
+}
+
+impl Pane {
+    pub fn new(lines: Vec<Line<'static>>) -> Self {
+        Self {
+            width: None,
+            title: None,
+            lines,
+            wrap: false,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn titled(title: impl Into<String>, lines: Vec<Line<'static>>) -> Self {
+        Self {
+            title: Some(title.into()),
+            ..Self::new(lines)
+        }
+    }
+
+    /// Makes the pane just wide enough for lines of `width` characters.
+    pub fn width(mut self, width: u16) -> Self {
+        self.width = Some(width + if self.title.is_some() { 2 } else { 0 });
+        self
+    }
+
+    pub fn target(mut self, area: Rect, target: Target) -> Self {
+        self.targets.push((area, target));
+        self
+    }
+
+    pub fn wrapped(mut self) -> Self {
+        self.wrap = true;
+        self
+    }
+
+    /// How many rows the pane takes when it is `width` columns wide.
+    fn height(&self, width: u16) -> u16 {
+        let border = if self.title.is_some() { 2 } else { 0 };
+        let lines = if self.wrap {
+            Paragraph::new(self.lines.clone())
+                .wrap(Wrap { trim: false })
+                .line_count(width.saturating_sub(border)) as u16
+        } else {
+            self.lines.len() as u16
+        };
+        lines + border
+    }
+
+    /// Draws the pane, keeping its last lines if there is not enough room.
+    fn render(mut self, area: Rect, buf: &mut Buffer, p: &Palette, targets: &mut Targets) {
+        let inner = match self.title {
+            Some(title) => {
+                let block = Block::bordered()
+                    .border_style(p.dim)
+                    .title(Span::styled(title, p.dim));
+                let inner = block.inner(area);
+                block.render(area, buf);
+                inner
+            }
+            None => area,
+        };
+        let skip = if self.wrap {
+            0
+        } else {
+            self.lines.len().saturating_sub(inner.height as usize) as u16
+        };
+        for (rect, target) in self.targets {
+            let Some(y) = rect.y.checked_sub(skip) else {
+                continue;
+            };
+            let rect = Rect {
+                x: rect.x.saturating_add(inner.x),
+                y: y.saturating_add(inner.y),
+                ..rect
+            };
+            targets.push((rect.intersection(inner), target));
+        }
+        if self.wrap {
+            Paragraph::new(self.lines)
+                .wrap(Wrap { trim: false })
+                .render(inner, buf);
+        } else {
+            Paragraph::new(self.lines.split_off(skip as usize)).render(inner, buf);
+        }
+    }
+}
+
+/// A band of panes side by side, stacked top to bottom with the others.
+#[derive(Clone)]
+pub struct Section {
+    panes: Vec<Pane>,
+    /// Takes up the rows the other sections leave, pinning the sections after
+    /// it to the bottom of the screen.
+    fill: bool,
+}
+
+impl Section {
+    pub fn new(panes: Vec<Pane>) -> Self {
+        Self { panes, fill: false }
+    }
+
+    pub fn line(line: Line<'static>) -> Self {
+        Self::new(vec![Pane::new(vec![line])])
+    }
+
+    /// An empty filler.
+    pub fn fill() -> Self {
+        Self::new(Vec::new()).filling()
+    }
+
+    /// Makes the section a filler.
+    pub fn filling(mut self) -> Self {
+        self.fill = true;
+        self
+    }
+
+    fn areas(&self, area: Rect) -> Rc<[Rect]> {
+        let constraints = self.panes.iter().map(|it| match it.width {
+            Some(width) => Constraint::Length(width),
+            None => Constraint::Min(0),
+        });
+        Layout::horizontal(constraints).split(area)
+    }
+
+    /// How many rows the section takes when it is `width` columns wide.
+    fn height(&self, width: u16) -> u16 {
+        let areas = self.areas(Rect::new(0, 0, width, 0));
+        self.panes
+            .iter()
+            .zip(areas.iter())
+            .map(|(pane, area)| pane.height(area.width))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn render(self, area: Rect, buf: &mut Buffer, p: &Palette, targets: &mut Targets) {
+        let areas = self.areas(area);
+        for (pane, &area) in self.panes.into_iter().zip(areas.iter()) {
+            pane.render(area, buf, p, targets);
+        }
+    }
+}
+
+/// Stacks `sections` in `area`, returning where their targets are. The first
+/// filler takes up the rows the others leave, and the sections after it are
+/// pinned to the bottom. If they do not fit, the ones at the top are cut off
+/// at the bottom.
+pub fn render(mut sections: Vec<Section>, theme: Theme, area: Rect, buf: &mut Buffer) -> Targets {
+    let p = theme.palette();
+    buf.set_style(area, p.base);
+    let mut targets = Vec::new();
+    let (bottom, filler) = match sections.iter().position(|it| it.fill) {
+        Some(index) => {
+            let bottom = sections.split_off(index + 1);
+            (bottom, sections.pop())
+        }
+        None => (Vec::new(), None),
+    };
+    let mut end = area.bottom();
+    for section in bottom.into_iter().rev() {
+        let height = section.height(area.width).min(end - area.y);
+        end -= height;
+        section.render(
+            Rect {
+                y: end,
+                height,
+                ..area
+            },
+            buf,
+            p,
+            &mut targets,
+        );
+    }
+    let mut y = area.y;
+    for section in sections {
+        let height = section.height(area.width).min(end - y);
+        section.render(Rect { y, height, ..area }, buf, p, &mut targets);
+        y += height;
+    }
+    if let Some(filler) = filler {
+        let height = end - y;
+        filler.render(Rect { y, height, ..area }, buf, p, &mut targets);
+    }
+    targets
+}
+
+/// How wide printed output is: the width of the terminal, if there is one.
+pub fn columns() -> u16 {
+    crossterm::terminal::size().map_or(100, |(cols, _)| cols)
+}
+
+/// Renders `sections` at their natural height, `width` columns wide.
+fn draw(sections: Vec<Section>, theme: Theme, width: u16) -> Buffer {
+    let height = sections.iter().map(|it| it.height(width)).sum();
+    let area = Rect::new(0, 0, width, height);
+    let mut buf = Buffer::empty(area);
+    render(sections, theme, area, &mut buf);
+    buf
+}
+
+/// The cells of every row of `buf`, without trailing blanks.
+fn rows(buf: &Buffer) -> impl Iterator<Item = Vec<&Cell>> {
+    let area = buf.area;
+    (area.top()..area.bottom()).map(move |y| {
+        let mut cells: Vec<_> = (area.left()..area.right()).map(|x| &buf[(x, y)]).collect();
+        while cells
+            .last()
+            .is_some_and(|it| it.symbol() == " " && it.bg == Color::Reset)
+        {
+            cells.pop();
+        }
+        cells
+    })
+}
+
+/// Prints `sections` as lines of text, as wide as the terminal.
+pub fn print(sections: Vec<Section>, theme: Theme) -> Result<()> {
+    let buf = draw(sections, theme, columns());
+    let mut out = BufWriter::new(stdout().lock());
+    for cells in rows(&buf) {
+        let mut last = None;
+        for cell in cells {
+            let style = (cell.fg, cell.bg, cell.modifier);
+            if last != Some(style) {
+                queue!(
+                    out,
+                    style::SetAttribute(style::Attribute::Reset),
+                    style::SetForegroundColor(cell.fg.into()),
+                    style::SetBackgroundColor(cell.bg.into()),
+                )?;
+                for (modifier, attribute) in [
+                    (Modifier::BOLD, style::Attribute::Bold),
+                    (Modifier::UNDERLINED, style::Attribute::Underlined),
+                    (Modifier::REVERSED, style::Attribute::Reverse),
+                ] {
+                    if cell.modifier.contains(modifier) {
+                        queue!(out, style::SetAttribute(attribute))?;
+                    }
+                }
+                last = Some(style);
+            }
+            queue!(out, style::Print(cell.symbol()))?;
+        }
+        queue!(
+            out,
+            style::SetAttribute(style::Attribute::Reset),
+            style::ResetColor,
+            style::Print('\n'),
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The final state of a run as plain text without colors or cursor
+/// movement, for logs and pipes: the program counter, the registers, what
+/// was printed and all of memory, 16 bytes per row.
+pub fn report(vm: &VM, halted: bool) -> String {
+    let width = width(vm);
+    let steps = vm.history.iter().filter(|it| !it.edit).count();
+    let status = if halted { "halted" } else { "stopped" };
+    let mut out = format!("PC 0x{:0width$X}, {steps} steps, {status}\n", vm.pc.0);
+    if let Some(seed) = vm.seed() {
+        out += &format!("Seed {seed}\n");
+    }
+    for (i, regs) in vm.regs.chunks(8).enumerate() {
+        let regs: Vec<_> = regs
+            .iter()
+            .enumerate()
+            .map(|(j, value)| format!("R{:X} {value:02X}", i * 8 + j))
+            .collect();
+        out += &regs.join("  ");
+        out.push('\n');
+    }
+    if vm.isa.flags {
+        let flag = |mask, name| if vm.flags & mask != 0 { name } else { "-" };
+        out += &format!("Flags {}{}\n", flag(ZERO, "Z"), flag(CARRY, "C"));
+    }
+    if let Some(output) = vm
+        .devices
+        .output
+        .as_ref()
+        .filter(|it| !it.buffer.is_empty())
+    {
+        out += &format!("Output {:?}\n", output.text());
+    }
+    for (i, row) in vm.memory.bytes().chunks(16).enumerate() {
+        let bytes: Vec<_> = row.iter().map(|it| format!("{it:02X}")).collect();
+        out += &format!("0x{:0width$X}: {}\n", i * 16, bytes.join(" "));
+    }
+    out
+}
+
+/// Writes `sections`, `width` columns wide, to `path`: as an HTML page with
+/// the colors of `theme` if it ends in `.html`, and as plain text otherwise.
+pub fn export(path: &Path, sections: Vec<Section>, theme: Theme, width: u16) -> Result<()> {
+    let buf = draw(sections, theme, width);
+    let html = path
+        .extension()
+        .is_some_and(|it| it.eq_ignore_ascii_case("html") || it.eq_ignore_ascii_case("htm"));
+    let mut out = String::new();
+    if html {
+        let (fg, bg) = match theme {
+            Theme::Light | Theme::Mono => ("#000000", "#ffffff"),
+            Theme::Dark | Theme::HighContrast => ("#e5e5e5", "#000000"),
+        };
+        out += &format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>v8-cpu</title></head>\n\
+             <body style=\"background: {bg}\">\n<pre style=\"color: {fg}; background: {bg}\">\n"
+        );
+        for cells in rows(&buf) {
+            let mut last = None;
+            for cell in cells {
+                let style = (cell.fg, cell.bg, cell.modifier);
+                if last != Some(style) {
+                    if last.is_some() {
+                        out += "</span>";
+                    }
+                    out += &format!("<span style=\"{}\">", css(cell, fg, bg));
+                    last = Some(style);
+                }
+                out += &match cell.symbol() {
+                    "<" => "&lt;".to_string(),
+                    ">" => "&gt;".to_string(),
+                    "&" => "&amp;".to_string(),
+                    symbol => symbol.to_string(),
+                };
+            }
+            if last.is_some() {
+                out += "</span>";
+            }
+            out.push('\n');
+        }
+        out += "</pre>\n</body>\n</html>\n";
+    } else {
+        for cells in rows(&buf) {
+            out.extend(cells.iter().map(|it| it.symbol()));
+            out.push('\n');
+        }
+    }
+    fs::write(path, out).context(format!("Failed to write to {}", path.display()))
+}
+
+/// The CSS declarations giving text the style of `cell`, where the default
+/// colors are `fg` and `bg`.
+fn css(cell: &Cell, fg: &str, bg: &str) -> String {
+    let mut colors = (
+        hex(cell.fg).unwrap_or(fg.to_string()),
+        hex(cell.bg).unwrap_or(bg.to_string()),
+    );
+    if cell.modifier.contains(Modifier::REVERSED) {
+        colors = (colors.1, colors.0);
+    }
+    let mut css = format!("color: {}; background: {}", colors.0, colors.1);
+    if cell.modifier.contains(Modifier::BOLD) {
+        css += "; font-weight: bold";
+    }
+    if cell.modifier.contains(Modifier::UNDERLINED) {
+        css += "; text-decoration: underline";
+    }
+    css
+}
+
+/// `color` as a CSS hex color, with the colors of xterm for the named ones,
+/// or `None` for the terminal default.
+fn hex(color: Color) -> Option<String> {
+    let rgb = match color {
+        Color::Black => (0x00, 0x00, 0x00),
+        Color::Red => (0xcd, 0x00, 0x00),
+        Color::Green => (0x00, 0xcd, 0x00),
+        Color::Yellow => (0xcd, 0xcd, 0x00),
+        Color::Blue => (0x00, 0x00, 0xee),
+        Color::Magenta => (0xcd, 0x00, 0xcd),
+        Color::Cyan => (0x00, 0xcd, 0xcd),
+        Color::Gray => (0xe5, 0xe5, 0xe5),
+        Color::DarkGray => (0x7f, 0x7f, 0x7f),
+        Color::LightRed => (0xff, 0x00, 0x00),
+        Color::LightGreen => (0x00, 0xff, 0x00),
+        Color::LightYellow => (0xff, 0xff, 0x00),
+        Color::LightBlue => (0x5c, 0x5c, 0xff),
+        Color::LightMagenta => (0xff, 0x00, 0xff),
+        Color::LightCyan => (0x00, 0xff, 0xff),
+        Color::White => (0xff, 0xff, 0xff),
+        Color::Rgb(r, g, b) => (r, g, b),
+        Color::Reset | Color::Indexed(_) => return None,
+    };
+    Some(format!("#{:02x}{:02x}{:02x}", rgb.0, rgb.1, rgb.2))
+}
+
+/// The header row of the register panes for the registers in `regs`, after
+/// `indent` columns. The flags come after the last register.
+fn register_names(vm: &VM, regs: Range<u8>, indent: usize, view: View) -> Line<'static> {
+    let width = register_width(view);
+    let mut names = " ".repeat(indent);
+    for i in regs.clone() {
+        names += &format!("{:<width$} ", format!("R{i:X}"));
+    }
+    if vm.isa.flags && regs.end == 16 {
+        names += " Z C";
+    }
+    Line::styled(names, view.theme.palette().name)
+}
+
+/// How many columns each register takes, besides the space after it.
+fn register_width(view: View) -> usize {
+    let width = view.base.width();
+    if view.signed {
+        // Room for -128
+        width.max(4)
+    } else {
+        width
+    }
+}
+
+/// The registers shown in each row: all of them, or fewer if they would not
+/// fit in the compact layout or with wider values.
+fn register_rows(view: View) -> Vec<Range<u8>> {
+    let budget = if view.row_len < 16 { 40 } else { 72 };
+    let mut len = 16;
+    while len > 1 && len * (register_width(view) + 1) > budget {
+        len /= 2;
+    }
+    (0..16)
+        .step_by(len)
+        .map(|start| start..start + len as u8)
+        .collect()
+}
+
+/// The values of the registers in `regs` as signed numbers.
+fn register_signed(vm: &VM, regs: Range<u8>, view: View) -> Line<'static> {
+    let width = register_width(view);
+    let values: String = regs
+        .map(|i| format!("{:<width$} ", vm.getr(Reg(i)).0 as i8))
+        .collect();
+    Line::styled(values, view.theme.palette().dim)
+}
+
+/// The values of the registers in `regs`, and then the flags, of `vm`.
+/// Registers that differ from the start are marked if `view` asks for it, as
+/// are those that differ from `diff`.
+fn register_values(
+    vm: &VM,
+    regs: Range<u8>,
+    highlights: &Highlights,
+    diff: Option<&Snapshot>,
+    view: View,
+) -> Vec<Span<'static>> {
+    let p = view.theme.palette();
+    let mut spans = Vec::new();
+    for i in regs.clone() {
+        let value = format!(
+            "{:<width$}",
+            view.base.digits(vm.getr(Reg(i)).0),
+            width = register_width(view)
+        );
+        let (style, marker) = if highlights.reg(Reg(i)) {
+            (p.written, "*")
+        } else if view.changes && highlights.reg_changed(vm, Reg(i))
+            || diff.is_some_and(|it| it.regs[i as usize] != vm.regs[i as usize])
+        {
+            (p.changed, "+")
+        } else {
+            (Style::new(), " ")
+        };
+        spans.push(Span::styled(value, style));
+        spans.push(Span::raw(if p.markers { marker } else { " " }));
+    }
+    if vm.isa.flags && regs.end == 16 {
+        for flag in [ZERO, CARRY] {
+            spans.push(Span::raw(format!(" {}", (vm.flags & flag != 0) as u8)));
+        }
+    }
+    spans
+}
+
+/// How many of the latest values of a register `register_history` shows.
+const REGISTER_HISTORY: usize = 8;
+
+/// The latest values `reg` was set to, with the steps that set them.
+fn register_history(vm: &VM, reg: Reg, view: View) -> Line<'static> {
+    let p = view.theme.palette();
+    let mut changes: Vec<(usize, Const)> = Vec::new();
+    for (step, value) in reg_history(vm, reg, usize::MAX).into_iter().enumerate() {
+        if changes.last().is_none_or(|&(_, last)| last.0 != value.0) {
+            changes.push((step, value));
+        }
+    }
+    let skip = changes.len().saturating_sub(REGISTER_HISTORY);
+    let mut spans = vec![Span::styled(format!("Values of {reg:?}:"), p.dim)];
+    if skip > 0 {
+        spans.push(Span::styled(" ...", p.dim));
+    }
+    for (i, &(step, value)) in changes.iter().enumerate().skip(skip) {
+        let latest = i + 1 == changes.len();
+        spans.push(Span::raw("  "));
+        spans.push(Span::styled(
+            view.base.format(value.0),
+            if latest { p.name } else { Style::new() },
+        ));
+        spans.push(Span::styled(format!(" @{step}"), p.dim));
+    }
+    Line::from(spans)
+}
+
+/// The instruction at the program counter after `label`, with notes on how
+/// execution got there.
+fn current(vm: &VM, label: String, p: &Palette) -> Vec<Span<'static>> {
+    let instr = vm.dis(vm.pc);
+    let s = format!("{instr:?}");
+    let index = s.find('(').unwrap_or(s.len());
+    let mut spans = vec![
+        Span::styled(label, p.dim),
+        Span::styled(s[..index].to_string(), p.name),
+        Span::styled(s[index..].to_string(), p.operand),
+    ];
+    if let Some(operands) = resolved(vm, instr) {
+        spans.push(Span::styled(format!("  {operands}"), p.dim));
+    }
+    if vm.ran_off.is_some() && vm.pc.0 as usize >= vm.image.len() {
+        spans.push(Span::styled(
+            "  (past the end of the program, missing halt?)",
+            p.note,
+        ));
+    }
+    let step = vm.history.len().wrapping_sub(1);
+    let modified: Vec<_> = vm
+        .code_writes
+        .iter()
+        .filter(|&&(at, _)| at == step)
+        .map(|(_, addr)| format!("{addr:?}"))
+        .collect();
+    if !modified.is_empty() {
+        spans.push(Span::styled(
+            format!("  (modified code at {})", modified.join(", ")),
+            p.note,
+        ));
+    }
+    spans
+}
+
+/// The values the operands of `instr` have as it is about to execute, and
+/// whether it jumps, if it has any.
+fn resolved(vm: &VM, instr: Instr) -> Option<String> {
+    use Instr::*;
+    let reg = |reg: Reg| format!("{reg:?}=0x{:02X}", vm.getr(reg).0);
+    let mem = |addr: Const| {
+        let addr = vm.addr(addr);
+        format!("mem[{addr:?}]=0x{:02X}", vm.memory.load(addr.0))
+    };
+    let jumps = |cond: bool| if cond { "jumps" } else { "does not jump" }.to_string();
+    let top = || mem(vm.getr(SP));
+    let parts = match instr {
+        LoadFromMemory(_, addr) => vec![mem(addr)],
+        StoreToMemory(r, _) | Move(_, r) | Rotate(r, _) | Push(r) => vec![reg(r)],
+        AddInt(_, a, b) | AddFloat(_, a, b) | Or(_, a, b) | And(_, a, b) | Xor(_, a, b) => {
+            vec![reg(a), reg(b)]
+        }
+        JumpIfEqual(r, _) if r.0 == 0 => vec![jumps(true)],
+        JumpIfEqual(r, _) => vec![
+            reg(r),
+            reg(Reg(0)),
+            jumps(vm.getr(r).0 == vm.getr(Reg(0)).0),
+        ],
+        JumpIfLess(r, _) => vec![reg(r), reg(Reg(0)), jumps(vm.getr(r).0 < vm.getr(Reg(0)).0)],
+        LoadFromPointer(_, ptr) => vec![reg(ptr), mem(vm.getr(ptr))],
+        StoreToPointer(r, ptr) => vec![reg(r), reg(ptr)],
+        Pop(_) => vec![top()],
+        Call(_) => vec![reg(SP)],
+        Ret => vec![format!("returns to the address in {}", top())],
+        JumpIfZero(_) => vec![jumps(vm.flags & ZERO != 0)],
+        JumpIfNotZero(_) => vec![jumps(vm.flags & ZERO == 0)],
+        JumpIfCarry(_) => vec![jumps(vm.flags & CARRY != 0)],
+        JumpIfNoCarry(_) => vec![jumps(vm.flags & CARRY == 0)],
+        None | LoadWithConstant(..) | Halt | Custom(..) => return Option::None,
+    };
+    Some(parts.join(", "))
+}
+
+/// How many hex digits addresses take.
+fn width(vm: &VM) -> usize {
+    if vm.memory.len() > 256 {
+        3
+    } else {
+        2
+    }
+}
+
+/// How many instructions the listing pane shows before the current one.
+const LISTING_BEFORE: u16 = 2;
+
+/// A line of the listing pane.
+struct ListingEntry {
+    /// Where the line is in memory.
+    addr: Option<u16>,
+    text: String,
+    current: bool,
+    breakpoint: bool,
+}
+
+/// `len` instructions around the program counter, assuming they are laid out
+/// back to back.
+fn disassembly(vm: &VM, len: u16) -> Vec<ListingEntry> {
+    let width = width(vm);
+    let size = vm.memory.len() as u32;
+    let before = (vm.pc.0 / 2).min(LISTING_BEFORE);
+    let start = vm.pc.0 - before * 2;
+    (0..len)
+        .map(|i| start as u32 + i as u32 * 2)
+        .take_while(|&addr| addr + 1 < size)
+        .map(|addr| {
+            let addr = Addr(addr as u16);
+            ListingEntry {
+                addr: Some(addr.0),
+                text: format!("0x{:0width$X}: {:?}", addr.0, vm.dis(addr)),
+                current: addr == vm.pc,
+                breakpoint: vm.breakpoints.contains(&addr),
+            }
+        })
+        .collect()
+}
+
+/// `len` source lines around the one the program counter is in, or the
+/// disassembly if it is outside the program.
+fn source_listing(vm: &VM, source: &Source, len: u16) -> Vec<ListingEntry> {
+    let Some(line) = source.map.line(vm.pc.0) else {
+        return disassembly(vm, len);
+    };
+    let start = line.saturating_sub(LISTING_BEFORE as usize).max(1);
+    let end = (start + len as usize).min(source.lines.len() + 1);
+    (start..end)
+        .map(|i| {
+            let addr = source.map.addr(i);
+            ListingEntry {
+                addr,
+                text: format!("{i:>4} | {}", source.lines[i - 1].trim_end()),
+                current: i == line,
+                breakpoint: addr.is_some_and(|addr| vm.breakpoints.contains(&Addr(addr))),
+            }
+        })
+        .collect()
+}
+
+/// The memory pane titled `title`, with the addresses in `window` marked,
+/// and the listing pane next to it.
+fn memory(
+    vm: &VM,
+    view: View,
+    highlights: &Highlights,
+    diff: Option<&Snapshot>,
+    window: Range<usize>,
+    source: Option<&Source>,
+    title: String,
+) -> Section {
+    let p = view.theme.palette();
+    let width = width(vm);
+    let (rows, len) = (view.rows(vm), view.row_len);
+    // The address label, then a space and two digits per cell
+    let label = width as u16 + 3;
+    let mut cells = Pane::titled(title, Vec::new())
+        .width(label + len * 3)
+        .target(Rect::new(0, 0, label + len * 3, rows), Target::Memory);
+    for row in 0..rows {
+        for col in 0..len {
+            let rect = Rect::new(label + col * 3 + 1, row, 2, 1);
+            cells = cells.target(rect, Target::Cell(view.origin + row * len + col));
+        }
+    }
+    cells.lines = (0..rows)
+        .map(|row| {
+            let start = view.origin + row * len;
+            let mut spans = vec![Span::styled(
+                format!("0x{start:0width$X}:"),
+                if window.contains(&(start as usize)) {
+                    p.mapped
+                } else {
+                    p.dim
+                },
+            )];
+            for i in start..start + len {
+                let mut style = Style::new();
+                let mut marker = " ";
+                if highlights.mem(Addr(i)) {
+                    style = style.patch(p.written);
+                    marker = "*";
+                } else if view.changes && highlights.mem_changed(vm, Addr(i))
+                    || diff.is_some_and(|it| it.memory.get(i as usize) != Some(&vm.memory.load(i)))
+                {
+                    style = style.patch(p.changed);
+                    marker = "+";
+                }
+                if vm.code_writes.iter().any(|&(_, addr)| addr == Addr(i)) {
+                    style = style.patch(p.modified);
+                    marker = "!";
+                }
+                if i == vm.pc.0 {
+                    style = style.patch(p.pc);
+                    marker = ">";
+                }
+                if view.selected(i) {
+                    style = style.patch(p.selected);
+                }
+                spans.push(Span::raw(if p.markers { marker } else { " " }));
+                spans.push(Span::styled(format!("{:02X}", vm.memory.load(i)), style));
+            }
+            Line::from(spans)
+        })
+        .collect();
+    let (name, listing) = match source {
+        Some(source) => ("Source", source_listing(vm, source, rows)),
+        None => ("Disassembly", disassembly(vm, rows)),
+    };
+    let mut code = Pane::titled(name, Vec::new());
+    for (row, entry) in listing.iter().enumerate() {
+        if let Some(addr) = entry.addr {
+            code = code.target(Rect::new(0, row as u16, 2, 1), Target::Gutter(addr));
+        }
+    }
+    code.lines = listing
+        .into_iter()
+        .map(|entry| {
+            let style = if entry.current { p.name } else { p.dim };
+            Line::from(vec![
+                Span::styled(if entry.current { ">" } else { " " }, style),
+                Span::styled(if entry.breakpoint { "*" } else { " " }, p.breakpoint),
+                Span::styled(format!(" {}", entry.text), style),
+            ])
+        })
+        .collect();
+    let mut panes = vec![cells];
+    if vm.isa.ext {
+        panes.push(stack(vm, p));
+    }
+    panes.push(code);
+    Section::new(panes)
+}
+
+/// The pane showing the stack of the extended instruction set, from its
+/// base, where the stack pointer was before the first push, down to the stack
+/// pointer.
+fn stack(vm: &VM, p: &Palette) -> Pane {
+    // A push wraps a stack pointer of 0 around to the end of the page
+    let top = |sp: Const| if sp.0 == 0 { 0x100 } else { sp.0 as u16 };
+    let sp = top(vm.getr(SP));
+    let base = reg_history(vm, SP, usize::MAX)
+        .windows(2)
+        .find(|it| it[1].0 == it[0].0.wrapping_sub(1))
+        .map_or(sp, |it| top(it[0]))
+        .max(sp);
+    let width = width(vm);
+    let mut lines: Vec<_> = (sp..base)
+        .rev()
+        .map(|offset| {
+            let addr = vm.addr(Const(offset as u8));
+            let mut spans = vec![
+                Span::styled(format!("0x{:0width$X}: ", addr.0), p.dim),
+                Span::raw(format!("{:02X}", vm.memory.load(addr.0))),
+            ];
+            if offset == sp {
+                spans[1].style = p.pc;
+                spans.push(Span::styled(" < SP", p.name));
+            }
+            Line::from(spans)
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push(Line::styled(format!("SP = 0x{:02X}", vm.getr(SP).0), p.dim));
+    }
+    // The address label, the value and the marker
+    Pane::titled("Stack", lines).width(width as u16 + 12)
+}
+
+/// The title of the memory pane, with the values of the selected cells.
+fn memory_title(vm: &VM, view: View) -> String {
+    let Some(cursor) = view.cursor else {
+        return "Memory".to_string();
+    };
+    let values: Vec<_> = (cursor
+        ..cursor
+            .saturating_add(view.selection)
+            .min(vm.memory.len() as u16))
+        .take(MAX_SELECTED)
+        .map(|addr| view.base.format(vm.memory.load(addr)))
+        .collect();
+    let more = if view.selection as usize > MAX_SELECTED {
+        " ..."
+    } else {
+        ""
+    };
+    format!("Memory, 0x{cursor:02X} = {}{more}", values.join(" "))
+}
+
+/// How many of the selected cells the memory pane title shows.
+const MAX_SELECTED: usize = 8;
+
+/// The pane showing the cell at `addr` in every base, the labels at it and
+/// the instruction starting there.
+fn inspector(vm: &VM, addr: u16, source: Option<&Source>, p: &Palette) -> Section {
+    let width = width(vm);
+    let value = vm.memory.load(addr);
+    let ascii = match value {
+        0x20..=0x7E => format!("'{}'", value as char),
+        _ => "not printable".to_string(),
+    };
+    let mut lines = vec![Line::from(vec![
+        Span::styled("Value: ", p.dim),
+        Span::raw(format!(
+            "0x{value:02X}  {value} (signed {})  0b{value:08b}  {ascii}",
+            value as i8
+        )),
+    ])];
+    let labels = source.map_or(Vec::new(), |it| it.map.labels_at(addr));
+    if !labels.is_empty() {
+        lines.push(Line::from(vec![
+            Span::styled("Labels: ", p.dim),
+            Span::styled(labels.join(", "), p.name),
+        ]));
+    }
+    if (addr as usize) + 1 < vm.memory.len() {
+        lines.push(Line::from(vec![
+            Span::styled("As an instruction: ", p.dim),
+            Span::styled(format!("{:?}", vm.dis(Addr(addr))), p.operand),
+        ]));
+    }
+    let title = format!("Cell 0x{addr:0width$X}");
+    Section::new(vec![Pane::titled(title, lines)])
+}
+
+/// The pane listing every register and cell that differs from `snapshot`.
+fn snapshot_diff(vm: &VM, name: &str, snapshot: &Snapshot, p: &Palette) -> Section {
+    let width = width(vm);
+    let mut changes = Vec::new();
+    if snapshot.pc.0 != vm.pc.0 {
+        changes.push(format!(
+            "PC 0x{:0width$X} -> 0x{:0width$X}",
+            snapshot.pc.0, vm.pc.0
+        ));
+    }
+    for (i, (&old, &new)) in snapshot.regs.iter().zip(&vm.regs).enumerate() {
+        if old != new {
+            changes.push(format!("R{i:X} {old:02X} -> {new:02X}"));
+        }
+    }
+    if vm.isa.flags && snapshot.flags != vm.flags {
+        changes.push(format!("Flags {:02b} -> {:02b}", snapshot.flags, vm.flags));
+    }
+    for (addr, &old) in snapshot.memory.iter().enumerate() {
+        let new = vm.memory.load(addr as u16);
+        if old != new {
+            changes.push(format!("[0x{addr:0width$X}] {old:02X} -> {new:02X}"));
+        }
+    }
+    let mut spans = Vec::new();
+    for change in changes {
+        spans.push(Span::styled(change, p.changed));
+        spans.push(Span::raw("  "));
+    }
+    if spans.is_empty() {
+        spans.push(Span::styled("Nothing changed", p.dim));
+    }
+    let title = format!("Diff since {name}");
+    Section::new(vec![Pane::titled(title, vec![Line::from(spans)]).wrapped()])
+}
+
+/// The pane listing the latest steps of `vm` and what they changed.
+fn history(vm: &VM, view: View, p: &Palette) -> Section {
+    let rows = HISTORY_ROWS as usize;
+    let mut entries = TraceEntry::recent(vm, rows + view.history_offset);
+    entries.truncate(entries.len().saturating_sub(view.history_offset));
+    let lines = entries
+        .iter()
+        .map(|entry| {
+            let latest = entry.step + 1 == vm.history.len();
+            Line::styled(entry.to_string(), if latest { p.name } else { p.dim })
+        })
+        .collect();
+    let title = match view.history_offset {
+        0 => "History".to_string(),
+        offset => format!("History, {offset} steps back"),
+    };
+    let height = rows as u16;
+    Section::new(vec![
+        Pane::titled(title, lines).target(Rect::new(0, 0, u16::MAX, height), Target::History)
+    ])
+}
+
+/// The lines `vm` wrote to its output port, if it wrote anything.
+fn output(vm: &VM) -> Option<Vec<Line<'static>>> {
+    let output = vm
+        .devices
+        .output
+        .as_ref()
+        .filter(|it| !it.buffer.is_empty())?;
+    let lines = output
+        .text()
+        .split('\n')
+        .map(|line| Line::raw(line.replace(|c: char| c.is_control(), "")))
+        .collect();
+    Some(lines)
+}
+
+/// How many lines `vm` wrote to its output port.
+pub fn output_len(vm: &VM) -> usize {
+    output(vm).map_or(0, |it| it.len())
+}
+
+/// The pane showing what `vm` wrote, scrolled back by `view`, and the input
+/// it has yet to read, or the line being typed for it.
+fn console(vm: &VM, view: View, typing: Option<&str>) -> Option<Section> {
+    let p = view.theme.palette();
+    let mut lines = output(vm).unwrap_or_default();
+    let input = vm.devices.input.as_ref();
+    if lines.is_empty() && input.is_none() {
+        return None;
+    }
+    lines.truncate(lines.len().saturating_sub(view.console_offset));
+    if let Some(input) = input {
+        let unread = String::from_utf8_lossy(&input.data[input.pos.min(input.data.len())..])
+            .escape_debug()
+            .to_string();
+        lines.push(match typing {
+            Some(text) => Line::from(vec![
+                Span::styled("> ", p.name),
+                Span::raw(text.to_string()),
+                Span::styled(" ", p.selected),
+            ]),
+            None => Line::styled(format!("> {unread}"), p.dim),
+        });
+    }
+    let title = match view.console_offset {
+        0 => "Console".to_string(),
+        offset => format!("Console, {offset} lines back"),
+    };
+    let height = lines.len() as u16;
+    let pane =
+        Pane::titled(title, lines).target(Rect::new(0, 0, u16::MAX, height), Target::Console);
+    Some(Section::new(vec![pane]).filling())
+}
+
+/// A row of `[key] description` hints.
+fn hint_line(hints: &[(&str, &str)], p: &Palette) -> Line<'static> {
+    let mut spans = Vec::new();
+    for &(key, desc) in hints {
+        if !key.is_empty() {
+            spans.push(Span::styled(format!("[{key}]"), p.key));
+        }
+        spans.push(Span::styled(format!(" {desc} "), p.hint));
+    }
+    Line::from(spans)
+}
+
+/// The first and last address of `range`, which may be empty.
+fn span(range: &Range<usize>, width: usize) -> String {
+    match range.end.checked_sub(1).filter(|&last| last >= range.start) {
+        Some(last) => format!("0x{:0width$X}-0x{last:0width$X}", range.start),
+        None => format!("nothing at 0x{:0width$X}", range.start),
+    }
+}
+
+/// The sections showing the state of `vm`.
+pub fn debugger(
+    vm: &VM,
+    view: View,
+    highlights: &Highlights,
+    session: Option<&Session>,
+    typing: Option<&str>,
+) -> Vec<Section> {
+    let p = view.theme.palette();
+    let diff = session
+        .zip(view.diff)
+        .and_then(|(session, index)| session.snapshots.get(index));
+    let mut registers = Vec::new();
+    for regs in register_rows(view) {
+        registers.push(register_names(vm, regs.clone(), 0, view));
+        registers.push(Line::from(register_values(
+            vm,
+            regs.clone(),
+            highlights,
+            diff.map(|(_, it)| it),
+            view,
+        )));
+        if view.signed {
+            registers.push(register_signed(vm, regs.clone(), view));
+        }
+    }
+    if view.floats {
+        registers.push(Line::default());
+        let per_row = if view.row_len < 16 { 2 } else { 4 };
+        for row in 0..16 / per_row {
+            let mut spans = Vec::new();
+            for i in row * per_row..row * per_row + per_row {
+                spans.push(Span::styled(format!("R{i:X} "), p.name));
+                spans.push(Span::raw(format!(
+                    "{:<12}",
+                    float::decode(vm.getr(Reg(i)).0)
+                )));
+            }
+            registers.push(Line::from(spans));
+        }
+    }
+    let mut sections = vec![Section::new(vec![Pane::titled("Registers", registers)])];
+    if let Some(reg) = view.register {
+        sections.push(Section::line(register_history(vm, Reg(reg), view)));
+    }
+    let mut line = current(vm, "Current: ".to_string(), p);
+    let mut position = format!("  (step {}", vm.history.len());
+    if vm.redo_len() > 0 {
+        position += &format!(" of {}", vm.history.len() + vm.redo_len());
+    }
+    line.push(Span::styled(position + ")", p.dim));
+    sections.push(Section::line(Line::from(line)));
+    let watches = session.map_or(&[][..], |it| &it.watches);
+    if !watches.is_empty() {
+        let mut spans = vec![Span::styled("Watch:", p.dim)];
+        for watch in watches {
+            spans.push(Span::raw("  "));
+            spans.push(Span::styled(watch.text.clone(), p.name));
+            spans.push(Span::raw(" = "));
+            let value = format!("0x{:02X}", watch.value);
+            spans.push(if watch.changed {
+                Span::styled(value, p.written)
+            } else {
+                Span::raw(value)
+            });
+            if watch.changed && p.markers {
+                spans.push(Span::raw("*"));
+            }
+        }
+        sections.push(Section::line(Line::from(spans)));
+    }
+    let width = width(vm);
+    let mut window = 0..0;
+    if let Some(banks) = &vm.devices.banks {
+        window = banks.window();
+        sections.push(Section::line(Line::from(vec![
+            Span::styled("Bank: ", p.dim),
+            Span::styled(format!("{}/{}", banks.current, banks.data.len()), p.mapped),
+            Span::styled(format!(" mapped at {}", span(&window, width)), p.dim),
+        ])));
+    }
+    let source = session.and_then(|it| it.source.as_ref());
+    sections.push(memory(
+        vm,
+        view,
+        highlights,
+        diff.map(|(_, it)| it),
+        window,
+        source,
+        memory_title(vm, view),
+    ));
+    if let Some(cursor) = view.cursor {
+        sections.push(inspector(vm, cursor, source, p));
+    }
+    if let Some((name, snapshot)) = diff {
+        sections.push(snapshot_diff(vm, name, snapshot, p));
+    }
+    if view.history {
+        sections.push(history(vm, view, p));
+    }
+    if let Some(screen) = &vm.devices.screen {
+        let rows = screen
+            .rows(vm.memory.as_ref())
+            .map(|row| Line::styled(row, p.screen))
+            .collect();
+        sections.push(Section::new(vec![
+            Pane::titled("Screen", rows).width(Screen::WIDTH as u16)
+        ]));
+    }
+    if let Some(framebuffer) = &vm.devices.framebuffer {
+        let lines = framebuffer
+            .lines(vm.memory.as_ref())
+            .map(|line| Line::styled(line, p.framebuffer))
+            .collect();
+        sections.push(Section::new(vec![
+            Pane::titled("Framebuffer", lines).width(Framebuffer::SIZE as u16)
+        ]));
+    }
+    sections.extend(console(vm, view, typing));
+    sections
+}
+
+/// The keys available in `mode`.
+pub fn hints(vm: &VM, mode: Mode, view: View) -> Section {
+    let hints: &[_] = match mode {
+        Mode::Debugger => &[
+            ("Q", "Quit"),
+            ("S", "Step"),
+            ("O", "Step Over"),
+            ("Shift+N", "Run N Steps"),
+            ("Z", "Undo"),
+            ("Y", "Redo"),
+            ("Home/End", "First/Last Step"),
+            ("R", "Reset"),
+            ("Shift+R", "Reload"),
+            ("P", "Save Point"),
+            ("L", "Load Point"),
+            ("F", "Floats"),
+            ("V", "Values"),
+            ("A", "Follow PC"),
+            ("Shift+H", "History"),
+            ("C", "Changes"),
+            ("D", "Hex/Dec/Bin"),
+            ("Shift+S", "Signed"),
+            ("G", "Go To"),
+            ("/", "Search"),
+            ("W", "Watch"),
+            (":", "Command"),
+            ("Space", "Play"),
+            ("B", "Breakpoint"),
+            ("E", "Edit Register"),
+            ("Enter", "Run All"),
+            ("K", "Keyboard"),
+            ("Shift+I", "Type Input"),
+        ],
+        Mode::Keyboard => &[("Esc", "Debugger"), ("", "Keys are sent to the program")],
+        Mode::Input => &[("Enter", "Send"), ("Esc", "Cancel")],
+        Mode::Play => &[("Space", "Pause"), ("+/-", "Speed")],
+        Mode::Run => &[("Esc", "Stop")],
+    };
+    let mut hints: Vec<_> = hints
+        .iter()
+        .copied()
+        .filter(|&(key, _)| {
+            let needs_input = key == "K" || key == "Shift+I";
+            !(needs_input && vm.devices.input.is_none()
+                || key == "Y" && vm.redo_len() == 0
+                || key == "O" && !vm.isa.ext)
+        })
+        .collect();
+    if view.vim && mode == Mode::Debugger {
+        for (key, _) in &mut hints {
+            *key = match *key {
+                "K" => "I",
+                "P" => "M",
+                "L" => "'",
+                _ => key,
+            };
+        }
+        hints.insert(1, ("HJKL", "Move"));
+    }
+    let p = view.theme.palette();
+    let mut line = hint_line(&hints, p);
+    if mode == Mode::Play {
+        line.push_span(Span::styled(format!(" {} steps/s", view.speed), p.dim));
+    }
+    Section::new(vec![Pane::new(vec![line]).wrapped()])
+}
+
+/// The bottom row, where prompts are typed and errors shown.
+pub fn status(message: Option<&str>, theme: Theme) -> Section {
+    Section::line(match message {
+        Some(message) => Line::styled(message.to_string(), theme.palette().error),
+        None => Line::default(),
+    })
+}
+
+/// The sections showing every VM of `cluster`, and the memory of the one at
+/// `focus`.
+pub fn cluster(
+    cluster: &Cluster,
+    focus: usize,
+    highlights: &[Rc<RefCell<Highlights>>],
+    view: View,
+) -> Vec<Section> {
+    let p = view.theme.palette();
+    let mut registers = Vec::new();
+    for regs in register_rows(view) {
+        registers.push(register_names(&cluster.vms[0], regs.clone(), 8, view));
+        for (core, vm) in cluster.vms.iter().enumerate() {
+            let mut spans = vec![Span::styled(
+                format!("Core {core}: "),
+                if core == focus { p.mapped } else { p.dim },
+            )];
+            spans.extend(register_values(
+                vm,
+                regs.clone(),
+                &highlights[core].borrow(),
+                None,
+                view,
+            ));
+            registers.push(Line::from(spans));
+        }
+    }
+    let mut sections = vec![Section::new(vec![Pane::titled("Registers", registers)])];
+    for (core, vm) in cluster.vms.iter().enumerate() {
+        let mut line = current(vm, format!("Core {core}: "), p);
+        if !cluster.running[core] {
+            line.push(Span::styled("  (halted)", p.dim));
+        }
+        sections.push(Section::line(Line::from(line)));
+    }
+    let vm = &cluster.vms[focus];
+    let width = width(vm);
+    sections.push(memory(
+        vm,
+        view,
+        &highlights[focus].borrow(),
+        None,
+        cluster.window.clone(),
+        None,
+        format!(
+            "Memory of core {focus}, shared at {}",
+            span(&cluster.window, width)
+        ),
+    ));
+    for (core, vm) in cluster.vms.iter().enumerate() {
+        if let Some(lines) = output(vm) {
+            let title = format!("Console of core {core}");
+            sections.push(Section::new(vec![Pane::titled(title, lines)]));
+        }
+    }
+    sections
+}
+
+/// The keys available in the cluster view, or only Esc while `running`.
+pub fn cluster_hints(running: bool, theme: Theme) -> Section {
+    let hints: &[_] = if running {
+        &[("Esc", "Stop")]
+    } else {
+        &[
+            ("Q", "Quit"),
+            ("S", "Step All"),
+            ("Z", "Undo"),
+            ("R", "Reset"),
+            ("Shift+R", "Reload"),
+            ("Tab", "Switch Core"),
+            ("Enter", "Run All"),
+        ]
+    };
+    let hints = hint_line(hints, theme.palette());
+    Section::new(vec![Pane::new(vec![hints]).wrapped()])
+}
@@ -1,10 +1,31 @@
-use anyhow::{anyhow, Result};
-use std::fmt::Debug;
+use crate::{
+    device::{DeviceState, Devices, Random, ReadState},
+    float,
+    memory::Memory,
+    observer::Observer,
+    trace::{TraceEntry, Tracer},
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
 
-#[derive(Clone, Copy)]
+/// One of the 16 registers, R0 to RF.
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Reg(pub u8);
-#[derive(Clone, Copy)]
+/// A byte, as a value or as an 8-bit address within the current page.
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Const(pub u8);
+/// A full memory address. Memories larger than 256 bytes are split into
+/// 256-byte pages, and the 8-bit addresses in instructions refer to the page
+/// the program counter is in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Addr(pub u16);
 
 impl Debug for Reg {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -18,32 +39,163 @@ impl Debug for Const {
     }
 }
 
-#[derive(Debug)]
+impl Debug for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:X}", self.0)
+    }
+}
+
+/// An error stopping the machine from executing further.
+#[derive(Debug, Error)]
+pub enum VmError {
+    /// The program counter ran off the end of memory, see `EndOfMemory`.
+    #[error("Program counter exceeded memory bounds (> {size})")]
+    PcOverflow {
+        /// The size of memory.
+        size: usize,
+    },
+    /// The machine got back into a state it was in, see `VM::detect_loops`.
+    #[error("Program will never halt: state repeated at step {step} (first seen at step {first})")]
+    InfiniteLoop {
+        /// The step the state came back at.
+        step: usize,
+        /// The step it was first in at.
+        first: usize,
+    },
+    /// The program counter ran into fill memory, see `Unloaded::Stop`.
+    #[error("Program counter ran past the end of the program into unloaded memory at {pc:?}")]
+    UnloadedCode {
+        /// Where it ran to.
+        pc: Addr,
+    },
+    /// `VM::step_limit` ran out.
+    #[error("Gave up after {limit} steps without halting")]
+    StepLimit {
+        /// The limit.
+        limit: usize,
+    },
+    /// `VM::time_limit` ran out.
+    #[error("Gave up after {limit:?} without halting")]
+    TimeLimit {
+        /// The limit.
+        limit: Duration,
+    },
+    /// Writing to `VM::tracer` failed.
+    #[error("Failed to write trace")]
+    Trace(#[from] std::io::Error),
+}
+
+impl VmError {
+    /// A name for this kind of error that stays the same across versions,
+    /// for tools reading `--error-format json`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmError::PcOverflow { .. } => "pc-overflow",
+            VmError::InfiniteLoop { .. } => "infinite-loop",
+            VmError::UnloadedCode { .. } => "unloaded-code",
+            VmError::StepLimit { .. } => "step-limit",
+            VmError::TimeLimit { .. } => "time-limit",
+            VmError::Trace(_) => "trace",
+        }
+    }
+}
+
+/// The result of running the machine.
+pub type Result<T> = std::result::Result<T, VmError>;
+
+/// The memory sizes a VM can be constructed with.
+pub const MEMORY_SIZES: [usize; 4] = [256, 512, 1024, 4096];
+
+/// How many steps go by between checks of `VM::time_limit`.
+const TIME_CHECK: usize = 1024;
+
+/// The stack pointer of the extended instruction set.
+pub const SP: Reg = Reg(0xF);
+
+/// A decoded instruction, see `Instr::new`. The ones after `JumpIfLess`
+/// belong to the extensions of `Isa`.
+#[derive(Clone, Copy, Debug)]
 pub enum Instr {
+    /// `none`, which does nothing.
     None,
+    /// `loadm`: a register from a memory cell.
     LoadFromMemory(Reg, Const),
+    /// `loadb`: a register from a constant.
     LoadWithConstant(Reg, Const),
+    /// `storem`: a register to a memory cell.
     StoreToMemory(Reg, Const),
+    /// `move`: from the first register to the second.
     Move(Reg, Reg),
+    /// `addi`: the first register to the sum of the others.
     AddInt(Reg, Reg, Reg),
+    /// `addf`: the first register to the float sum of the others.
     AddFloat(Reg, Reg, Reg),
+    /// `or`: the first register to the bitwise or of the others.
     Or(Reg, Reg, Reg),
+    /// `and`: the first register to the bitwise and of the others.
     And(Reg, Reg, Reg),
+    /// `xor`: the first register to the bitwise xor of the others.
     Xor(Reg, Reg, Reg),
+    /// `rot`: a register rotated right by a number of bits.
     Rotate(Reg, Const),
+    /// `jump`: to the address if the register equals R0.
     JumpIfEqual(Reg, Const),
+    /// `halt`.
     Halt,
+    /// `loadp`: the first register from the cell the second points at.
     LoadFromPointer(Reg, Reg),
+    /// `storep`: the first register to the cell the second points at.
     StoreToPointer(Reg, Reg),
+    /// `jumpl`: to the address if the register is less than R0.
     JumpIfLess(Reg, Const),
+    // Only decoded by the extended instruction set, which uses RF as the
+    // stack pointer
+    /// `push` a register onto the stack.
+    Push(Reg),
+    /// `pop` a register off the stack.
+    Pop(Reg),
+    /// `call`: push the return address and jump.
+    Call(Const),
+    /// `ret`: pop the return address and jump to it.
+    Ret,
+    // Only decoded with the flags extension
+    /// `jz`: jump if the last result was zero.
+    JumpIfZero(Const),
+    /// `jnz`: jump if the last result was not zero.
+    JumpIfNotZero(Const),
+    /// `jc`: jump if the last result carried.
+    JumpIfCarry(Const),
+    /// `jnc`: jump if the last result did not carry.
+    JumpIfNoCarry(Const),
+    /// A reserved encoding with a handler registered by `VM::define`.
+    Custom(Const, Const),
+}
+
+/// The optional extensions of the instruction set a VM decodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Isa {
+    /// The stack instructions, using RF as the stack pointer.
+    pub ext: bool,
+    /// The zero and carry flags, and conditional jumps on them.
+    pub flags: bool,
 }
 
+/// The zero flag, set when an arithmetic or logic result is zero.
+pub const ZERO: u8 = 1;
+/// The carry flag, set when an integer addition carries out of bit 7.
+pub const CARRY: u8 = 2;
+
 impl Instr {
-    pub fn new(i0: u8, i1: u8) -> Self {
+    /// Decodes an instruction word. The extensions in `isa` take over some of
+    /// the encodings that are otherwise `None` or `Halt`.
+    pub fn new(i0: u8, i1: u8, isa: Isa) -> Self {
+        let Isa { ext, flags } = isa;
         let low = |byte: u8| byte & 0xf;
         let high = |byte: u8| (byte >> 4) & 0xf;
         use Instr::*;
         match high(i0) {
+            0 if ext && low(i0) == 1 => Push(Reg(low(i1))),
+            0 if ext && low(i0) == 2 => Pop(Reg(low(i1))),
             0 => None,
             1 => LoadFromMemory(Reg(low(i0)), Const(i1)),
             2 => LoadWithConstant(Reg(low(i0)), Const(i1)),
@@ -56,6 +208,12 @@ impl Instr {
             9 => Xor(Reg(low(i0)), Reg(high(i1)), Reg(low(i1))),
             10 => Rotate(Reg(low(i0)), Const(i1)),
             11 => JumpIfEqual(Reg(low(i0)), Const(i1)),
+            12 if ext && low(i0) == 1 => Call(Const(i1)),
+            12 if ext && low(i0) == 2 => Ret,
+            12 if flags && low(i0) == 3 => JumpIfZero(Const(i1)),
+            12 if flags && low(i0) == 4 => JumpIfNotZero(Const(i1)),
+            12 if flags && low(i0) == 5 => JumpIfCarry(Const(i1)),
+            12 if flags && low(i0) == 6 => JumpIfNoCarry(Const(i1)),
             12 => Halt,
             13 => LoadFromPointer(Reg(low(i0)), Reg(low(i1))),
             14 => StoreToPointer(Reg(low(i0)), Reg(low(i1))),
@@ -63,21 +221,239 @@ impl Instr {
             _ => unreachable!(),
         }
     }
+
+    /// Whether the word decodes to `None` or `Halt` without being their
+    /// canonical `0000` or `C000`, leaving it free for `VM::define`.
+    pub fn is_reserved(i0: u8, i1: u8, isa: Isa) -> bool {
+        match Self::new(i0, i1, isa) {
+            Instr::None => (i0, i1) != (0, 0),
+            Instr::Halt => (i0, i1) != (0xC0, 0),
+            _ => false,
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Carries out a custom instruction given its two bytes, returning what it
+/// does or `None` to halt.
+pub type Handler = Box<dyn FnMut(&VM, u8, u8) -> Option<Action>>;
+
+/// A change to the machine state, which is what an instruction does. Every
+/// action has an inverse, which `VM::execute` returns.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Action {
+    /// Nothing changes.
     None,
+    /// A register is set.
     SetReg(Reg, Const),
-    SetMem(Const, Const),
-    Jump(Const),
+    /// A memory cell is set.
+    SetMem(Addr, Const),
+    /// The program counter is set.
+    Jump(Addr),
+    /// The flags are set.
+    SetFlags(Const),
+    /// Several actions taking effect in order as a single step.
+    Batch(Vec<Action>),
+}
+
+/// An executed step, recorded so that it can be undone.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Step {
+    /// The program counter before the step.
+    pub pc: Addr,
+    /// The inverse of everything the step did other than moving the program
+    /// counter.
+    pub undo: Action,
+    /// Whether this is a change made by the user rather than an executed
+    /// instruction. Observers only see executed steps.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edit: bool,
+    /// What reading the ports changed, as it was before the step, if the step
+    /// read them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reads: Option<ReadState>,
+}
+
+/// A copy of the complete state of a VM, see `VM::snapshot`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// See `VM::regs`.
+    pub regs: [u8; 16],
+    /// See `VM::flags`.
+    #[serde(default)]
+    pub flags: u8,
+    /// The whole contents of memory.
+    pub memory: Vec<u8>,
+    /// See `VM::pc`.
+    pub pc: Addr,
+    /// See `VM::history`.
+    pub history: Vec<Step>,
+    #[serde(skip)]
+    seen: Option<HashMap<u64, usize>>,
+    devices: DeviceState,
 }
 
+/// What a step did, as yielded by `VM::steps`.
+#[derive(Clone, Debug)]
+pub struct StepRecord {
+    /// The program counter before the step.
+    pub pc: Addr,
+    /// The instruction executed.
+    pub instr: Instr,
+    /// What it did, apart from moving on to the next instruction.
+    pub action: Action,
+    /// The program counter after the step.
+    pub next: Addr,
+    /// Whether the machine is still running, i.e. `instr` did not halt.
+    pub running: bool,
+}
+
+/// The iterator of `VM::steps`.
+pub struct Steps<'a> {
+    vm: &'a mut VM,
+    done: bool,
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<StepRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.vm.step_record();
+        self.done = res.as_ref().map_or(true, |it| !it.running);
+        Some(res)
+    }
+}
+
+/// What memory past the loaded program is filled with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillPattern {
+    /// This byte everywhere.
+    Byte(u8),
+    /// Pseudo-random bytes from this seed, the same on every reload.
+    Random(u64),
+}
+
+impl Default for FillPattern {
+    fn default() -> Self {
+        Self::Byte(0)
+    }
+}
+
+/// What to do when the program counter runs past the loaded program into
+/// fill memory, usually because of a missing `halt`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Unloaded {
+    /// Execute it like any other memory.
+    #[value(help = "Execute it like any other memory")]
+    Ignore,
+    /// Note the first time it happens in `VM::ran_off`.
+    #[value(help = "Warn the first time it happens")]
+    Warn,
+    /// Fail with `VmError::UnloadedCode`.
+    #[value(help = "Stop with an error")]
+    Stop,
+}
+
+/// What happens once the program counter reaches the end of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EndOfMemory {
+    /// Continue at address 0, also for an instruction straddling the end.
+    #[value(help = "Continue at address 0")]
+    Wrap,
+    /// Halt after the last instruction in memory.
+    #[value(help = "Halt after the last instruction in memory")]
+    Halt,
+    /// Fail with `VmError::PcOverflow` when fetching past the end.
+    #[value(help = "Stop with an error when fetching past the end")]
+    Error,
+}
+
+/// Why a run of several steps stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program executed a `halt`.
+    Halted,
+    /// The predicate given to `VM::run_until` held.
+    Predicate,
+    /// The program counter reached one of `VM::breakpoints`.
+    Breakpoint(Addr),
+    /// The number of steps given to `VM::run_steps` ran out.
+    Budget,
+}
+
+/// The machine: its registers, memory and devices, along with the history
+/// of executed steps, which makes every step undoable. Set one up with
+/// `VmBuilder`, or with `VM::new` and `VM::fill`.
 pub struct VM {
+    /// R0 to RF.
     pub regs: [u8; 16],
-    pub memory: [u8; 256],
-    pub pc: Const,
-    pub actions: Vec<Action>,
+    /// Everything the program addresses, apart from devices.
+    pub memory: Box<dyn Memory>,
+    /// The address of the next instruction.
+    pub pc: Addr,
+    /// Every step since the last reset, oldest first.
+    pub history: Vec<Step>,
+    /// Hashes of every machine state seen so far, mapped to the step they
+    /// first appeared at. `None` disables infinite-loop detection.
+    pub seen: Option<HashMap<u64, usize>>,
+    /// The devices mapped into memory.
+    pub devices: Devices,
+    /// The extensions the VM decodes.
+    pub isa: Isa,
+    /// The flags of the flags extension, see `ZERO` and `CARRY`.
+    pub flags: u8,
+    /// Where every step is logged to, if anywhere.
+    pub tracer: Option<Tracer>,
+    /// The loaded program, spanning memory from address 0; the rest of memory
+    /// holds `fill_pattern`.
+    pub image: Vec<u8>,
+    /// What memory past the program is filled with.
+    pub fill_pattern: FillPattern,
+    /// Bytes stored over every program `fill` loads, at their addresses.
+    pub data: Vec<(u16, Vec<u8>)>,
+    /// The registers and program counter a reset starts from.
+    pub initial_regs: [u8; 16],
+    /// Where a reset starts executing.
+    pub entry: Addr,
+    /// Steps after which to fail with `VmError::StepLimit`.
+    pub step_limit: Option<usize>,
+    /// Time after which to fail with `VmError::TimeLimit`, counted from the
+    /// first step since the last reset.
+    pub time_limit: Option<Duration>,
+    started: Option<Instant>,
+    /// What happens when the program counter runs into fill memory.
+    pub unloaded: Unloaded,
+    /// What happens when the program counter runs off the end of memory.
+    pub end_of_memory: EndOfMemory,
+    /// The step at which, and the address to which, the program counter
+    /// first ran past the program.
+    pub ran_off: Option<(usize, Addr)>,
+    /// How many steps in the history executed each byte of memory.
+    executed: Vec<u32>,
+    /// Writes that landed on already-executed instructions, by the step they
+    /// happened at.
+    pub code_writes: Vec<(usize, Addr)>,
+    /// Addresses that runs stop at before executing them.
+    pub breakpoints: HashSet<Addr>,
+    observers: Vec<Box<dyn Observer>>,
+    /// Handlers of reserved encodings, by opcode.
+    handlers: HashMap<u8, Handler>,
+    /// Undone steps, the most recently undone last.
+    redo: Vec<Undone>,
+}
+
+/// An undone step, kept so that redoing it repeats exactly what it did, even
+/// if it read from a device.
+struct Undone {
+    pc: Addr,
+    /// The program counter after the step.
+    next: Addr,
+    action: Action,
+    edit: bool,
+    /// What reading the ports changed, as it was after the step.
+    reads: Option<ReadState>,
 }
 
 impl Default for VM {
@@ -87,20 +463,107 @@ impl Default for VM {
 }
 
 impl VM {
+    /// A machine with 256 bytes of plain RAM, all zero.
     pub fn new() -> Self {
+        Self::with_memory_size(256)
+    }
+
+    /// Creates a VM with `size` bytes of memory, which must be one of
+    /// `MEMORY_SIZES`.
+    pub fn with_memory_size(size: usize) -> Self {
+        assert!(
+            MEMORY_SIZES.contains(&size),
+            "Unsupported memory size {size}"
+        );
+        Self::with_memory(Box::new(vec![0; size]))
+    }
+
+    /// Creates a VM running on a custom memory backend, whose length must be
+    /// a multiple of the 256-byte page size.
+    pub fn with_memory(memory: Box<dyn Memory>) -> Self {
+        assert!(
+            memory.len().is_multiple_of(256) && memory.len() <= 1 << 16,
+            "Unsupported memory size {}",
+            memory.len()
+        );
+        let size = memory.len();
         Self {
             regs: [0; 16],
-            memory: [0; 256],
-            pc: Const(0),
-            actions: Vec::new(),
+            memory,
+            pc: Addr(0),
+            history: Vec::new(),
+            seen: None,
+            devices: Devices::default(),
+            isa: Isa::default(),
+            flags: 0,
+            tracer: None,
+            image: Vec::new(),
+            fill_pattern: FillPattern::default(),
+            data: Vec::new(),
+            initial_regs: [0; 16],
+            entry: Addr(0),
+            step_limit: None,
+            time_limit: None,
+            started: None,
+            unloaded: Unloaded::Ignore,
+            end_of_memory: EndOfMemory::Error,
+            ran_off: None,
+            executed: vec![0; size],
+            code_writes: Vec::new(),
+            breakpoints: HashSet::new(),
+            observers: Vec::new(),
+            handlers: HashMap::new(),
+            redo: Vec::new(),
         }
     }
 
+    /// Fails with `VmError::InfiniteLoop` once the machine state repeats,
+    /// since the program would then never halt.
+    pub fn detect_loops(&mut self, enable: bool) {
+        self.seen = enable.then(HashMap::new);
+    }
+
+    fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.regs.hash(&mut hasher);
+        self.flags.hash(&mut hasher);
+        self.memory.bytes().hash(&mut hasher);
+        self.pc.0.hash(&mut hasher);
+        self.devices.hash_state(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The seed of the random port, or else of the random fill, if the run
+    /// uses either: all it takes to run it again the same way.
+    pub fn seed(&self) -> Option<u64> {
+        let fill = match self.fill_pattern {
+            FillPattern::Random(seed) => Some(seed),
+            FillPattern::Byte(_) => None,
+        };
+        self.devices.random.as_ref().map(|it| it.seed).or(fill)
+    }
+
+    /// Loads `memory` as the program, filling the rest with `fill_pattern`.
     pub fn fill(&mut self, memory: &[u8]) {
-        self.memory.fill(0);
-        self.memory[..memory.len()].copy_from_slice(memory);
+        let background: Vec<_> = match self.fill_pattern {
+            FillPattern::Byte(byte) => vec![byte; self.memory.len()],
+            FillPattern::Random(seed) => {
+                let mut random = Random::new(0, seed);
+                (0..self.memory.len()).map(|_| random.read()).collect()
+            }
+        };
+        self.memory.copy_from(&background);
+        self.memory.copy_from(memory);
+        for (addr, bytes) in &self.data {
+            for (i, &byte) in bytes.iter().enumerate() {
+                self.memory.store(addr + i as u16, byte);
+            }
+        }
+        self.image = memory.to_vec();
     }
 
+    /// Carries out `action`, returning the action undoing it. It is not
+    /// recorded in the history, which `edit` does.
     pub fn execute(&mut self, action: Action) -> Action {
         use std::mem::replace;
         use Action::*;
@@ -109,118 +572,802 @@ impl VM {
             SetReg(reg, value) => {
                 SetReg(reg, Const(replace(&mut self.regs[reg.0 as usize], value.0)))
             }
-            SetMem(addr, value) => SetMem(
-                addr,
-                Const(replace(&mut self.memory[addr.0 as usize], value.0)),
-            ),
+            SetMem(addr, value) => {
+                let old = self.memory.load(addr.0);
+                self.memory.store(addr.0, value.0);
+                // Done here rather than by the device so that undoing the
+                // switch maps the previous bank back
+                if let Some(banks) = self
+                    .devices
+                    .banks
+                    .as_mut()
+                    .filter(|it| it.control == addr.0)
+                {
+                    banks.switch(self.memory.as_mut(), value.0);
+                }
+                SetMem(addr, Const(old))
+            }
             Jump(addr) => Jump(replace(&mut self.pc, addr)),
+            SetFlags(value) => SetFlags(Const(replace(&mut self.flags, value.0))),
+            Batch(actions) => {
+                let mut inverse: Vec<_> = actions.into_iter().map(|it| self.execute(it)).collect();
+                inverse.reverse();
+                Batch(inverse)
+            }
+        }
+    }
+
+    fn notify_devices(&mut self, action: &Action, undo: bool) {
+        match action {
+            Action::SetMem(addr, _) if undo => self.devices.undo_write(addr.0),
+            Action::SetMem(addr, value) => self.devices.write(addr.0, value.0),
+            Action::Batch(actions) => {
+                for action in actions {
+                    self.notify_devices(action, undo);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Carries out `action` as the program would, returning its inverse.
+    fn apply(&mut self, action: Action) -> Action {
+        if let Action::Batch(actions) = action {
+            let mut inverse: Vec<_> = actions.into_iter().map(|it| self.apply(it)).collect();
+            inverse.reverse();
+            return Action::Batch(inverse);
+        }
+        self.notify_devices(&action, false);
+        let inverse = self.execute(action);
+        match inverse {
+            Action::SetReg(reg, old) => {
+                let new = self.getr(reg);
+                for observer in &mut self.observers {
+                    observer.on_reg_write(reg, old, new);
+                }
+            }
+            Action::SetMem(addr, old) => {
+                if self.was_executed(addr) {
+                    self.code_writes.push((self.history.len(), addr));
+                }
+                let new = self.load(addr);
+                for observer in &mut self.observers {
+                    observer.on_mem_write(addr, old, new);
+                }
+            }
+            _ => {}
+        }
+        inverse
+    }
+
+    /// Notifies `observer` of everything the machine does from now on.
+    pub fn observe(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn notify_observers(&mut self, f: impl Fn(&mut dyn Observer, &VM)) {
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in &mut observers {
+            f(observer.as_mut(), self);
         }
+        self.observers = observers;
     }
 
-    pub fn redo(&mut self, action: Action) {
-        let action = self.execute(action);
-        self.actions.push(action);
+    /// Applies `action` as a change made by the user, which is undone like a
+    /// step.
+    pub fn edit(&mut self, action: Action) {
+        let undo = self.execute(action);
+        self.history.push(Step {
+            pc: self.pc,
+            undo,
+            edit: true,
+            reads: None,
+        });
+        self.redo.clear();
     }
 
+    /// Undoes the latest step, if there is one, keeping it to redo.
     pub fn undo(&mut self) {
-        if let Some(action) = self.actions.pop() {
-            self.execute(action);
-            self.pc.0 -= 2;
+        if let Some(step) = self.history.pop() {
+            if step.edit {
+                let action = self.execute(step.undo);
+                self.forget_after(self.history.len());
+                self.redo.push(Undone {
+                    pc: step.pc,
+                    next: step.pc,
+                    action,
+                    edit: true,
+                    reads: None,
+                });
+                return;
+            }
+            self.notify_devices(&step.undo, true);
+            let next = self.pc;
+            let action = self.execute(step.undo);
+            let reads = step.reads.map(|reads| {
+                let after = self.devices.reads();
+                self.devices.set_reads(reads);
+                after
+            });
+            self.redo.push(Undone {
+                pc: step.pc,
+                next,
+                action,
+                edit: false,
+                reads,
+            });
+            self.pc = step.pc;
+            self.count_executed(step.pc, false);
+            self.forget_after(self.history.len());
+            self.notify_observers(|observer, vm| observer.on_undo(vm));
+            let hash = self.seen.is_some().then(|| self.state_hash());
+            if let (Some(hash), Some(seen)) = (hash, &mut self.seen) {
+                seen.remove(&hash);
+            }
         }
     }
 
+    /// How many undone steps there are to redo.
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Redoes the last undone step, returning whether the machine is still
+    /// running after it. The step does what it did the first time, even if
+    /// memory changed since.
+    pub fn redo(&mut self) -> bool {
+        let Some(undone) = self.redo.pop() else {
+            return true;
+        };
+        if undone.edit {
+            let undo = self.execute(undone.action);
+            self.history.push(Step {
+                pc: undone.pc,
+                undo,
+                edit: true,
+                reads: None,
+            });
+            return true;
+        }
+        let len = self.memory.len();
+        let instr = self.dis(undone.pc);
+        if undone.pc.0 as usize >= self.image.len()
+            && self.ran_off.is_none()
+            && self.unloaded == Unloaded::Warn
+        {
+            self.ran_off = Some((self.history.len(), undone.pc));
+        }
+        if (undone.pc.0 as usize) < len {
+            self.count_executed(undone.pc, true);
+        }
+        self.pc = undone.next;
+        let undo = self.apply(undone.action);
+        let reads = undone.reads.map(|after| {
+            let reads = self.devices.reads();
+            self.devices.set_reads(after);
+            reads
+        });
+        self.history.push(Step {
+            pc: undone.pc,
+            undo,
+            edit: false,
+            reads,
+        });
+        let halted = matches!(instr, Instr::Halt)
+            || self.end_of_memory == EndOfMemory::Halt && self.pc.0 as usize >= len;
+        let running = !halted;
+        self.notify_observers(|observer, vm| {
+            observer.on_step(vm, vm.history.last().unwrap());
+            if !running {
+                observer.on_halt(vm);
+            }
+        });
+        running
+    }
+
+    /// The value of `reg`.
     pub fn getr(&self, reg: Reg) -> Const {
         Const(self.regs[reg.0 as usize])
     }
 
-    pub fn load(&self, addr: Const) -> Const {
-        Const(self.memory[addr.0 as usize])
+    /// The byte in memory at `addr`, without reading from devices.
+    pub fn load(&self, addr: Addr) -> Const {
+        Const(self.memory.load(addr.0))
     }
 
+    /// Resolves an 8-bit address in the page of the program counter.
+    pub fn addr(&self, addr: Const) -> Addr {
+        Addr(self.pc.0 & 0xff00 | addr.0 as u16)
+    }
+
+    /// Loads a byte as the executing program would, giving mapped devices a
+    /// chance to respond instead of memory.
+    pub fn read(&mut self, addr: Addr) -> Const {
+        let value = self
+            .devices
+            .read(addr.0)
+            .map_or_else(|| self.load(addr), Const);
+        for observer in &mut self.observers {
+            observer.on_mem_read(addr, value);
+        }
+        value
+    }
+
+    /// A copy of the complete state, which `restore` goes back to.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            regs: self.regs,
+            flags: self.flags,
+            memory: self.memory.bytes(),
+            pc: self.pc,
+            history: self.history.clone(),
+            seen: self.seen.clone(),
+            devices: self.devices.save(),
+        }
+    }
+
+    /// Brings the VM back to the state `snapshot` was taken in.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.regs = snapshot.regs;
+        self.flags = snapshot.flags;
+        self.memory.copy_from(&snapshot.memory);
+        self.pc = snapshot.pc;
+        self.history.clone_from(&snapshot.history);
+        self.redo.clear();
+        self.executed.fill(0);
+        for i in 0..self.history.len() {
+            if !self.history[i].edit {
+                self.count_executed(self.history[i].pc, true);
+            }
+        }
+        self.forget_after(self.history.len());
+        // Snapshots loaded from disk carry no loop detection state
+        self.seen = match &snapshot.seen {
+            Some(seen) => Some(seen.clone()),
+            None => self.seen.as_ref().map(|_| HashMap::new()),
+        };
+        self.devices.restore(&snapshot.devices);
+    }
+
+    /// Whether an instruction covering `addr` was executed by a step in the
+    /// history.
+    pub fn was_executed(&self, addr: Addr) -> bool {
+        self.executed[addr.0 as usize] != 0
+    }
+
+    fn count_executed(&mut self, pc: Addr, add: bool) {
+        let len = self.executed.len();
+        for addr in [pc.0 as usize, (pc.0 as usize + 1) % len] {
+            if add {
+                self.executed[addr] += 1;
+            } else {
+                self.executed[addr] -= 1;
+            }
+        }
+    }
+
+    /// Drops what was noted about steps from `step` on, as they were undone.
+    fn forget_after(&mut self, step: usize) {
+        self.ran_off = self.ran_off.filter(|&(at, _)| at < step);
+        self.code_writes.retain(|&(at, _)| at < step);
+    }
+
+    fn jump_if(page: impl Fn(Const) -> Addr, cond: bool, addr: Const) -> Action {
+        if cond {
+            Action::Jump(page(addr))
+        } else {
+            Action::None
+        }
+    }
+
+    /// Also restores memory to the loaded program, undoing everything the
+    /// program wrote.
+    pub fn reset_hard(&mut self) {
+        self.reset();
+        let image = std::mem::take(&mut self.image);
+        self.fill(&image);
+    }
+
+    /// Restarts the program with memory left as it is.
     pub fn reset(&mut self) {
-        self.regs.fill(0);
-        self.pc = Const(0);
-        self.actions.clear();
+        self.regs = self.initial_regs;
+        self.flags = 0;
+        self.pc = self.entry;
+        self.history.clear();
+        self.redo.clear();
+        self.executed.fill(0);
+        self.forget_after(0);
+        self.devices.reset();
+        self.started = None;
+        if let Some(seen) = &mut self.seen {
+            seen.clear();
+        }
     }
 
-    pub fn dis(&self, addr: Const) -> Instr {
-        let addr = addr.0 as usize;
-        Instr::new(self.memory[addr], self.memory[addr + 1])
+    /// Registers `handler` for the reserved encodings of `opcode`, which must
+    /// be 0x0 or 0xC, replacing any previous one. See `Instr::is_reserved`.
+    pub fn define(
+        &mut self,
+        opcode: u8,
+        handler: impl FnMut(&VM, u8, u8) -> Option<Action> + 'static,
+    ) {
+        assert!(
+            opcode == 0x0 || opcode == 0xC,
+            "Opcode 0x{opcode:X} has no reserved encodings"
+        );
+        self.handlers.insert(opcode, Box::new(handler));
     }
 
-    pub fn exec(&mut self, instr: Instr) -> bool {
+    /// Decodes the instruction at `addr`, wrapping around the end of memory.
+    pub fn dis(&self, addr: Addr) -> Instr {
+        let len = self.memory.len();
+        let load = |addr: usize| self.memory.load((addr % len) as u16);
+        let (i0, i1) = (load(addr.0 as usize), load(addr.0 as usize + 1));
+        if Instr::is_reserved(i0, i1, self.isa) && self.handlers.contains_key(&(i0 >> 4)) {
+            return Instr::Custom(Const(i0), Const(i1));
+        }
+        Instr::new(i0, i1, self.isa)
+    }
+
+    /// Executes `instr`, fetched from `at`, returning what it did along with
+    /// the inverse, or `None` if it halts the machine. Its 8-bit addresses are
+    /// in the page of `at`, since the program counter may already have moved
+    /// on to the next page, or past the end of memory.
+    pub fn exec(&mut self, instr: Instr, at: Addr) -> Option<(Action, Action)> {
         use Action::None;
         use Action::*;
         use Instr::*;
-        self.redo(match instr {
+        let page = |addr: Const| Addr(at.0 & 0xff00 | addr.0 as u16);
+        let action = match instr {
             Instr::None => None,
-            LoadFromMemory(reg, addr) => SetReg(reg, self.load(addr)),
+            LoadFromMemory(reg, addr) => SetReg(reg, self.read(page(addr))),
             LoadWithConstant(reg, value) => SetReg(reg, value),
-            StoreToMemory(reg, addr) => SetMem(addr, self.getr(reg)),
+            StoreToMemory(reg, addr) => SetMem(page(addr), self.getr(reg)),
             Move(from, to) => SetReg(to, self.getr(from)),
             AddInt(r0, r1, r2) => SetReg(r0, Const(self.getr(r1).0.wrapping_add(self.getr(r2).0))),
-            AddFloat(r0, r1, r2) => SetReg(r0, Const(add_float(self.getr(r1).0, self.getr(r2).0))),
+            AddFloat(r0, r1, r2) => SetReg(r0, Const(float::add(self.getr(r1).0, self.getr(r2).0))),
             Or(r0, r1, r2) => SetReg(r0, Const(self.getr(r1).0 | self.getr(r2).0)),
             And(r0, r1, r2) => SetReg(r0, Const(self.getr(r1).0 & self.getr(r2).0)),
             Xor(r0, r1, r2) => SetReg(r0, Const(self.getr(r1).0 ^ self.getr(r2).0)),
-            Rotate(reg, shift) => {
-                let shift = shift.0 & 7;
-                let val = self.getr(reg).0;
-                SetReg(
-                    reg,
-                    Const((val >> shift) | ((val & ((1 << shift) - 1)) << (8 - shift))),
-                )
-            }
+            Rotate(reg, shift) => SetReg(
+                reg,
+                Const(self.getr(reg).0.rotate_right(shift.0 as u32 & 7)),
+            ),
             JumpIfEqual(reg, addr) => {
                 if self.getr(reg).0 == self.getr(Reg(0)).0 {
-                    Jump(addr)
+                    Jump(page(addr))
                 } else {
                     None
                 }
             }
             Halt => {
-                return false;
+                return Option::None;
+            }
+            LoadFromPointer(reg, ptr) => {
+                let addr = page(self.getr(ptr));
+                SetReg(reg, self.read(addr))
             }
-            LoadFromPointer(reg, ptr) => SetReg(reg, self.load(self.getr(ptr))),
-            StoreToPointer(reg, ptr) => SetMem(self.getr(ptr), self.getr(reg)),
+            StoreToPointer(reg, ptr) => SetMem(page(self.getr(ptr)), self.getr(reg)),
             JumpIfLess(reg, addr) => {
                 if self.getr(reg).0 < self.getr(Reg(0)).0 {
-                    Jump(addr)
+                    Jump(page(addr))
                 } else {
                     None
                 }
             }
-        });
-        true
+            Push(reg) => {
+                let sp = Const(self.getr(SP).0.wrapping_sub(1));
+                Batch(vec![SetReg(SP, sp), SetMem(page(sp), self.getr(reg))])
+            }
+            Pop(reg) => {
+                let sp = self.getr(SP);
+                let value = self.read(page(sp));
+                Batch(vec![
+                    SetReg(SP, Const(sp.0.wrapping_add(1))),
+                    SetReg(reg, value),
+                ])
+            }
+            Call(addr) => {
+                let sp = Const(self.getr(SP).0.wrapping_sub(1));
+                Batch(vec![
+                    SetReg(SP, sp),
+                    SetMem(page(sp), Const(self.pc.0 as u8)),
+                    Jump(page(addr)),
+                ])
+            }
+            Ret => {
+                let sp = self.getr(SP);
+                let addr = self.read(page(sp));
+                Batch(vec![
+                    SetReg(SP, Const(sp.0.wrapping_add(1))),
+                    Jump(page(addr)),
+                ])
+            }
+            Custom(i0, i1) => {
+                let opcode = i0.0 >> 4;
+                let mut handler = self.handlers.remove(&opcode)?;
+                let action = handler(self, i0.0, i1.0);
+                self.handlers.insert(opcode, handler);
+                action?
+            }
+            JumpIfZero(addr) => Self::jump_if(page, self.flags & ZERO != 0, addr),
+            JumpIfNotZero(addr) => Self::jump_if(page, self.flags & ZERO == 0, addr),
+            JumpIfCarry(addr) => Self::jump_if(page, self.flags & CARRY != 0, addr),
+            JumpIfNoCarry(addr) => Self::jump_if(page, self.flags & CARRY == 0, addr),
+        };
+        let flags = match (&action, instr) {
+            _ if !self.isa.flags => Option::None,
+            (SetReg(_, value), AddInt(_, r1, r2)) => {
+                let sum = self.getr(r1).0 as u16 + self.getr(r2).0 as u16;
+                Some(if value.0 == 0 { ZERO } else { 0 } | if sum > 0xff { CARRY } else { 0 })
+            }
+            // Every float with a zero mantissa is zero
+            (SetReg(_, value), AddFloat(..)) => Some(if value.0 & 0xf == 0 { ZERO } else { 0 }),
+            (SetReg(_, value), Or(..) | And(..) | Xor(..) | Rotate(..)) => {
+                Some(if value.0 == 0 { ZERO } else { 0 })
+            }
+            _ => Option::None,
+        };
+        let action = match flags {
+            Some(flags) => Batch(vec![action, SetFlags(Const(flags))]),
+            Option::None => action,
+        };
+        let mut done = action.clone();
+        let mut inverse = self.apply(action);
+        if let Some(interrupt) = self
+            .devices
+            .timer
+            .as_ref()
+            .and_then(|timer| timer.tick(self.memory.as_ref(), self.pc))
+        {
+            done = Batch(vec![done, interrupt.clone()]);
+            inverse = Batch(vec![self.apply(interrupt), inverse]);
+        }
+        Some((done, inverse))
     }
 
+    /// Executes one instruction, returning whether the machine is still
+    /// running.
     pub fn step(&mut self) -> Result<bool> {
-        let instr = self.dis(self.pc);
-        self.pc.0 = self
-            .pc
-            .0
-            .checked_add(2)
-            .ok_or_else(|| anyhow!("Program counter exceeded memory bounds (> 256)"))?;
-        Ok(self.exec(instr))
-    }
-}
-
-fn add_float(a: u8, b: u8) -> u8 {
-    fn decompose(x: u8) -> (u8, u16) {
-        (x >> 7, ((x & 0xf) as u16) << ((x >> 4) & 0x7))
-    }
-    let (a_sign, a_fix) = decompose(a);
-    let (b_sign, b_fix) = decompose(b);
-    let (sign, fix) = if a_sign == b_sign {
-        (a_sign, a_fix + b_fix)
-    } else if a_fix > b_fix {
-        (a_sign, a_fix - b_fix)
-    } else if b_fix > a_fix {
-        (b_sign, b_fix - a_fix)
-    } else {
-        (0, 0)
-    };
-    let expo = 16 - fix.leading_zeros() - 4;
-    let expo = (expo as u8).max(0).min(7);
-    (sign << 7) | (expo << 4) | ((fix >> expo) as u8 & 0xf)
+        Ok(self.step_record()?.running)
+    }
+
+    /// Steps until the machine halts, yielding every step including the
+    /// halting one. Iteration ends after an error.
+    pub fn steps(&mut self) -> Steps<'_> {
+        Steps {
+            vm: self,
+            done: false,
+        }
+    }
+
+    fn step_record(&mut self) -> Result<StepRecord> {
+        if let Some(limit) = self.step_limit.filter(|&it| self.history.len() >= it) {
+            return Err(VmError::StepLimit { limit });
+        }
+        if let Some(limit) = self.time_limit {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            // Reading the clock every step would slow down the run it limits
+            if self.history.len().is_multiple_of(TIME_CHECK) && started.elapsed() >= limit {
+                return Err(VmError::TimeLimit { limit });
+            }
+        }
+        let hash = self.seen.is_some().then(|| self.state_hash());
+        if let (Some(hash), Some(seen)) = (hash, &mut self.seen) {
+            let step = self.history.len();
+            if let Some(first) = seen.insert(hash, step) {
+                return Err(VmError::InfiniteLoop { step, first });
+            }
+        }
+        let pc = self.pc;
+        if pc.0 as usize >= self.image.len() && self.ran_off.is_none() {
+            match self.unloaded {
+                Unloaded::Ignore => {}
+                Unloaded::Warn => self.ran_off = Some((self.history.len(), pc)),
+                Unloaded::Stop => return Err(VmError::UnloadedCode { pc }),
+            }
+        }
+        let len = self.memory.len();
+        let wrap = self.end_of_memory == EndOfMemory::Wrap;
+        let instr = if pc.0 as usize + 1 < len || (wrap && (pc.0 as usize) < len) {
+            self.dis(pc)
+        } else if self.end_of_memory == EndOfMemory::Halt {
+            Instr::Halt
+        } else {
+            return Err(VmError::PcOverflow { size: len });
+        };
+        let mut next = pc.0 as usize + 2;
+        if wrap && next >= len {
+            next -= len;
+        }
+        self.pc.0 = next as u16;
+        if (pc.0 as usize) < len {
+            // Counted first, so that an instruction overwriting itself is
+            // caught
+            self.count_executed(pc, true);
+        }
+        let reads = self.devices.reads();
+        let (action, undo) = self.exec(instr, pc).unzip();
+        // Running off the end halts right after the last instruction, unless
+        // it jumped away
+        let running = action.is_some()
+            && !(self.end_of_memory == EndOfMemory::Halt && self.pc.0 as usize >= len);
+        self.history.push(Step {
+            pc,
+            undo: undo.unwrap_or(Action::None),
+            edit: false,
+            reads: (self.devices.reads() != reads).then_some(reads),
+        });
+        self.redo.clear();
+        let entry = self
+            .tracer
+            .is_some()
+            .then(|| TraceEntry::last(self, instr))
+            .flatten();
+        if let (Some(entry), Some(tracer)) = (entry, &mut self.tracer) {
+            tracer.record(&entry)?;
+        }
+        self.notify_observers(|observer, vm| {
+            observer.on_step(vm, vm.history.last().unwrap());
+            if !running {
+                observer.on_halt(vm);
+            }
+        });
+        Ok(StepRecord {
+            pc,
+            instr,
+            action: action.unwrap_or(Action::None),
+            next: self.pc,
+            running,
+        })
+    }
+
+    /// Runs until the machine halts or stops at a breakpoint.
+    pub fn run(&mut self) -> Result<StopReason> {
+        self.run_with(None, |_| false)
+    }
+
+    /// Runs until `pred` holds after a step, or the machine halts or stops at
+    /// a breakpoint.
+    pub fn run_until(&mut self, pred: impl FnMut(&VM) -> bool) -> Result<StopReason> {
+        self.run_with(None, pred)
+    }
+
+    /// Runs at most `n` steps, stopping early if the machine halts or stops
+    /// at a breakpoint.
+    pub fn run_steps(&mut self, n: usize) -> Result<StopReason> {
+        self.run_with(Some(n), |_| false)
+    }
+
+    /// Runs at most `n` steps until `pred` holds after a step, stopping early
+    /// if the machine halts or stops at a breakpoint.
+    pub fn run_steps_until(
+        &mut self,
+        n: usize,
+        pred: impl FnMut(&VM) -> bool,
+    ) -> Result<StopReason> {
+        self.run_with(Some(n), pred)
+    }
+
+    fn run_with(
+        &mut self,
+        budget: Option<usize>,
+        mut pred: impl FnMut(&VM) -> bool,
+    ) -> Result<StopReason> {
+        let mut steps = 0;
+        loop {
+            if budget.is_some_and(|it| steps >= it) {
+                return Ok(StopReason::Budget);
+            }
+            // The breakpoint a run starts at has already been stopped at
+            if steps > 0 && self.breakpoints.contains(&self.pc) {
+                return Ok(StopReason::Breakpoint(self.pc));
+            }
+            if !self.step()? {
+                return Ok(StopReason::Halted);
+            }
+            steps += 1;
+            if pred(self) {
+                return Ok(StopReason::Predicate);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::VmBuilder;
+
+    /// A jump to `loadm r1, 0x80` in the last word of 256 bytes, with 0x42 at
+    /// 0x80.
+    fn at_end(end_of_memory: EndOfMemory) -> VM {
+        let mut program = vec![0; 256];
+        program[..2].copy_from_slice(&[0xB0, 0xFE]);
+        program[0x80] = 0x42;
+        program[0xFE..].copy_from_slice(&[0x11, 0x80]);
+        VmBuilder::new()
+            .program(&program)
+            .end_of_memory(end_of_memory)
+            .build()
+    }
+
+    #[test]
+    fn last_word_wraps() {
+        let mut vm = at_end(EndOfMemory::Wrap);
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.regs[1], 0x42);
+        assert_eq!(vm.pc.0, 0);
+    }
+
+    #[test]
+    fn last_word_halts() {
+        let mut vm = at_end(EndOfMemory::Halt);
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(vm.regs[1], 0x42);
+        assert_eq!(vm.history.len(), 2);
+    }
+
+    #[test]
+    fn last_word_errors_after() {
+        let mut vm = at_end(EndOfMemory::Error);
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.regs[1], 0x42);
+        assert!(matches!(vm.step(), Err(VmError::PcOverflow { size: 256 })));
+    }
+
+    #[test]
+    fn last_word_of_page_stays_in_page() {
+        let mut program = vec![0; 0x200];
+        program[..2].copy_from_slice(&[0xB0, 0xFC]);
+        program[0x80] = 0x42;
+        program[0x180] = 0x99;
+        // loadm r1, 0x80, then jump r0, 0x04
+        program[0xFC..0x100].copy_from_slice(&[0x11, 0x80, 0xB0, 0x04]);
+        let mut vm = VmBuilder::new().memory_size(512).program(&program).build();
+        vm.run_steps(3).unwrap();
+        assert_eq!(vm.regs[1], 0x42);
+        assert_eq!(vm.pc.0, 0x04);
+    }
+
+    /// Sets R0 and R1 to 1, takes `jump r1` to 0x08, then does not take
+    /// `jump r2` at 0x0A, and halts at 0x0C.
+    fn jumps() -> VM {
+        let code = "loadb r0, 1\nloadb r1, 1\njump r1, taken\nhalt\n\
+                    taken: loadb r2, 2\njump r2, 0x00\nhalt";
+        let program = crate::asm::assemble(code, Isa::default(), 256).unwrap();
+        VmBuilder::new().program(&program).build()
+    }
+
+    #[test]
+    fn undo_taken_jump() {
+        let mut vm = jumps();
+        vm.run_steps(3).unwrap();
+        assert_eq!(vm.pc.0, 0x08);
+        vm.undo();
+        assert_eq!(vm.pc.0, 0x04);
+        assert_eq!(vm.history.len(), 2);
+        assert_eq!(vm.regs[1], 1);
+    }
+
+    #[test]
+    fn undo_untaken_jump() {
+        let mut vm = jumps();
+        vm.run_steps(5).unwrap();
+        assert_eq!(vm.pc.0, 0x0C);
+        vm.undo();
+        assert_eq!(vm.pc.0, 0x0A);
+        assert_eq!(vm.regs[2], 2);
+    }
+
+    #[test]
+    fn undo_halt() {
+        let mut vm = jumps();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        vm.undo();
+        assert_eq!(vm.pc.0, 0x0C);
+        assert_eq!(vm.history.len(), 5);
+        assert!(!vm.step().unwrap());
+    }
+
+    #[test]
+    fn undo_at_start() {
+        let mut vm = jumps();
+        vm.undo();
+        assert_eq!(vm.pc.0, 0);
+        assert!(vm.history.is_empty());
+        assert_eq!(vm.regs, [0; 16]);
+    }
+
+    #[test]
+    fn redo_after_undo() {
+        let mut vm = jumps();
+        vm.run_steps(4).unwrap();
+        let (regs, pc) = (vm.regs, vm.pc.0);
+        for _ in 0..4 {
+            vm.undo();
+        }
+        assert_eq!((vm.regs, vm.pc.0), ([0; 16], 0));
+        for _ in 0..4 {
+            assert!(vm.redo());
+        }
+        assert_eq!((vm.regs, vm.pc.0), (regs, pc));
+        assert_eq!(vm.redo_len(), 0);
+    }
+
+    /// `code` with loop detection on and `devices` attached.
+    fn detecting(code: &str, devices: Devices) -> VM {
+        let program = crate::asm::assemble(code, Isa::default(), 256).unwrap();
+        VmBuilder::new()
+            .program(&program)
+            .devices(devices)
+            .detect_loops(true)
+            .build()
+    }
+
+    #[test]
+    fn jump_to_itself_is_a_loop() {
+        let mut vm = detecting("loop: jump r0, loop", Devices::default());
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, VmError::InfiniteLoop { step: 1, first: 0 }));
+        assert!(err.to_string().contains("state repeated at step 1"));
+    }
+
+    #[test]
+    fn reading_input_is_not_a_loop() {
+        // Every read gives the same byte, so only the input position differs
+        let devices = Devices {
+            input: Some(crate::device::InputPort::new(0xF0, vec![b'a'; 16])),
+            ..Default::default()
+        };
+        let mut vm = detecting("loop: loadm r1, 0xF0\njump r0, loop", devices);
+        vm.run_steps(32).unwrap();
+        assert_eq!(vm.regs[1], b'a');
+    }
+
+    #[test]
+    fn reading_random_bytes_is_not_a_loop() {
+        let devices = Devices {
+            random: Some(Random::new(0xF0, 1)),
+            ..Default::default()
+        };
+        let mut vm = detecting("loop: loadm r1, 0xF0\nloadb r1, 0\njump r0, loop", devices);
+        vm.run_steps(300).unwrap();
+    }
+
+    #[test]
+    fn undo_reads_input_again() {
+        let devices = Devices {
+            input: Some(crate::device::InputPort::new(0xF0, b"ab".to_vec())),
+            ..Default::default()
+        };
+        let mut vm = detecting("loadm r1, 0xF0\nloadm r2, 0xF0", devices);
+        vm.step().unwrap();
+        vm.undo();
+        vm.step().unwrap();
+        assert_eq!(vm.regs[1], b'a');
+        // Redoing the second read leaves the port after it, like stepping
+        vm.step().unwrap();
+        vm.undo();
+        assert!(vm.redo());
+        vm.undo();
+        vm.step().unwrap();
+        assert_eq!(vm.regs[2], b'b');
+    }
+
+    #[test]
+    fn undo_reads_random_bytes_again() {
+        let devices = Devices {
+            random: Some(Random::new(0xF0, 1)),
+            ..Default::default()
+        };
+        let mut vm = detecting("loadm r1, 0xF0", devices);
+        vm.step().unwrap();
+        let first = vm.regs[1];
+        vm.undo();
+        vm.step().unwrap();
+        assert_eq!(vm.regs[1], first);
+    }
 }
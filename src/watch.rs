@@ -0,0 +1,85 @@
+use crate::{
+    asm::SourceMap,
+    vm::{Reg, VM},
+};
+
+/// An expression watched in the interactive UI, like `R3`, `mem[0x80]` or
+/// `mem[R1]`.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Reg(Reg),
+    Const(u16),
+    /// The memory cell at the address the inner expression evaluates to.
+    Mem(Box<Expr>),
+}
+
+impl Expr {
+    /// Parses `s`, resolving labels with `map`.
+    pub fn parse(s: &str, map: Option<&SourceMap>) -> Option<Self> {
+        let s = s.trim();
+        let lower = s.to_ascii_lowercase();
+        if let Some(inner) = lower
+            .strip_prefix("mem[")
+            .or_else(|| lower.strip_prefix('['))
+            .and_then(|it| it.strip_suffix(']'))
+        {
+            // Keep the case of labels inside
+            let start = s.len() - inner.len() - 1;
+            return Some(Self::Mem(Box::new(Self::parse(
+                &s[start..s.len() - 1],
+                map,
+            )?)));
+        }
+        if let Some(hex) = lower.strip_prefix("0x") {
+            return u16::from_str_radix(hex, 16).ok().map(Self::Const);
+        }
+        if let Ok(value) = lower.parse() {
+            return Some(Self::Const(value));
+        }
+        if let Some(reg) = lower
+            .strip_prefix('r')
+            .and_then(|it| u8::from_str_radix(it, 16).ok())
+            .filter(|&it| it < 16 && lower.len() == 2)
+        {
+            return Some(Self::Reg(Reg(reg)));
+        }
+        map?.label(s).map(Self::Const)
+    }
+
+    pub fn eval(&self, vm: &VM) -> u16 {
+        match self {
+            Self::Reg(reg) => vm.getr(*reg).0 as u16,
+            &Self::Const(value) => value,
+            Self::Mem(addr) => {
+                let addr = addr.eval(vm) as usize % vm.memory.len();
+                vm.memory.load(addr as u16) as u16
+            }
+        }
+    }
+}
+
+/// A watched expression with its value as of the last two updates.
+pub struct Watch {
+    pub text: String,
+    pub expr: Expr,
+    pub value: u16,
+    pub changed: bool,
+}
+
+impl Watch {
+    pub fn new(text: String, expr: Expr, vm: &VM) -> Self {
+        Self {
+            value: expr.eval(vm),
+            text,
+            expr,
+            changed: false,
+        }
+    }
+
+    /// Re-evaluates the expression, noting whether its value changed.
+    pub fn update(&mut self, vm: &VM) {
+        let value = self.expr.eval(vm);
+        self.changed = value != self.value;
+        self.value = value;
+    }
+}